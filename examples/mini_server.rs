@@ -63,7 +63,7 @@ async fn main() -> Result<(), HypErr> {
         }
     });
 
-    let bind_to = format!("0.0.0.0:8080").parse().unwrap();
+    let bind_to = "0.0.0.0:8080".parse().unwrap();
     let server = Server::bind(&bind_to).serve(new_service);
     println!("Listening on http://{}", &bind_to);
     server.await?;
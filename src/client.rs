@@ -2,43 +2,465 @@
 
 
 // standard library
-use std::{env};
+use std::{env, sync::Arc};
 // crates.io
+use bytes::Bytes;
+use futures_util::TryStreamExt;
 use serde::{self, Serialize, de::DeserializeOwned};
 use serde_json;
-use hyper::body; // brings the to_bytes() method into scope:
-use hyper::{Request, Body, Method, Client};
+use hyper::{client::HttpConnector, header, Request, Response, Body, Method, Client, StatusCode};
+use url::Url;
 // this crate 
 use crate::err::HypErr;
+use crate::format::{Format, Json};
+use crate::server::{compress::decode_content, read_body_limited, webhook::WebhookSigner, MAX_DECOMPRESSED_PAYLOAD};
+use breaker::CircuitBreaker;
+use cache::HttpCache;
+use cookies::CookieJar;
+use multipart::MultipartBuilder;
+use redirect::{FinalUrl, RedirectPolicy};
+use throttle::Throttle;
+
+pub mod breaker;
+pub mod cache;
+pub mod cookies;
+pub mod multipart;
+pub mod redirect;
+pub mod stream;
+pub mod sse;
+pub mod throttle;
+pub mod ws;
 
 // return the value of the environment variable X_API_KEY
 fn get_api_key(optkey: Option<&str>) -> String {
     match optkey {
         Some(key) => key.to_string(),
-        None => match env::var("X_API_KEY") {
-            Ok(key) => key,
-            Err(_) => String::new(),
+        None => env::var("X_API_KEY").unwrap_or_default(),
+    }
+}
+
+// copy a request built by ApiClient into one hyper can send
+fn to_hyper_request(request: &Request<Bytes>) -> Request<Body> {
+    let mut copy = Request::new(Body::from(request.body().clone()));
+    *copy.method_mut() = request.method().clone();
+    *copy.uri_mut() = request.uri().clone();
+    *copy.version_mut() = request.version();
+    *copy.headers_mut() = request.headers().clone();
+    copy
+}
+
+// the Accept-Encoding sent when decompression is enabled
+const ACCEPT_ENCODING: &str = "br, gzip, deflate";
+const APPLICATION_JSON: &str = "application/json; charset=UTF-8";
+const APPLICATION_FORM: &str = "application/x-www-form-urlencoded";
+
+
+/// An ApiClient holds a pool of connections along with settings shared by many requests.  
+/// The free functions in this module (get, post, etc.) each use a fresh default ApiClient,
+/// so create an ApiClient to reuse connections or change its settings.  
+/// Cloning an ApiClient is cheap and shares the connection pool, so a setting can be changed
+/// for a single call, i.e. `client.clone().with_decompression(false).get(url)`  
+#[derive(Clone, Debug)]
+pub struct ApiClient {
+    client: Client<HttpConnector>,
+    api_key: Option<String>,
+    decompress: bool,
+    max_decompressed: usize,
+    cache: Option<Arc<HttpCache>>,
+    throttle: Option<Arc<Throttle>>,
+    breaker: Option<Arc<CircuitBreaker>>,
+    signer: Option<WebhookSigner>,
+    cookie_jar: Option<Arc<CookieJar>>,
+    redirects: RedirectPolicy,
+}
+
+
+impl ApiClient {
+    /// Create a client that reads X-Api-Key from the X_API_KEY environment variable
+    /// and transparently decompresses gzip, brotli and deflate responses, following redirects.
+    pub fn new() -> Self {
+        ApiClient{
+            client: Client::new(),
+            api_key: None,
+            decompress: true,
+            max_decompressed: MAX_DECOMPRESSED_PAYLOAD,
+            cache: None,
+            throttle: None,
+            breaker: None,
+            signer: None,
+            cookie_jar: None,
+            redirects: RedirectPolicy::new(),
+        }
+    }
+
+    /// Send this X-Api-Key instead of the X_API_KEY environment variable
+    pub fn with_api_key(mut self, api_key: &str) -> Self {
+        self.api_key = Some(api_key.to_string());
+        self
+    }
+
+    /// Choose whether to advertise Accept-Encoding and decompress responses (on by default)
+    pub fn with_decompression(mut self, decompress: bool) -> Self {
+        self.decompress = decompress;
+        self
+    }
+
+    /// Limit how many bytes a response body may be, as received and once decompressed, 16 MiB by default.
+    /// Larger responses return HypErr::BodyTooLarge, so neither a huge body nor a "zip bomb" from an
+    /// upstream can exhaust memory. Streamed bodies (see stream) apply it to each chunk instead
+    pub fn with_max_decompressed(mut self, max_decompressed: usize) -> Self {
+        self.max_decompressed = max_decompressed;
+        self
+    }
+
+    // build a client from the optkey argument taken by the free functions
+    fn from_optkey(optkey: Option<&str>) -> Self {
+        match optkey {
+            Some(key) => ApiClient::new().with_api_key(key),
+            None => ApiClient::new(),
+        }
+    }
+
+    /// Use an HttpCache for GET requests. The same cache can be shared by several clients
+    pub fn with_cache(mut self, cache: Arc<HttpCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Queue requests through a Throttle, which enforces per-host rate limits and concurrency caps.
+    /// The same Throttle should be shared by every client calling the same hosts
+    pub fn with_throttle(mut self, throttle: Arc<Throttle>) -> Self {
+        self.throttle = Some(throttle);
+        self
+    }
+
+    /// Fail fast with HypErr::CircuitOpen while a host keeps failing, instead of waiting on it.
+    /// Share the CircuitBreaker between clients so they all see the same state
+    pub fn with_circuit_breaker(mut self, breaker: Arc<CircuitBreaker>) -> Self {
+        self.breaker = Some(breaker);
+        self
+    }
+
+    /// Sign every request body with HMAC-SHA256, i.e. when delivering webhooks.
+    /// Requests without a body are not signed, and retries and redirects are signed again.
+    /// See server::webhook for the headers sent and how to verify them
+    pub fn with_webhook_signer(mut self, signer: WebhookSigner) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Store the cookies servers set in a CookieJar, and send them back on later requests.
+    /// Share the jar between clients that should act as the same session
+    pub fn with_cookie_jar(mut self, cookie_jar: Arc<CookieJar>) -> Self {
+        self.cookie_jar = Some(cookie_jar);
+        self
+    }
+
+    /// Choose how redirects are followed. By default up to 10 are followed to any origin,
+    /// and X-Api-Key, Authorization and Cookie headers are dropped when one leads to another origin
+    pub fn with_redirects(mut self, redirects: RedirectPolicy) -> Self {
+        self.redirects = redirects;
+        self
+    }
+
+    // build a request with the common headers, the media type to Accept, and optionally a body with its content type.
+    // The body is kept as Bytes so the request can be sent again, i.e. to retry it
+    fn build_request(&self, method: Method, url: &str, accept: &str, body: Option<(&str, Bytes)>) -> Result<Request<Bytes>, HypErr> {
+        let mut builder = Request::builder()
+            .method(method)
+            .uri(url)
+            .header(header::ACCEPT, accept)
+            .header("X-Api-Key", get_api_key(self.api_key.as_deref()));
+        if self.decompress {
+            builder = builder.header(header::ACCEPT_ENCODING, ACCEPT_ENCODING);
         }
+        let request = match body {
+            // IF YOU DON'T INCLUDE THE CONTENT TYPE, ONLY THE FIRST PROPERTY OF THE STRUCT GETS RETURNED???
+            Some((content_type, bytes)) => builder.header(header::CONTENT_TYPE, content_type).body(bytes)?,
+            None => builder.body(Bytes::new())?,
+        };
+        Ok(request)
+    }
+
+    // send a request that has already been built, following redirects according to the policy.
+    // The response carries the url it was finally fetched from as a FinalUrl extension
+    async fn execute(&self, mut request: Request<Bytes>) -> Result<Response<Body>, HypErr> {
+        let mut hops = 0;
+        loop {
+            let mut resp = self.execute_hop(&request).await?;
+            match self.redirects.next_request(&request, &resp, hops)? {
+                Some(next_request) => request = next_request,
+                None => {
+                    if let Ok(url) = Url::parse(&request.uri().to_string()) {
+                        resp.extensions_mut().insert(FinalUrl(url));
+                    }
+                    return Ok(resp)
+                },
+            }
+            hops += 1;
+        }
+    }
+
+    // send a single request, failing fast if the host's circuit breaker is open
+    async fn execute_hop(&self, request: &Request<Bytes>) -> Result<Response<Body>, HypErr> {
+        let host = request.uri().authority().map(|a| a.to_string()).unwrap_or_default();
+        let breaker = match &self.breaker {
+            Some(breaker) => breaker,
+            None => return self.execute_throttled(&host, request).await,
+        };
+        let permit = breaker.allow(&host)?;
+        let result = self.execute_throttled(&host, request).await;
+        // connection errors and 5xx responses count against the host, anything else means it is up
+        let success = matches!(&result, Ok(resp) if !resp.status().is_server_error());
+        breaker.record(&host, permit, success);
+        result
+    }
+
+    // send a request, waiting for the throttle if there is one.
+    // A throttled request that gets 429 Too Many Requests is retried after the server's Retry-After
+    async fn execute_throttled(&self, host: &str, request: &Request<Bytes>) -> Result<Response<Body>, HypErr> {
+        let throttle = match &self.throttle {
+            Some(throttle) => throttle,
+            None => return self.dispatch(request).await,
+        };
+        let mut retries = 0;
+        loop {
+            let permit = throttle.acquire(host).await;
+            let resp = self.dispatch(request).await?;
+            drop(permit);
+            throttle.observe(host, resp.status(), resp.headers());
+            if resp.status() != StatusCode::TOO_MANY_REQUESTS || retries >= throttle.retries() {
+                return Ok(resp)
+            }
+            retries += 1;
+        }
+    }
+
+    // send a request once, attaching the cookie jar's cookies and storing the ones set by the response.
+    // A body is signed here, so every retry and redirect carries a fresh signature over what it sends
+    async fn dispatch(&self, request: &Request<Bytes>) -> Result<Response<Body>, HypErr> {
+        let mut hyper_request = to_hyper_request(request);
+        if let Some(signer) = self.signer.as_ref().filter(|_| !request.body().is_empty()) {
+            signer.sign(hyper_request.headers_mut(), request.body())?;
+        }
+        self.dispatch_body(hyper_request).await
+    }
+
+    async fn dispatch_body(&self, mut hyper_request: Request<Body>) -> Result<Response<Body>, HypErr> {
+        let jar = self.cookie_jar.as_ref().and_then(|jar| Some((jar, Url::parse(&hyper_request.uri().to_string()).ok()?)));
+        if let Some((jar, url)) = &jar {
+            jar.add_request_cookies(url, hyper_request.headers_mut());
+        }
+        let resp = self.client.request(hyper_request).await?;
+        if let Some((jar, url)) = &jar {
+            jar.store_response_cookies(url, resp.headers());
+        }
+        Ok(resp)
+    }
+
+    // send a request whose body is a stream, which can only be sent once: the circuit breaker,
+    // throttle and cookie jar apply, but a 429 is not retried and redirects are not followed
+    async fn execute_stream(&self, request: Request<Body>) -> Result<Response<Body>, HypErr> {
+        let host = request.uri().authority().map(|a| a.to_string()).unwrap_or_default();
+        let circuit_permit = match &self.breaker {
+            Some(breaker) => Some(breaker.allow(&host)?),
+            None => None,
+        };
+        let permit = match &self.throttle {
+            Some(throttle) => Some(throttle.acquire(&host).await),
+            None => None,
+        };
+        let result = self.dispatch_body(request).await;
+        drop(permit);
+        if let (Some(throttle), Ok(resp)) = (&self.throttle, &result) {
+            throttle.observe(&host, resp.status(), resp.headers());
+        }
+        if let (Some(breaker), Some(circuit_permit)) = (&self.breaker, circuit_permit) {
+            breaker.record(&host, circuit_permit, matches!(&result, Ok(resp) if !resp.status().is_server_error()));
+        }
+        result
+    }
+
+    // build and send a request
+    async fn send(&self, method: Method, url: &str, accept: &str, body: Option<(&str, Bytes)>) -> Result<Response<Body>, HypErr> {
+        let request = self.build_request(method, url, accept, body)?;
+        self.execute(request).await
+    }
+
+    // the cookies the jar would send to url, sorted so the same cookies always give the same cache key
+    fn cookie_key(&self, url: &str) -> String {
+        let mut cookies = match (&self.cookie_jar, Url::parse(url)) {
+            (Some(jar), Ok(url)) => jar.cookies(&url),
+            _ => return String::new(),
+        };
+        cookies.sort();
+        cookies.iter().map(|(name, value)| format!("{}={}", name, value)).collect::<Vec<_>>().join("; ")
+    }
+
+    // make a GET request and return the (decompressed) body, going through the cache if there is one
+    async fn get_bytes(&self, url: &str, accept: &str) -> Result<Bytes, HypErr> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => {
+                let resp = self.send(Method::GET, url, accept, None).await?;
+                return self.read_body(resp).await
+            }
+        };
+        let key = cache::cache_key(url, &get_api_key(self.api_key.as_deref()), accept, &self.cookie_key(url), self.decompress);
+        let cached = cache.lookup(&key).await;
+        let mut request = self.build_request(Method::GET, url, accept, None)?;
+        if let Some(entry) = &cached {
+            if entry.is_fresh() {
+                cache.record_hit();
+                return Ok(entry.body.clone())
+            }
+            entry.add_validators(request.headers_mut());
+        }
+        let resp = self.execute(request).await?;
+        if let (Some(entry), StatusCode::NOT_MODIFIED) = (cached, resp.status()) {
+            return Ok(cache.revalidated(&key, entry, resp.headers()).await)
+        }
+        cache.record_miss();
+        let status = resp.status();
+        let headers = resp.headers().clone();
+        let bytes = self.read_body(resp).await?;
+        if status == StatusCode::OK {
+            cache.store(&key, &headers, bytes.clone()).await;
+        }
+        Ok(bytes)
+    }
+
+    /// Read the whole body of a response, decompressing it according to its Content-Encoding
+    /// if decompression is enabled. The body as received and the decompressed body may each be
+    /// at most the limit set with with_max_decompressed, past which HypErr::BodyTooLarge is returned
+    pub async fn read_body(&self, resp: Response<Body>) -> Result<Bytes, HypErr> {
+        let content_encoding = resp.headers().get(header::CONTENT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        let bytes = read_body_limited(resp.into_body(), self.max_decompressed).await?;
+        match content_encoding {
+            Some(coding) if self.decompress => Ok(Bytes::from(decode_content(&bytes, &coding, self.max_decompressed)?)),
+            _ => Ok(bytes),
+        }
+    }
+
+    /// Make a GET request and return the response as it is, after following any redirects.  
+    /// Use redirect::final_url to see where it was fetched from, and read_body to read it.  
+    pub async fn get_response(&self, url: &str) -> Result<Response<Body>, HypErr> {
+        self.send(Method::GET, url, Json::CONTENT_TYPE, None).await
+    }
+
+    /// Let T be any struct implementing serde::de::DeserializeOwned.  
+    /// Make a GET request and deserialize the JSON response into T.  
+    /// If the client has an HttpCache, fresh cached responses are returned without a request,
+    /// and stale ones are revalidated with If-None-Match/If-Modified-Since.  
+    pub async fn get<T: DeserializeOwned>(&self, url: &str) -> Result<T, HypErr> {
+        let bytes = self.get_bytes(url, Json::CONTENT_TYPE).await?;
+        let payload = serde_json::from_slice::<T>(&bytes)?;
+        Ok(payload)
+    }
+
+    /// Let U be any struct implementing serde::Serialize.  
+    /// Let T be any struct implementing serde::de::DeserializeOwned.  
+    /// POST U as JSON and deserialize the JSON response into T.  
+    pub async fn post<U: Serialize, T: DeserializeOwned>(&self, url: &str, payload: &U) -> Result<T, HypErr> {
+        let body_bytes = Bytes::from(serde_json::to_vec(payload)?);
+        let resp = self.send(Method::POST, url, Json::CONTENT_TYPE, Some((APPLICATION_JSON, body_bytes))).await?;
+        let bytes = self.read_body(resp).await?;
+        let payload = serde_json::from_slice::<T>(&bytes)?;
+        Ok(payload)
+    }
+
+    /// Let T be any struct implementing serde::de::DeserializeOwned.  
+    /// Make a GET request accepting the format F, i.e. get_as::<MsgPack, _>(url), and deserialize the response into T.  
+    /// The cache works as in get, keeping each format apart.  
+    pub async fn get_as<F: Format, T: DeserializeOwned>(&self, url: &str) -> Result<T, HypErr> {
+        let bytes = self.get_bytes(url, F::CONTENT_TYPE).await?;
+        F::from_slice(&bytes)
+    }
+
+    /// Let U be any struct implementing serde::Serialize.  
+    /// Let T be any struct implementing serde::de::DeserializeOwned.  
+    /// POST U encoded with the format F, i.e. post_as::<Cbor, _, _>(url, &batch), and deserialize the response into T,
+    /// which the server is asked to send in the same format.  
+    pub async fn post_as<F: Format, U: Serialize, T: DeserializeOwned>(&self, url: &str, payload: &U) -> Result<T, HypErr> {
+        let body_bytes = Bytes::from(F::to_vec(payload)?);
+        let resp = self.send(Method::POST, url, F::CONTENT_TYPE, Some((F::CONTENT_TYPE, body_bytes))).await?;
+        let bytes = self.read_body(resp).await?;
+        F::from_slice(&bytes)
+    }
+
+    /// Let U be any struct implementing serde::Serialize.  
+    /// Let T be any struct implementing serde::de::DeserializeOwned.  
+    /// POST U as an application/x-www-form-urlencoded form and deserialize the JSON response into T.  
+    /// U must be flat: its fields can be strings, numbers, bools and options, but not nested structs or lists.  
+    pub async fn post_form<U: Serialize, T: DeserializeOwned>(&self, url: &str, payload: &U) -> Result<T, HypErr> {
+        let form = serde_urlencoded::to_string(payload).map_err(|e| HypErr::Format(e.to_string()))?;
+        let resp = self.send(Method::POST, url, Json::CONTENT_TYPE, Some((APPLICATION_FORM, Bytes::from(form)))).await?;
+        let bytes = self.read_body(resp).await?;
+        let payload = serde_json::from_slice::<T>(&bytes)?;
+        Ok(payload)
+    }
+
+    /// Let T be any struct implementing serde::de::DeserializeOwned.  
+    /// POST a multipart/form-data body and deserialize the JSON response into T.  
+    /// Files added with MultipartBuilder::file are streamed from disk, so such a request is sent once:
+    /// it is not retried after a 429 and redirects are returned as errors rather than followed.
+    /// With a webhook signer the body has to be signed, so it is read into memory first and sent as usual.
+    pub async fn post_multipart<T: DeserializeOwned>(&self, url: &str, form: MultipartBuilder) -> Result<T, HypErr> {
+        let content_type = form.content_type();
+        let resp = match form.to_bytes() {
+            Some(body_bytes) => self.send(Method::POST, url, Json::CONTENT_TYPE, Some((&content_type, body_bytes))).await?,
+            None if self.signer.is_some() => {
+                let chunks: Vec<Bytes> = form.into_stream().try_collect().await?;
+                self.send(Method::POST, url, Json::CONTENT_TYPE, Some((&content_type, Bytes::from(chunks.concat())))).await?
+            },
+            None => {
+                let (parts, _) = self.build_request(Method::POST, url, Json::CONTENT_TYPE, Some((&content_type, Bytes::new())))?.into_parts();
+                let resp = self.execute_stream(Request::from_parts(parts, Body::wrap_stream(form.into_stream()))).await?;
+                if resp.status().is_redirection() && self.redirects.max_hops > 0 && resp.headers().contains_key(header::LOCATION) {
+                    return Err(HypErr::Redirect(format!("a streamed multipart body cannot follow a {} redirect", resp.status())))
+                }
+                resp
+            },
+        };
+        let bytes = self.read_body(resp).await?;
+        let payload = serde_json::from_slice::<T>(&bytes)?;
+        Ok(payload)
+    }
+
+    /// Let U be any struct implementing serde::Serialize.  
+    /// POST U as JSON, expecting no struct back.  
+    pub async fn post_noback<U: Serialize>(&self, url: &str, payload: &U) -> Result<(), HypErr> {
+        let body_bytes = Bytes::from(serde_json::to_vec(payload)?);
+        let _resp = self.send(Method::POST, url, Json::CONTENT_TYPE, Some((APPLICATION_JSON, body_bytes))).await?;
+        Ok(())
+    }
+
+    /// Let T be any struct implementing serde::de::DeserializeOwned.  
+    /// Make a PUT request and deserialize the JSON response into T.  
+    pub async fn put<T: DeserializeOwned>(&self, url: &str) -> Result<T, HypErr> {
+        let resp = self.send(Method::PUT, url, Json::CONTENT_TYPE, None).await?;
+        let bytes = self.read_body(resp).await?;
+        let payload = serde_json::from_slice::<T>(&bytes)?;
+        Ok(payload)
     }
 }
 
+
+impl Default for ApiClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
 /// Let T be any struct implementing serde::de::DeserializeOwned.  
 /// You can make an API call to get that struct using this get function.  
 /// An optional X-Api-Key can be provided using optkey.  
 /// If optkey is none, it will look for the environment variable X_API_KEY.  
 pub async fn get<T: DeserializeOwned>(url: &str, optkey: Option<&str>) -> Result<T, HypErr> {
-    let x_api_key = get_api_key(optkey);
-    let request = Request::builder()
-        .method(Method::GET)
-        .uri(url)
-        .header("accept", "application/json")
-        .header("X-Api-Key", x_api_key)
-        .body(Body::empty())?;
-    let client = Client::new();
-    let resp = client.request(request).await?;
-    let bytes = body::to_bytes(resp.into_body()).await?;
-    let foo = serde_json::from_slice::<T>(&bytes)?;
-    Ok(foo)
+    ApiClient::from_optkey(optkey).get(url).await
 }
 
 
@@ -50,22 +472,26 @@ pub async fn get<T: DeserializeOwned>(url: &str, optkey: Option<&str>) -> Result
 /// If optkey is None, the request will use the environment variable X_API_KEY to set the X-Api-Key header,
 /// defaulting to "" if the X_API_KEY is not defined. 
 pub async fn post<U: Serialize, T: DeserializeOwned>(url: &str, payload: &U, optkey: Option<&str>) -> Result<T, HypErr> {
-    let body_string = serde_json::to_string(payload)?;
-    let x_api_key = get_api_key(optkey);
-    let request = Request::builder()
-        .method(Method::POST)
-        .uri(url)
-        .header("accept", "application/json")
-        .header("X-Api-Key", x_api_key)
-        // IF YOU DON'T INCLUDE THIS HEADER, ONLY THE FIRST PROPERTY OF THE STRUCT GETS RETURNED???
-        .header("Content-type", "application/json; charset=UTF-8")
-        .body(Body::from(body_string))?;
-    let client = Client::new();
-    let resp = client.request(request).await?;
-    let bytes = body::to_bytes(resp.into_body()).await?;
-    //println!("   DEV_98Mi9 GOT BYTES: {}", std::str::from_utf8(&bytes).unwrap() );
-    let foo = serde_json::from_slice::<T>(&bytes)?;
-    Ok(foo)
+    ApiClient::from_optkey(optkey).post(url, payload).await
+}
+
+/// Let U be any struct implementing serde::Serialize.  
+/// Let T be any struct implementing serde::de::DeserializeOwned.  
+/// This function sends U as an application/x-www-form-urlencoded form, i.e. to legacy partners, and gets T back as JSON.  
+/// To set the X-Api-Key header, pass a Some() variant of a string slice to the optkey argument.  
+/// If optkey is None, the request will use the environment variable X_API_KEY to set the X-Api-Key header,
+/// defaulting to "" if the X_API_KEY is not defined. 
+pub async fn post_form<U: Serialize, T: DeserializeOwned>(url: &str, payload: &U, optkey: Option<&str>) -> Result<T, HypErr> {
+    ApiClient::from_optkey(optkey).post_form(url, payload).await
+}
+
+/// Let T be any struct implementing serde::de::DeserializeOwned.  
+/// This function sends a multipart/form-data body built with MultipartBuilder, i.e. an upload, and gets T back as JSON.  
+/// To set the X-Api-Key header, pass a Some() variant of a string slice to the optkey argument.  
+/// If optkey is None, the request will use the environment variable X_API_KEY to set the X-Api-Key header,
+/// defaulting to "" if the X_API_KEY is not defined. 
+pub async fn post_multipart<T: DeserializeOwned>(url: &str, form: MultipartBuilder, optkey: Option<&str>) -> Result<T, HypErr> {
+    ApiClient::from_optkey(optkey).post_multipart(url, form).await
 }
 
 /// Let U be any struct implementing serde::Serialize.  
@@ -74,19 +500,7 @@ pub async fn post<U: Serialize, T: DeserializeOwned>(url: &str, payload: &U, opt
 /// If optkey is None, the request will use the environment variable X_API_KEY to set the X-Api-Key header,
 /// defaulting to "" if the X_API_KEY is not defined. 
 pub async fn post_noback<U: Serialize>(url: &str, payload: &U, optkey: Option<&str>) -> Result<(), HypErr> {
-    let body_string = serde_json::to_string(payload)?;
-    let x_api_key = get_api_key(optkey);
-    let request = Request::builder()
-        .method(Method::POST)
-        .uri(url)
-        .header("accept", "application/json")
-        .header("X-Api-Key", x_api_key)
-        // IF YOU DON'T INCLUDE THIS HEADER, ONLY THE FIRST PROPERTY OF THE STRUCT GETS RETURNED???
-        .header("Content-type", "application/json; charset=UTF-8")
-        .body(Body::from(body_string))?;
-    let client = Client::new();
-    let _resp = client.request(request).await?;
-    Ok(())
+    ApiClient::from_optkey(optkey).post_noback(url, payload).await
 }
 
 
@@ -96,17 +510,95 @@ pub async fn post_noback<U: Serialize>(url: &str, payload: &U, optkey: Option<&s
 /// If optkey is None, the request will use the environment variable X_API_KEY to set the X-Api-Key header,
 /// defaulting to "" if the X_API_KEY is not defined. 
 pub async fn put<T: DeserializeOwned>(url: &str, optkey: Option<&str>) -> Result<T, HypErr> {
-    let x_api_key = get_api_key(optkey);
-    let request = Request::builder()
-        .method(Method::PUT)
-        .uri(url)
-        .header("accept", "application/json")
-        .header("X-Api-Key", x_api_key)
-        .body(Body::empty())?;
-    let client = Client::new();
-    let resp = client.request(request).await?;
-    let bytes = body::to_bytes(resp.into_body()).await?;
-    let foo = serde_json::from_slice::<T>(&bytes)?;
-    Ok(foo)
+    ApiClient::from_optkey(optkey).put(url).await
 }
 
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{convert::Infallible, net::SocketAddr, sync::Mutex};
+    use hyper::{header::HeaderMap, service::{make_service_fn, service_fn}, Server};
+    use crate::server::webhook::{WebhookVerifier, DEFAULT_SIGNATURE_HEADER};
+
+    type Seen = Arc<Mutex<Vec<(Method, String, HeaderMap, Bytes)>>>;
+
+    // serve /hook with a 303 to /done, and /retry with a 429 the first time
+    async fn serve() -> (SocketAddr, Seen) {
+        let seen: Seen = Arc::default();
+        let make_service = {
+            let seen = seen.clone();
+            make_service_fn(move |_| {
+                let seen = seen.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                        let seen = seen.clone();
+                        async move {
+                            let (parts, body) = req.into_parts();
+                            let body = hyper::body::to_bytes(body).await?;
+                            let path = parts.uri.path().to_string();
+                            let mut seen = seen.lock().unwrap();
+                            let retried = seen.iter().any(|(_, seen_path, _, _)| *seen_path == path);
+                            seen.push((parts.method, path.clone(), parts.headers, body));
+                            let builder = Response::builder();
+                            let builder = match path.as_str() {
+                                "/hook" => builder.status(StatusCode::SEE_OTHER).header(header::LOCATION, "/done"),
+                                "/retry" if !retried => builder.status(StatusCode::TOO_MANY_REQUESTS).header(header::RETRY_AFTER, "0"),
+                                _ => builder,
+                            };
+                            Ok::<_, hyper::Error>(builder.body(Body::from("{}")).unwrap())
+                        }
+                    }))
+                }
+            })
+        };
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, seen)
+    }
+
+    #[tokio::test]
+    async fn signs_each_attempt_over_the_body_sent() {
+        let (addr, seen) = serve().await;
+        let client = ApiClient::new()
+            .with_api_key("key")
+            .with_webhook_signer(WebhookSigner::new(b"secret"))
+            .with_throttle(Arc::new(Throttle::default().with_retries(1)));
+        client.post_noback(&format!("http://{}/hook", addr), &serde_json::json!({"id": 1})).await.unwrap();
+        client.post_noback(&format!("http://{}/retry", addr), &serde_json::json!({"id": 2})).await.unwrap();
+        let verifier = WebhookVerifier::new(b"secret");
+        let seen = seen.lock().unwrap();
+        let paths: Vec<(&Method, &str)> = seen.iter().map(|(method, path, _, _)| (method, path.as_str())).collect();
+        assert_eq!(paths, [(&Method::POST, "/hook"), (&Method::GET, "/done"), (&Method::POST, "/retry"), (&Method::POST, "/retry")]);
+        for (method, _, headers, body) in seen.iter() {
+            if *method == Method::GET {
+                // the 303 rewrote the request to a bodiless GET, which is not signed
+                assert!(body.is_empty());
+                assert!(!headers.contains_key(DEFAULT_SIGNATURE_HEADER));
+            } else {
+                verifier.verify(headers, body).unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn limits_the_body_as_received() {
+        let (addr, _) = serve().await;
+        let url = format!("http://{}/body", addr);
+        let small = ApiClient::new().with_max_decompressed(1);
+        assert!(matches!(small.get::<serde_json::Value>(&url).await, Err(HypErr::BodyTooLarge(1))));
+        assert_eq!(ApiClient::new().with_max_decompressed(2).get::<serde_json::Value>(&url).await.unwrap(), serde_json::json!({}));
+    }
+
+    #[tokio::test]
+    async fn unencodable_forms_are_format_errors() {
+        let (addr, seen) = serve().await;
+        // a urlencoded form has no way to express a nested object
+        let nested = serde_json::json!({"user": {"name": "a"}});
+        let result = ApiClient::new().post_form::<_, serde_json::Value>(&format!("http://{}/form", addr), &nested).await;
+        assert!(matches!(result, Err(HypErr::Format(_))));
+        assert!(seen.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,304 @@
+//! The breaker module stops an ApiClient from waiting on hosts that keep failing.
+//! Each host has a circuit that opens when too many recent requests failed, refusing requests
+//! with HypErr::CircuitOpen until a cooldown has passed and a trial request succeeds.
+
+
+// standard library
+use std::{collections::HashMap, sync::Mutex, time::{Duration, Instant}};
+// crates.io
+use serde::Serialize;
+// this crate
+use crate::err::HypErr;
+
+
+/// The state of the circuit for one host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// requests go through, and their outcomes are counted
+    Closed,
+    /// requests fail fast until the cooldown has passed
+    Open,
+    /// the cooldown has passed and a trial request decides whether to close or reopen
+    HalfOpen,
+}
+
+
+// the counts and state of one host
+#[derive(Debug)]
+struct Circuit {
+    state: CircuitState,
+    window_started: Instant,
+    successes: u32,
+    failures: u32,
+    opened_at: Instant,
+    // the id of the trial request let through while half open, and when it started
+    probe: Option<(u64, Instant)>,
+    // the id given to the next trial request
+    next_probe: u64,
+}
+
+
+impl Circuit {
+    fn new(now: Instant) -> Self {
+        Circuit{
+            state: CircuitState::Closed,
+            window_started: now,
+            successes: 0,
+            failures: 0,
+            opened_at: now,
+            probe: None,
+            next_probe: 0,
+        }
+    }
+
+    fn reset_counts(&mut self, now: Instant) {
+        self.window_started = now;
+        self.successes = 0;
+        self.failures = 0;
+    }
+
+    fn open(&mut self, now: Instant) {
+        self.state = CircuitState::Open;
+        self.opened_at = now;
+        self.probe = None;
+    }
+}
+
+
+/// A CircuitPermit is returned by CircuitBreaker::allow and passed back to CircuitBreaker::record,
+/// so that only the outcome of the trial request decides whether a half open circuit closes
+#[derive(Debug)]
+#[must_use]
+pub struct CircuitPermit {
+    probe: Option<u64>,
+}
+
+
+/// A CircuitBreaker tracks a circuit per host (the authority of the url, i.e. "api.partner.com:8443").
+/// A circuit opens once at least min_requests were made in the current window and the share of them
+/// that failed reaches failure_rate. Clients holding the same breaker see the same circuits, so
+/// failures seen by one spare the others from waiting on the host:
+/// # Examples:
+/// ```ignore
+/// let breaker = Arc::new(CircuitBreaker::new().with_cooldown(Duration::from_secs(10)));
+/// let client = ApiClient::new().with_circuit_breaker(breaker.clone());
+/// match client.post::<Order, Receipt>(url, &order).await {
+///     Err(HypErr::CircuitOpen(host)) => ..., // the request was never sent
+///     ...
+/// }
+/// // i.e. in a health check handler
+/// server::build_response_json(&breaker.states())
+/// ```
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_rate: f64,
+    min_requests: u32,
+    window: Duration,
+    cooldown: Duration,
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+
+impl CircuitBreaker {
+    /// Open after half of at least 10 requests in a minute failed, and try again after 30 seconds
+    pub fn new() -> Self {
+        CircuitBreaker{
+            failure_rate: 0.5,
+            min_requests: 10,
+            window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(30),
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Open when this share (between 0 and 1) of the requests in the window failed. 0 never opens
+    pub fn with_failure_rate(mut self, failure_rate: f64) -> Self {
+        self.failure_rate = failure_rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Never open before this many requests were made in the window
+    pub fn with_min_requests(mut self, min_requests: u32) -> Self {
+        self.min_requests = min_requests.max(1);
+        self
+    }
+
+    /// Count requests over windows of this length
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Stay open this long before letting a trial request through
+    pub fn with_cooldown(mut self, cooldown: Duration) -> Self {
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// Return a permit if a request to host may be sent, or HypErr::CircuitOpen if it should fail fast.
+    /// Pass the permit to record along with the outcome of the request
+    pub fn allow(&self, host: &str) -> Result<CircuitPermit, HypErr> {
+        let now = Instant::now();
+        let mut circuits = self.circuits.lock().expect("circuit breaker lock poisoned");
+        let circuit = circuits.entry(host.to_string()).or_insert_with(|| Circuit::new(now));
+        if circuit.state == CircuitState::Open && now.duration_since(circuit.opened_at) >= self.cooldown {
+            circuit.state = CircuitState::HalfOpen;
+        }
+        match circuit.state {
+            CircuitState::Closed => Ok(CircuitPermit{probe: None}),
+            CircuitState::Open => Err(HypErr::CircuitOpen(host.to_string())),
+            CircuitState::HalfOpen => {
+                // one trial at a time, unless the last one never reported back within a cooldown
+                let probing = circuit.probe.is_some_and(|(_, started)| now.duration_since(started) < self.cooldown);
+                if probing {
+                    return Err(HypErr::CircuitOpen(host.to_string()))
+                }
+                let id = circuit.next_probe;
+                circuit.next_probe += 1;
+                circuit.probe = Some((id, now));
+                Ok(CircuitPermit{probe: Some(id)})
+            },
+        }
+    }
+
+    /// Record whether a request to host, sent with a permit from allow, succeeded
+    pub fn record(&self, host: &str, permit: CircuitPermit, success: bool) {
+        let now = Instant::now();
+        let mut circuits = self.circuits.lock().expect("circuit breaker lock poisoned");
+        let circuit = circuits.entry(host.to_string()).or_insert_with(|| Circuit::new(now));
+        let is_probe = permit.probe.is_some_and(|id| circuit.probe.is_some_and(|(probe, _)| probe == id));
+        match circuit.state {
+            CircuitState::Closed => {
+                if now.duration_since(circuit.window_started) >= self.window {
+                    circuit.reset_counts(now);
+                }
+                if success {
+                    circuit.successes += 1;
+                } else {
+                    circuit.failures += 1;
+                }
+                let total = circuit.successes + circuit.failures;
+                let tripped = f64::from(circuit.failures) >= f64::from(total) * self.failure_rate;
+                if self.failure_rate > 0.0 && total >= self.min_requests && tripped {
+                    circuit.open(now);
+                }
+            },
+            // only the trial request decides, not one sent before the circuit opened that finished late
+            CircuitState::HalfOpen if !is_probe => {},
+            CircuitState::HalfOpen if success => {
+                circuit.state = CircuitState::Closed;
+                circuit.probe = None;
+                circuit.reset_counts(now);
+            },
+            CircuitState::HalfOpen => circuit.open(now),
+            // a request sent before the circuit opened tells us nothing new
+            CircuitState::Open => {},
+        }
+    }
+
+    /// Return the state of the circuit for host. Hosts never called are Closed
+    pub fn state(&self, host: &str) -> CircuitState {
+        let circuits = self.circuits.lock().expect("circuit breaker lock poisoned");
+        circuits.get(host).map_or(CircuitState::Closed, |circuit| self.current_state(circuit))
+    }
+
+    /// Return the state of every host called so far, i.e. to report in a health check
+    pub fn states(&self) -> HashMap<String, CircuitState> {
+        let circuits = self.circuits.lock().expect("circuit breaker lock poisoned");
+        circuits.iter().map(|(host, circuit)| (host.clone(), self.current_state(circuit))).collect()
+    }
+
+    // an open circuit whose cooldown has passed is half open, even before the next request
+    fn current_state(&self, circuit: &Circuit) -> CircuitState {
+        match circuit.state {
+            CircuitState::Open if circuit.opened_at.elapsed() >= self.cooldown => CircuitState::HalfOpen,
+            state => state,
+        }
+    }
+}
+
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: &str = "api.partner.com";
+
+    fn send(breaker: &CircuitBreaker, success: bool) {
+        let permit = breaker.allow(HOST).unwrap();
+        breaker.record(HOST, permit, success);
+    }
+
+    #[test]
+    fn opens_at_the_failure_rate() {
+        let breaker = CircuitBreaker::new().with_min_requests(4).with_failure_rate(0.5);
+        send(&breaker, false);
+        send(&breaker, false);
+        send(&breaker, true);
+        assert_eq!(breaker.state(HOST), CircuitState::Closed);
+        send(&breaker, true);
+        assert_eq!(breaker.state(HOST), CircuitState::Open);
+        assert!(matches!(breaker.allow(HOST), Err(HypErr::CircuitOpen(host)) if host == HOST));
+        assert_eq!(breaker.state("other.com"), CircuitState::Closed);
+    }
+
+    #[test]
+    fn zero_failure_rate_never_opens() {
+        let breaker = CircuitBreaker::new().with_min_requests(2).with_failure_rate(0.0);
+        for success in [true, true, false, false, false] {
+            send(&breaker, success);
+        }
+        assert_eq!(breaker.state(HOST), CircuitState::Closed);
+    }
+
+    #[test]
+    fn counts_restart_each_window() {
+        let breaker = CircuitBreaker::new().with_min_requests(2).with_window(Duration::ZERO);
+        send(&breaker, false);
+        send(&breaker, false);
+        assert_eq!(breaker.state(HOST), CircuitState::Closed);
+    }
+
+    #[test]
+    fn probe_closes_or_reopens() {
+        let breaker = CircuitBreaker::new().with_min_requests(1).with_cooldown(Duration::ZERO);
+        send(&breaker, false);
+        assert_eq!(breaker.state(HOST), CircuitState::HalfOpen);
+        send(&breaker, false);
+        assert_eq!(breaker.state(HOST), CircuitState::HalfOpen);
+        send(&breaker, true);
+        assert_eq!(breaker.state(HOST), CircuitState::Closed);
+    }
+
+    #[test]
+    fn one_probe_at_a_time() {
+        let breaker = CircuitBreaker::new().with_min_requests(1).with_cooldown(Duration::from_millis(20));
+        send(&breaker, false);
+        std::thread::sleep(Duration::from_millis(25));
+        let probe = breaker.allow(HOST).unwrap();
+        assert!(breaker.allow(HOST).is_err());
+        breaker.record(HOST, probe, true);
+        assert_eq!(breaker.state(HOST), CircuitState::Closed);
+    }
+
+    #[test]
+    fn late_results_do_not_decide_half_open() {
+        let breaker = CircuitBreaker::new().with_min_requests(1).with_cooldown(Duration::ZERO);
+        let early = breaker.allow(HOST).unwrap();
+        send(&breaker, false);
+        let probe = breaker.allow(HOST).unwrap();
+        // a request let through while closed finishes during the trial
+        breaker.record(HOST, early, true);
+        assert_eq!(breaker.state(HOST), CircuitState::HalfOpen);
+        breaker.record(HOST, probe, false);
+        assert_eq!(breaker.circuits.lock().unwrap()[HOST].state, CircuitState::Open);
+    }
+}
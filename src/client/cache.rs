@@ -0,0 +1,463 @@
+//! The cache module is an opt-in http cache for GET requests made with an ApiClient.
+//! It follows Cache-Control and Expires, revalidates stale responses with If-None-Match/If-Modified-Since,
+//! and can optionally mirror its entries to a directory so they survive restarts.
+
+
+// standard library
+use std::{path::{Path, PathBuf}, sync::{Mutex, atomic::{AtomicU64, Ordering}}, time::{Duration, SystemTime}};
+// crates.io
+use bytes::Bytes;
+use hashlink::LruCache;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+// this crate
+use crate::util::{to_hex, unix_now};
+
+
+/// The default number of responses kept in memory
+pub const DEFAULT_MAX_ENTRIES: usize = 1000;
+
+
+/// A snapshot of how often the cache has been useful
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct CacheStats {
+    /// responses served from the cache without a request
+    pub hits: u64,
+    /// responses that had to be fetched in full
+    pub misses: u64,
+    /// stale responses the server confirmed were unchanged (304 Not Modified)
+    pub revalidations: u64,
+}
+
+
+/// A cached response body along with what is needed to decide if it is still fresh
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// the response may be used without revalidation until this many seconds after the unix epoch
+    pub fresh_until: u64,
+    /// when the entry was stored, in seconds after the unix epoch
+    pub stored_at: u64,
+    /// the decompressed response body
+    #[serde(skip)]
+    pub body: Bytes,
+}
+
+
+impl CacheEntry {
+    /// Returns true if the entry can be used without asking the server
+    pub fn is_fresh(&self) -> bool {
+        unix_now() < self.fresh_until
+    }
+
+    /// Add If-None-Match/If-Modified-Since headers, so the server can answer 304 if nothing changed
+    pub fn add_validators(&self, headers: &mut HeaderMap) {
+        if let Some(etag) = self.etag.as_ref().and_then(|v| HeaderValue::from_str(v).ok()) {
+            headers.insert(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = self.last_modified.as_ref().and_then(|v| HeaderValue::from_str(v).ok()) {
+            headers.insert(header::IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
+    fn has_validators(&self) -> bool {
+        self.etag.is_some() || self.last_modified.is_some()
+    }
+}
+
+
+/// HttpCache stores GET responses in memory, and optionally on disk.
+/// Clients holding the same Arc<HttpCache> serve each other's cached responses; entries are still
+/// kept apart by X-Api-Key, Accept, cookies and decompression setting, see cache_key.
+/// # Examples:
+/// ```ignore
+/// let cache = Arc::new(HttpCache::new().with_disk("/var/cache/myservice")?);
+/// let client = ApiClient::new().with_cache(cache.clone());
+/// let countries: Vec<Country> = client.get("http://refdata/countries").await?;
+/// println!("{:?}", cache.stats());
+/// ```
+#[derive(Debug)]
+pub struct HttpCache {
+    // least recently used entries are evicted first
+    entries: Mutex<LruCache<String, CacheEntry>>,
+    max_entries: usize,
+    disk_dir: Option<PathBuf>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    revalidations: AtomicU64,
+}
+
+
+impl HttpCache {
+    /// Create an in-memory cache holding up to DEFAULT_MAX_ENTRIES responses
+    pub fn new() -> Self {
+        HttpCache{
+            entries: Mutex::new(LruCache::new(DEFAULT_MAX_ENTRIES)),
+            max_entries: DEFAULT_MAX_ENTRIES,
+            disk_dir: None,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            revalidations: AtomicU64::new(0),
+        }
+    }
+
+    /// Keep at most this many responses, evicting the least recently used first
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries.max(1);
+        let entries = self.entries.get_mut().expect("cache lock poisoned");
+        while entries.len() > self.max_entries {
+            if let (Some((key, _)), Some(dir)) = (entries.remove_lru(), &self.disk_dir) {
+                remove_files(dir, &key);
+            }
+        }
+        entries.set_capacity(self.max_entries);
+        self
+    }
+
+    /// Also persist entries to files in this directory, creating it if needed, so they survive a restart.
+    /// The directory mirrors the entries in memory: the files of an evicted entry are deleted, and when
+    /// the cache is opened the max_entries most recently stored entries are loaded and the rest deleted.
+    /// Call with_max_entries first to change how many that is.
+    pub fn with_disk<P: Into<PathBuf>>(mut self, dir: P) -> Result<Self, std::io::Error> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        // every entry is a {key}.json file with its metadata and a {key}.body file
+        let mut keys: Vec<String> = Vec::new();
+        for file in std::fs::read_dir(&dir)? {
+            let path = file?.path();
+            let is_entry = path.extension().is_some_and(|ext| ext == "json" || ext == "body");
+            if let Some(key) = path.file_stem().and_then(|stem| stem.to_str()).filter(|_| is_entry) {
+                if !keys.iter().any(|seen| seen == key) {
+                    keys.push(key.to_string());
+                }
+            }
+        }
+        let mut stored: Vec<(u64, String, CacheEntry)> = Vec::new();
+        for key in keys {
+            match read_entry(&dir, &key) {
+                Some(entry) => stored.push((entry.stored_at, key, entry)),
+                None => remove_files(&dir, &key),
+            }
+        }
+        // the most recently stored come last, so they are the most recently used
+        stored.sort_by_key(|(stored_at, _, _)| *stored_at);
+        let kept = stored.split_off(stored.len().saturating_sub(self.max_entries));
+        for (_, key, _) in stored {
+            remove_files(&dir, &key);
+        }
+        let entries = self.entries.get_mut().expect("cache lock poisoned");
+        for (_, key, entry) in kept {
+            entries.insert(key, entry);
+        }
+        self.disk_dir = Some(dir);
+        Ok(self)
+    }
+
+    /// Return the hit, miss and revalidation counts so far
+    pub fn stats(&self) -> CacheStats {
+        CacheStats{
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            revalidations: self.revalidations.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Remove every entry, from memory and disk
+    pub async fn clear(&self) {
+        self.entries.lock().expect("cache lock poisoned").clear();
+        if let Some(dir) = &self.disk_dir {
+            if let Ok(mut files) = tokio::fs::read_dir(dir).await {
+                while let Ok(Some(file)) = files.next_entry().await {
+                    let _ = tokio::fs::remove_file(file.path()).await;
+                }
+            }
+        }
+    }
+
+    pub fn record_hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Look up the entry for a key, from memory or else from disk
+    pub async fn lookup(&self, key: &str) -> Option<CacheEntry> {
+        if let Some(entry) = self.entries.lock().expect("cache lock poisoned").get(key) {
+            return Some(entry.clone())
+        }
+        // another process sharing the directory may have stored it since
+        let dir = self.disk_dir.as_ref()?;
+        let meta = tokio::fs::read(dir.join(format!("{}.json", key))).await.ok()?;
+        let body = tokio::fs::read(dir.join(format!("{}.body", key))).await.ok()?;
+        let mut entry: CacheEntry = serde_json::from_slice(&meta).ok()?;
+        entry.body = Bytes::from(body);
+        self.insert_memory(key, entry.clone()).await;
+        Some(entry)
+    }
+
+    /// Store a 200 response if its headers allow it. Responses marked no-store, responses that vary on
+    /// headers other than Accept-Encoding, and responses that could never be reused are skipped.
+    pub async fn store(&self, key: &str, headers: &HeaderMap, body: Bytes) {
+        let varies = header_str(headers, header::VARY)
+            .is_some_and(|vary| vary.split(',').any(|v| !v.trim().eq_ignore_ascii_case("accept-encoding")));
+        let directives = CacheControl::parse(headers);
+        if directives.no_store || varies {
+            return
+        }
+        let now = unix_now();
+        let entry = CacheEntry{
+            etag: header_str(headers, header::ETAG).map(|v| v.to_string()),
+            last_modified: header_str(headers, header::LAST_MODIFIED).map(|v| v.to_string()),
+            fresh_until: now + freshness_lifetime(headers, &directives).as_secs(),
+            stored_at: now,
+            body,
+        };
+        if !entry.is_fresh() && !entry.has_validators() {
+            return // it would never be used
+        }
+        self.insert_memory(key, entry.clone()).await;
+        self.write_disk(key, &entry).await;
+    }
+
+    /// Update an entry after the server answered 304 Not Modified, returning its body
+    pub async fn revalidated(&self, key: &str, mut entry: CacheEntry, headers: &HeaderMap) -> Bytes {
+        self.revalidations.fetch_add(1, Ordering::Relaxed);
+        let directives = CacheControl::parse(headers);
+        entry.fresh_until = unix_now() + freshness_lifetime(headers, &directives).as_secs();
+        if let Some(etag) = header_str(headers, header::ETAG) {
+            entry.etag = Some(etag.to_string());
+        }
+        let body = entry.body.clone();
+        self.insert_memory(key, entry.clone()).await;
+        self.write_disk(key, &entry).await;
+        body
+    }
+
+    // insert an entry, evicting the least recently used one if the cache is full, along with its files
+    async fn insert_memory(&self, key: &str, entry: CacheEntry) {
+        let evicted = {
+            let mut entries = self.entries.lock().expect("cache lock poisoned");
+            let evicted = match entries.len() >= self.max_entries && !entries.contains_key(key) {
+                true => entries.remove_lru().map(|(evicted, _)| evicted),
+                false => None,
+            };
+            entries.insert(key.to_string(), entry);
+            evicted
+        };
+        if let (Some(evicted), Some(dir)) = (evicted, &self.disk_dir) {
+            let _ = tokio::fs::remove_file(dir.join(format!("{}.json", evicted))).await;
+            let _ = tokio::fs::remove_file(dir.join(format!("{}.body", evicted))).await;
+        }
+    }
+
+    async fn write_disk(&self, key: &str, entry: &CacheEntry) {
+        let dir = match &self.disk_dir {
+            Some(dir) => dir,
+            None => return,
+        };
+        let meta = match serde_json::to_vec(entry) {
+            Ok(meta) => meta,
+            Err(_) => return,
+        };
+        // the disk copy is best effort, the in-memory copy is still good if this fails
+        let _ = tokio::fs::write(dir.join(format!("{}.body", key)), &entry.body).await;
+        let _ = tokio::fs::write(dir.join(format!("{}.json", key)), meta).await;
+    }
+}
+
+
+// read the entry stored under key in dir, if both of its files are there and readable
+fn read_entry(dir: &Path, key: &str) -> Option<CacheEntry> {
+    let meta = std::fs::read(dir.join(format!("{}.json", key))).ok()?;
+    let body = std::fs::read(dir.join(format!("{}.body", key))).ok()?;
+    let mut entry: CacheEntry = serde_json::from_slice(&meta).ok()?;
+    entry.body = Bytes::from(body);
+    Some(entry)
+}
+
+
+fn remove_files(dir: &Path, key: &str) {
+    let _ = std::fs::remove_file(dir.join(format!("{}.json", key)));
+    let _ = std::fs::remove_file(dir.join(format!("{}.body", key)));
+}
+
+
+impl Default for HttpCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+/// Derive the cache key for a request. The X-Api-Key is part of the key, so responses fetched with
+/// one key are never served to a client using another. So are the Accept header, since one url may
+/// be fetched as JSON or as a binary format, the Cookie header sent, so a response for one session
+/// is never served to another, and whether the client decompresses responses,
+/// since a client that does not expects bodies in the encoding the server sent.
+pub fn cache_key(url: &str, x_api_key: &str, accept: &str, cookie: &str, decompressed: bool) -> String {
+    let mut hasher = Sha256::new();
+    for part in [url, x_api_key, accept, cookie] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    hasher.update([decompressed as u8]);
+    to_hex(&hasher.finalize())
+}
+
+
+// the Cache-Control directives a private client cache cares about
+#[derive(Debug, Default)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    max_age: Option<u64>,
+}
+
+impl CacheControl {
+    fn parse(headers: &HeaderMap) -> Self {
+        let mut directives = CacheControl::default();
+        for value in headers.get_all(header::CACHE_CONTROL).iter().filter_map(|v| v.to_str().ok()) {
+            for directive in value.split(',') {
+                let (name, arg) = match directive.split_once('=') {
+                    Some((name, arg)) => (name.trim(), Some(arg.trim().trim_matches('"'))),
+                    None => (directive.trim(), None),
+                };
+                match name.to_ascii_lowercase().as_str() {
+                    "no-store" => directives.no_store = true,
+                    "no-cache" => directives.no_cache = true,
+                    "max-age" => directives.max_age = arg.and_then(|a| a.parse().ok()),
+                    _ => {},
+                }
+            }
+        }
+        directives
+    }
+}
+
+
+// how long a response stays fresh, from Cache-Control max-age, or else Expires
+fn freshness_lifetime(headers: &HeaderMap, directives: &CacheControl) -> Duration {
+    if directives.no_cache {
+        return Duration::ZERO
+    }
+    // the Age header says how long the response already sat in a shared cache
+    let age = header_str(headers, header::AGE).and_then(|v| v.parse::<u64>().ok()).unwrap_or(0);
+    if let Some(max_age) = directives.max_age {
+        return Duration::from_secs(max_age.saturating_sub(age))
+    }
+    let expires = header_str(headers, header::EXPIRES).and_then(|v| httpdate::parse_http_date(v).ok());
+    if let Some(expires) = expires {
+        let date = header_str(headers, header::DATE)
+            .and_then(|v| httpdate::parse_http_date(v).ok())
+            .unwrap_or_else(SystemTime::now);
+        return expires.duration_since(date).unwrap_or(Duration::ZERO)
+    }
+    Duration::ZERO
+}
+
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(header::HeaderName::from_bytes(name.as_bytes()).unwrap(), HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn key_separates_variants() {
+        let key = cache_key("http://a/x", "key", "application/json", "", true);
+        assert_eq!(key, cache_key("http://a/x", "key", "application/json", "", true));
+        assert_ne!(key, cache_key("http://a/x", "other", "application/json", "", true));
+        assert_ne!(key, cache_key("http://a/x", "key", "application/cbor", "", true));
+        assert_ne!(key, cache_key("http://a/x", "key", "application/json", "session=1", true));
+        assert_ne!(key, cache_key("http://a/x", "key", "application/json", "", false));
+        // the separators keep parts from running into each other
+        assert_ne!(cache_key("ab", "c", "", "", true), cache_key("a", "bc", "", "", true));
+    }
+
+    #[test]
+    fn freshness_from_headers() {
+        let lifetime = |pairs: &[(&str, &str)]| {
+            let headers = headers(pairs);
+            freshness_lifetime(&headers, &CacheControl::parse(&headers)).as_secs()
+        };
+        assert_eq!(lifetime(&[("cache-control", "public, max-age=60")]), 60);
+        assert_eq!(lifetime(&[("cache-control", "max-age=60"), ("age", "15")]), 45);
+        assert_eq!(lifetime(&[("cache-control", "max-age=60, no-cache")]), 0);
+        assert_eq!(lifetime(&[("date", "Sun, 18 Oct 2026 10:00:00 GMT"), ("expires", "Sun, 18 Oct 2026 10:05:00 GMT")]), 300);
+        assert_eq!(lifetime(&[]), 0);
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used() {
+        let cache = HttpCache::new().with_max_entries(2);
+        let fresh = headers(&[("cache-control", "max-age=60")]);
+        cache.store("a", &fresh, Bytes::from("A")).await;
+        cache.store("b", &fresh, Bytes::from("B")).await;
+        // reading a makes b the least recently used
+        assert!(cache.lookup("a").await.is_some());
+        cache.store("c", &fresh, Bytes::from("C")).await;
+        assert!(cache.lookup("b").await.is_none());
+        assert_eq!(cache.lookup("a").await.unwrap().body, "A");
+        assert_eq!(cache.lookup("c").await.unwrap().body, "C");
+    }
+
+    #[tokio::test]
+    async fn skips_uncacheable_responses() {
+        let cache = HttpCache::new();
+        cache.store("no-store", &headers(&[("cache-control", "no-store, max-age=60")]), Bytes::new()).await;
+        cache.store("varies", &headers(&[("cache-control", "max-age=60"), ("vary", "Accept-Encoding, Cookie")]), Bytes::new()).await;
+        cache.store("useless", &headers(&[]), Bytes::new()).await;
+        cache.store("validated", &headers(&[("etag", "\"v1\"")]), Bytes::new()).await;
+        assert!(cache.lookup("no-store").await.is_none());
+        assert!(cache.lookup("varies").await.is_none());
+        assert!(cache.lookup("useless").await.is_none());
+        let entry = cache.lookup("validated").await.unwrap();
+        assert!(!entry.is_fresh());
+        assert_eq!(entry.etag.as_deref(), Some("\"v1\""));
+    }
+
+    #[tokio::test]
+    async fn disk_mirrors_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = || std::fs::read_dir(dir.path()).unwrap().count();
+        let fresh = headers(&[("cache-control", "max-age=60")]);
+        let cache = HttpCache::new().with_max_entries(2).with_disk(dir.path()).unwrap();
+        cache.store("a", &fresh, Bytes::from("A")).await;
+        cache.store("b", &fresh, Bytes::from("B")).await;
+        cache.store("c", &fresh, Bytes::from("C")).await;
+        // a was evicted, so its two files were deleted
+        assert_eq!(files(), 4);
+        assert!(!dir.path().join("a.body").exists());
+        drop(cache);
+
+        // reopening with room for one keeps the most recently stored entry, on disk and in memory
+        std::fs::write(dir.path().join("stray.body"), "orphan").unwrap();
+        let stored_at = |key: &str, at: u64| {
+            let path = dir.path().join(format!("{}.json", key));
+            let mut entry: CacheEntry = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+            entry.stored_at = at;
+            std::fs::write(path, serde_json::to_vec(&entry).unwrap()).unwrap();
+        };
+        stored_at("b", 1);
+        stored_at("c", 2);
+        let cache = HttpCache::new().with_max_entries(1).with_disk(dir.path()).unwrap();
+        assert_eq!(files(), 2);
+        assert_eq!(cache.lookup("c").await.unwrap().body, "C");
+        assert!(cache.lookup("b").await.is_none());
+    }
+}
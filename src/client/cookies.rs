@@ -0,0 +1,168 @@
+//! The cookies module is an opt-in cookie jar for ApiClient, for upstreams that hand out
+//! session cookies, i.e. after a login POST. Cookies are stored and sent back following their
+//! Domain, Path, Secure and expiry attributes, and can be saved to a JSON file to survive restarts.
+
+
+// standard library
+use std::{path::{Path, PathBuf}, sync::Mutex};
+// crates.io
+use cookie_store::{CookieStore, RawCookie};
+use hyper::header::{self, HeaderMap, HeaderValue};
+use url::Url;
+// this crate
+use crate::err::HypErr;
+
+
+/// CookieJar holds the cookies set by the servers a client talks to.
+/// Clients holding the same jar act as one session, so a login made through one is used by the others:
+/// # Examples:
+/// ```ignore
+/// let jar = Arc::new(CookieJar::load("/var/lib/myservice/cookies.json")?);
+/// let client = ApiClient::new().with_cookie_jar(jar.clone());
+/// client.post_noback("https://partner.example.com/login", &login).await?;
+/// let orders: Vec<Order> = client.get("https://partner.example.com/orders").await?;
+/// jar.save().await?;
+/// ```
+#[derive(Debug, Default)]
+pub struct CookieJar {
+    store: Mutex<CookieStore>,
+    file: Option<PathBuf>,
+}
+
+
+impl CookieJar {
+    /// Create an empty jar that only lives in memory
+    pub fn new() -> Self {
+        CookieJar::default()
+    }
+
+    /// Create a jar backed by a JSON file, loading the cookies already in it.
+    /// A missing file gives an empty jar, which save() will create
+    pub fn load<P: Into<PathBuf>>(path: P) -> Result<Self, HypErr> {
+        let path = path.into();
+        let store = match std::fs::File::open(&path) {
+            Ok(file) => cookie_store::serde::json::load(std::io::BufReader::new(file)).map_err(store_error)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => CookieStore::default(),
+            Err(err) => return Err(HypErr::Io(err)),
+        };
+        Ok(CookieJar{store: Mutex::new(store), file: Some(path)})
+    }
+
+    /// Write the persistent cookies to the file the jar was loaded from.
+    /// Session cookies (without Expires or Max-Age) and expired cookies are left out, as a browser would
+    pub async fn save(&self) -> Result<(), HypErr> {
+        match &self.file {
+            Some(path) => self.save_to(path).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Write the persistent cookies to a JSON file
+    pub async fn save_to<P: AsRef<Path>>(&self, path: P) -> Result<(), HypErr> {
+        let mut json = Vec::new();
+        {
+            let store = self.store.lock().expect("cookie jar lock poisoned");
+            cookie_store::serde::json::save(&store, &mut json).map_err(store_error)?;
+        }
+        tokio::fs::write(path, json).await?;
+        Ok(())
+    }
+
+    /// Return the name and value of every cookie that would be sent to url
+    pub fn cookies(&self, url: &Url) -> Vec<(String, String)> {
+        let store = self.store.lock().expect("cookie jar lock poisoned");
+        store.get_request_values(url).map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    /// Remove every cookie
+    pub fn clear(&self) {
+        self.store.lock().expect("cookie jar lock poisoned").clear();
+    }
+
+    /// Add a Cookie header with the cookies matching url, replacing any already there
+    pub fn add_request_cookies(&self, url: &Url, headers: &mut HeaderMap) {
+        let cookies = self.cookies(url);
+        if cookies.is_empty() {
+            return
+        }
+        let cookie_header = cookies.iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("; ");
+        if let Ok(value) = HeaderValue::from_str(&cookie_header) {
+            headers.insert(header::COOKIE, value);
+        }
+    }
+
+    /// Store the Set-Cookie headers of a response from url. Cookies the url may not set,
+    /// i.e. for another domain, are ignored, and expired ones remove the stored cookie
+    pub fn store_response_cookies(&self, url: &Url, headers: &HeaderMap) {
+        let cookies = headers.get_all(header::SET_COOKIE).iter()
+            .filter_map(|v| v.to_str().ok())
+            .filter_map(|v| RawCookie::parse(v.to_string()).ok())
+            .collect::<Vec<_>>();
+        if cookies.is_empty() {
+            return
+        }
+        let mut store = self.store.lock().expect("cookie jar lock poisoned");
+        store.store_response_cookies(cookies.into_iter(), url);
+    }
+}
+
+
+fn store_error(err: Box<dyn std::error::Error + Send + Sync>) -> HypErr {
+    HypErr::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set_cookies(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(header::SET_COOKIE, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn sends_cookies_by_domain_path_and_scheme() {
+        let jar = CookieJar::new();
+        let login = Url::parse("https://api.partner.com/login").unwrap();
+        jar.store_response_cookies(&login, &set_cookies(&[
+            "session=abc; Path=/; Secure",
+            "scoped=1; Path=/orders",
+            "foreign=1; Domain=other.com",
+        ]));
+        let mut headers = HeaderMap::new();
+        jar.add_request_cookies(&Url::parse("https://api.partner.com/orders/7").unwrap(), &mut headers);
+        let cookie = headers[header::COOKIE].to_str().unwrap();
+        assert!(cookie.contains("session=abc") && cookie.contains("scoped=1"));
+        assert_eq!(jar.cookies(&Url::parse("http://api.partner.com/").unwrap()), Vec::new());
+        assert!(jar.cookies(&Url::parse("https://other.com/").unwrap()).is_empty());
+    }
+
+    #[test]
+    fn expired_cookies_remove_stored_ones() {
+        let jar = CookieJar::new();
+        let url = Url::parse("https://api.partner.com/").unwrap();
+        jar.store_response_cookies(&url, &set_cookies(&["session=abc"]));
+        assert_eq!(jar.cookies(&url).len(), 1);
+        jar.store_response_cookies(&url, &set_cookies(&["session=; Max-Age=0"]));
+        assert!(jar.cookies(&url).is_empty());
+    }
+
+    #[tokio::test]
+    async fn saves_only_persistent_cookies() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cookies.json");
+        let url = Url::parse("https://api.partner.com/").unwrap();
+        let jar = CookieJar::load(&path).unwrap();
+        jar.store_response_cookies(&url, &set_cookies(&["session=abc", "remember=yes; Max-Age=3600"]));
+        jar.save().await.unwrap();
+        let loaded = CookieJar::load(&path).unwrap();
+        assert_eq!(loaded.cookies(&url), vec![("remember".to_string(), "yes".to_string())]);
+    }
+}
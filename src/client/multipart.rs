@@ -0,0 +1,294 @@
+//! The multipart module builds multipart/form-data request bodies for ApiClient::post_multipart,
+//! i.e. to forward uploads to storage services. Parts can be text, JSON, bytes, or files that are
+//! streamed from disk as the request is sent rather than read into memory first.
+
+
+// standard library
+use std::{io, path::{Path, PathBuf}, pin::Pin};
+// crates.io
+use bytes::Bytes;
+use futures_util::{stream, Stream, StreamExt, TryStreamExt};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Serialize;
+use tokio_util::io::ReaderStream;
+// this crate
+use crate::err::HypErr;
+
+
+/// A stream of body chunks, as sent by hyper
+pub type BodyStream = Pin<Box<dyn Stream<Item = Result<Bytes, io::Error>> + Send>>;
+
+
+#[derive(Debug)]
+enum PartBody {
+    Bytes(Bytes),
+    File(PathBuf),
+}
+
+
+#[derive(Debug)]
+struct Part {
+    name: String,
+    file_name: Option<String>,
+    content_type: Option<String>,
+    body: PartBody,
+}
+
+
+impl Part {
+    // the boundary line and headers that come before the part's body
+    fn head(&self, boundary: &str) -> Bytes {
+        let mut head = format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"", boundary, escape(&self.name));
+        if let Some(file_name) = &self.file_name {
+            head.push_str(&format!("; filename=\"{}\"", escape(file_name)));
+        }
+        head.push_str("\r\n");
+        if let Some(content_type) = &self.content_type {
+            head.push_str(&format!("Content-Type: {}\r\n", escape_line_breaks(content_type)));
+        }
+        head.push_str("\r\n");
+        Bytes::from(head)
+    }
+}
+
+
+// quotes and line breaks are percent encoded in names, as browsers do
+fn escape(name: &str) -> String {
+    escape_line_breaks(name).replace('"', "%22")
+}
+
+
+// a line break in a header value would end the header and let the value inject others
+fn escape_line_breaks(value: &str) -> String {
+    value.replace('\r', "%0D").replace('\n', "%0A")
+}
+
+
+/// MultipartBuilder collects the parts of a multipart/form-data body.
+/// # Examples:
+/// ```ignore
+/// let form = MultipartBuilder::new()
+///     .text("folder", "invoices")
+///     .json("meta", &meta)?
+///     .file("document", "/tmp/upload-1234")
+///     .with_file_name("invoice.pdf");
+/// let stored: StoredObject = client.post_multipart("http://storage/objects", form).await?;
+/// ```
+#[derive(Debug)]
+pub struct MultipartBuilder {
+    boundary: String,
+    parts: Vec<Part>,
+}
+
+
+impl MultipartBuilder {
+    /// Create an empty form with a random boundary
+    pub fn new() -> Self {
+        let random: String = rand::thread_rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect();
+        MultipartBuilder{boundary: format!("----hyperactive{}", random), parts: Vec::new()}
+    }
+
+    fn part(mut self, name: &str, file_name: Option<String>, content_type: Option<String>, body: PartBody) -> Self {
+        self.parts.push(Part{name: name.to_string(), file_name, content_type, body});
+        self
+    }
+
+    /// Add a text field
+    pub fn text(self, name: &str, value: &str) -> Self {
+        self.part(name, None, None, PartBody::Bytes(Bytes::from(value.to_string())))
+    }
+
+    /// Add any serializable struct as a JSON part
+    pub fn json<T: Serialize>(self, name: &str, value: &T) -> Result<Self, HypErr> {
+        let json = Bytes::from(serde_json::to_vec(value)?);
+        Ok(self.part(name, None, Some("application/json".to_string()), PartBody::Bytes(json)))
+    }
+
+    /// Add a file part from memory
+    pub fn bytes(self, name: &str, file_name: &str, content_type: &str, bytes: Bytes) -> Self {
+        self.part(name, Some(file_name.to_string()), Some(content_type.to_string()), PartBody::Bytes(bytes))
+    }
+
+    /// Add a file part streamed from disk when the request is sent. The file name and
+    /// content type are taken from the path, see with_file_name and with_content_type to change them
+    pub fn file<P: AsRef<Path>>(self, name: &str, path: P) -> Self {
+        let path = path.as_ref();
+        let file_name = path.file_name().map(|name| name.to_string_lossy().to_string());
+        let content_type = mime_guess::from_path(path).first_or_octet_stream().to_string();
+        self.part(name, file_name, Some(content_type), PartBody::File(path.to_path_buf()))
+    }
+
+    /// Change the file name sent with the last part added
+    pub fn with_file_name(mut self, file_name: &str) -> Self {
+        if let Some(part) = self.parts.last_mut() {
+            part.file_name = Some(file_name.to_string());
+        }
+        self
+    }
+
+    /// Change the content type sent with the last part added. Line breaks are percent encoded,
+    /// so the value cannot end the part's headers early
+    pub fn with_content_type(mut self, content_type: &str) -> Self {
+        if let Some(part) = self.parts.last_mut() {
+            part.content_type = Some(content_type.to_string());
+        }
+        self
+    }
+
+    /// The Content-Type header for the body, including the boundary
+    pub fn content_type(&self) -> String {
+        format!("multipart/form-data; boundary={}", self.boundary)
+    }
+
+    /// Returns true if any part is streamed from disk, so the body cannot be built in memory
+    pub fn is_streamed(&self) -> bool {
+        self.parts.iter().any(|part| matches!(part.body, PartBody::File(_)))
+    }
+
+    /// Build the whole body in memory, or None if a part is streamed from disk
+    pub fn to_bytes(&self) -> Option<Bytes> {
+        let mut body = Vec::new();
+        for part in &self.parts {
+            match &part.body {
+                PartBody::Bytes(bytes) => {
+                    body.extend_from_slice(&part.head(&self.boundary));
+                    body.extend_from_slice(bytes);
+                    body.extend_from_slice(b"\r\n");
+                },
+                PartBody::File(_) => return None,
+            }
+        }
+        body.extend_from_slice(format!("--{}--\r\n", self.boundary).as_bytes());
+        Some(Bytes::from(body))
+    }
+
+    /// Turn the form into a stream of body chunks, opening each file only when its part is reached
+    pub fn into_stream(self) -> BodyStream {
+        let boundary = self.boundary;
+        let closing = Bytes::from(format!("--{}--\r\n", boundary));
+        let parts = self.parts.into_iter().map(move |part| {
+            let head = stream::once(std::future::ready(Ok(part.head(&boundary))));
+            let body: BodyStream = match part.body {
+                PartBody::Bytes(bytes) => Box::pin(stream::once(std::future::ready(Ok(bytes)))),
+                PartBody::File(path) => Box::pin(
+                    stream::once(tokio::fs::File::open(path)).map_ok(ReaderStream::new).try_flatten()
+                ),
+            };
+            let tail = stream::once(std::future::ready(Ok(Bytes::from_static(b"\r\n"))));
+            head.chain(body).chain(tail)
+        });
+        Box::pin(stream::iter(parts).flatten().chain(stream::once(std::future::ready(Ok(closing)))))
+    }
+}
+
+
+impl Default for MultipartBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{convert::Infallible, net::SocketAddr};
+    use hyper::{service::{make_service_fn, service_fn}, Body, Request, Server};
+    use crate::client::ApiClient;
+    use crate::server::{build_response_json, multipart::{get_multipart, MultipartLimits}};
+
+    // a form with a fixed boundary, so the framing can be compared byte for byte
+    fn form() -> MultipartBuilder {
+        MultipartBuilder{boundary: "b".to_string(), parts: Vec::new()}
+    }
+
+    #[test]
+    fn frames_parts_in_memory() {
+        let two_parts = form()
+            .text("a\"b", "1")
+            .bytes("doc", "x.txt", "text/plain", Bytes::from("hi"));
+        assert_eq!(two_parts.content_type(), "multipart/form-data; boundary=b");
+        let expected = concat!(
+            "--b\r\nContent-Disposition: form-data; name=\"a%22b\"\r\n\r\n1\r\n",
+            "--b\r\nContent-Disposition: form-data; name=\"doc\"; filename=\"x.txt\"\r\nContent-Type: text/plain\r\n\r\nhi\r\n",
+            "--b--\r\n",
+        );
+        assert_eq!(two_parts.to_bytes().unwrap(), expected);
+        assert_eq!(form().to_bytes().unwrap(), "--b--\r\n");
+    }
+
+    #[tokio::test]
+    async fn streams_the_same_bytes_and_reads_files_from_disk() {
+        let in_memory = form().text("a", "1").json("meta", &serde_json::json!({"n": 1})).unwrap();
+        let expected = in_memory.to_bytes().unwrap();
+        let streamed: Vec<Bytes> = in_memory.into_stream().try_collect().await.unwrap();
+        assert_eq!(streamed.concat(), expected);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("report.csv");
+        std::fs::write(&path, "a,b\n").unwrap();
+        let on_disk = form().file("doc", &path);
+        assert!(on_disk.is_streamed() && on_disk.to_bytes().is_none());
+        let streamed: Vec<Bytes> = on_disk.into_stream().try_collect().await.unwrap();
+        let expected = "--b\r\nContent-Disposition: form-data; name=\"doc\"; filename=\"report.csv\"\r\nContent-Type: text/csv\r\n\r\na,b\n\r\n--b--\r\n";
+        assert_eq!(streamed.concat(), expected.as_bytes());
+        let missing: Result<Vec<Bytes>, _> = form().file("doc", dir.path().join("missing")).into_stream().try_collect().await;
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn escapes_line_breaks_in_content_types() {
+        let injected = form().bytes("doc", "x", "text/plain", Bytes::new()).with_content_type("text/plain\r\nX-Injected: 1");
+        let body = String::from_utf8(injected.to_bytes().unwrap().to_vec()).unwrap();
+        assert!(body.contains("Content-Type: text/plain%0D%0AX-Injected: 1\r\n"));
+        assert!(!body.contains("\r\nX-Injected"));
+    }
+
+    // answer each upload with the fields and files get_multipart read from it
+    async fn serve() -> SocketAddr {
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let form = get_multipart(req, &MultipartLimits::default()).await?;
+                let files: Vec<_> = form.files.iter().map(|file| serde_json::json!({
+                    "field": file.field_name,
+                    "file_name": file.file_name,
+                    "content_type": file.content_type,
+                    "content": std::fs::read_to_string(file.path()).unwrap(),
+                })).collect();
+                build_response_json(&serde_json::json!({"fields": form.fields, "files": files}))
+            }))
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn round_trips_through_post_multipart_and_get_multipart() {
+        let addr = serve().await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("upload-1234");
+        std::fs::write(&path, "from disk").unwrap();
+        let url = format!("http://{}/upload", addr);
+        let expected = serde_json::json!({
+            "fields": [["folder", "invoices"], ["meta", "{\"n\":1}"]],
+            "files": [
+                {"field": "memo", "file_name": "memo.txt", "content_type": "text/plain", "content": "in memory"},
+                {"field": "doc", "file_name": "invoice.pdf", "content_type": "application/pdf", "content": "from disk"},
+            ],
+        });
+        let build = || MultipartBuilder::new()
+            .text("folder", "invoices")
+            .json("meta", &serde_json::json!({"n": 1})).unwrap()
+            .bytes("memo", "memo.txt", "text/plain", Bytes::from("in memory"))
+            .file("doc", &path)
+            .with_file_name("invoice.pdf")
+            .with_content_type("application/pdf");
+        // streamed from disk, and read into memory first to be signed
+        let streamed: serde_json::Value = ApiClient::new().post_multipart(&url, build()).await.unwrap();
+        assert_eq!(streamed, expected);
+        let signed = ApiClient::new().with_webhook_signer(crate::server::webhook::WebhookSigner::new(b"secret"));
+        assert_eq!(signed.post_multipart::<serde_json::Value>(&url, build()).await.unwrap(), expected);
+    }
+}
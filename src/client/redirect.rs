@@ -0,0 +1,228 @@
+//! The redirect module decides how ApiClient follows 3xx responses.
+//! 303 See Other (and 301/302 after a POST) continue with a GET, while 307 and 308 repeat the
+//! request as it was. Credentials are never forwarded to another origin.
+
+
+// crates.io
+use bytes::Bytes;
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use url::Url;
+// this crate
+use crate::err::HypErr;
+
+
+/// The most redirects followed by default
+pub const DEFAULT_MAX_HOPS: usize = 10;
+
+// headers that would hand our credentials to whoever the redirect points at
+const CREDENTIAL_HEADERS: [&str; 3] = ["X-Api-Key", "Authorization", "Cookie"];
+
+
+/// How an ApiClient follows redirects
+#[derive(Debug, Clone, Copy)]
+pub struct RedirectPolicy {
+    /// follow at most this many redirects per request, 0 returns redirects as they are
+    pub max_hops: usize,
+    /// refuse redirects to another scheme, host or port with HypErr::Redirect
+    pub same_origin_only: bool,
+}
+
+
+impl RedirectPolicy {
+    /// Follow up to DEFAULT_MAX_HOPS redirects to any origin
+    pub fn new() -> Self {
+        RedirectPolicy{max_hops: DEFAULT_MAX_HOPS, same_origin_only: false}
+    }
+
+    /// Never follow redirects
+    pub fn none() -> Self {
+        RedirectPolicy{max_hops: 0, same_origin_only: false}
+    }
+
+    pub fn with_max_hops(mut self, max_hops: usize) -> Self {
+        self.max_hops = max_hops;
+        self
+    }
+
+    pub fn with_same_origin_only(mut self, same_origin_only: bool) -> Self {
+        self.same_origin_only = same_origin_only;
+        self
+    }
+
+    /// Build the request to send after a redirect response, or None if resp is not a redirect
+    /// to follow. hops is the number of redirects already followed
+    pub fn next_request(&self, request: &Request<Bytes>, resp: &Response<Body>, hops: usize) -> Result<Option<Request<Bytes>>, HypErr> {
+        let status = resp.status();
+        let keeps_method = match status {
+            StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND | StatusCode::SEE_OTHER => false,
+            StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT => true,
+            _ => return Ok(None),
+        };
+        let location = match resp.headers().get(header::LOCATION).and_then(|v| v.to_str().ok()) {
+            Some(location) => location,
+            None => return Ok(None), // i.e. 304-like uses of 3xx, nothing to follow
+        };
+        if self.max_hops == 0 {
+            return Ok(None)
+        }
+        let current = Url::parse(&request.uri().to_string()).map_err(|e| HypErr::Redirect(e.to_string()))?;
+        let next = current.join(location).map_err(|e| HypErr::Redirect(format!("bad Location {}: {}", location, e)))?;
+        if hops >= self.max_hops {
+            return Err(HypErr::Redirect(format!("more than {} redirects, the last to {}", self.max_hops, next)))
+        }
+        let same_origin = next.origin() == current.origin();
+        if self.same_origin_only && !same_origin {
+            return Err(HypErr::Redirect(format!("refused a redirect to another origin: {}", next)))
+        }
+
+        // 303 always continues with a GET, and so do 301 and 302 after a POST, as browsers do
+        let method = request.method();
+        let rewrite = !keeps_method && (status == StatusCode::SEE_OTHER || *method == Method::POST) && *method != Method::HEAD;
+        let mut next_request = Request::new(if rewrite { Bytes::new() } else { request.body().clone() });
+        *next_request.method_mut() = if rewrite { Method::GET } else { method.clone() };
+        *next_request.uri_mut() = next.as_str().parse().map_err(|e: hyper::http::uri::InvalidUri| HypErr::Redirect(e.to_string()))?;
+        *next_request.headers_mut() = request.headers().clone();
+        let headers = next_request.headers_mut();
+        headers.remove(header::HOST);
+        if rewrite {
+            headers.remove(header::CONTENT_TYPE);
+            headers.remove(header::CONTENT_LENGTH);
+        }
+        if !same_origin {
+            for name in CREDENTIAL_HEADERS {
+                headers.remove(name);
+            }
+        }
+        Ok(Some(next_request))
+    }
+}
+
+
+impl Default for RedirectPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+/// The url a response was finally fetched from, after any redirects.
+/// ApiClient adds it to the extensions of the responses it returns
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FinalUrl(pub Url);
+
+
+/// Return the url a response was finally fetched from, if it came from an ApiClient
+pub fn final_url(resp: &Response<Body>) -> Option<&Url> {
+    resp.extensions().get::<FinalUrl>().map(|final_url| &final_url.0)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{convert::Infallible, net::SocketAddr};
+    use hyper::service::{make_service_fn, service_fn};
+    use crate::client::ApiClient;
+
+    // a POST to url carrying a body and every credential header
+    fn post(url: &str) -> Request<Bytes> {
+        let mut request = Request::new(Bytes::from("{\"id\": 1}"));
+        *request.method_mut() = Method::POST;
+        *request.uri_mut() = url.parse().unwrap();
+        let headers = request.headers_mut();
+        for name in CREDENTIAL_HEADERS {
+            headers.insert(name, "secret".parse().unwrap());
+        }
+        headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+        request
+    }
+
+    fn redirect(status: StatusCode, location: &str) -> Response<Body> {
+        Response::builder().status(status).header(header::LOCATION, location).body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn keeps_method_and_body_for_307_and_308() {
+        for status in [StatusCode::TEMPORARY_REDIRECT, StatusCode::PERMANENT_REDIRECT] {
+            let next = RedirectPolicy::new().next_request(&post("http://a.test/x"), &redirect(status, "/y"), 0).unwrap().unwrap();
+            assert_eq!(next.method(), Method::POST);
+            assert_eq!(next.uri(), "http://a.test/y");
+            assert_eq!(next.body(), "{\"id\": 1}");
+            assert_eq!(next.headers()[header::CONTENT_TYPE], "application/json");
+        }
+    }
+
+    #[test]
+    fn turns_a_post_into_a_get_for_301_302_and_303() {
+        for status in [StatusCode::MOVED_PERMANENTLY, StatusCode::FOUND, StatusCode::SEE_OTHER] {
+            let next = RedirectPolicy::new().next_request(&post("http://a.test/x"), &redirect(status, "/y"), 0).unwrap().unwrap();
+            assert_eq!(next.method(), Method::GET);
+            assert!(next.body().is_empty());
+            assert!(!next.headers().contains_key(header::CONTENT_TYPE));
+        }
+        // a 301 after a PUT keeps the method, since only POST is rewritten
+        let mut put = post("http://a.test/x");
+        *put.method_mut() = Method::PUT;
+        let next = RedirectPolicy::new().next_request(&put, &redirect(StatusCode::MOVED_PERMANENTLY, "/y"), 0).unwrap().unwrap();
+        assert_eq!((next.method(), next.body().is_empty()), (&Method::PUT, false));
+    }
+
+    #[test]
+    fn strips_credentials_only_across_origins() {
+        let policy = RedirectPolicy::new();
+        let same = policy.next_request(&post("http://a.test/x"), &redirect(StatusCode::TEMPORARY_REDIRECT, "/y"), 0).unwrap().unwrap();
+        let other = policy.next_request(&post("http://a.test/x"), &redirect(StatusCode::TEMPORARY_REDIRECT, "http://b.test/y"), 0).unwrap().unwrap();
+        // another port is another origin too
+        let port = policy.next_request(&post("http://a.test/x"), &redirect(StatusCode::TEMPORARY_REDIRECT, "http://a.test:8080/y"), 0).unwrap().unwrap();
+        for name in CREDENTIAL_HEADERS {
+            assert_eq!(same.headers()[name], "secret");
+            assert!(!other.headers().contains_key(name));
+            assert!(!port.headers().contains_key(name));
+        }
+    }
+
+    #[test]
+    fn refuses_too_many_hops_and_other_origins() {
+        let request = post("http://a.test/x");
+        let policy = RedirectPolicy::new().with_max_hops(2);
+        assert!(policy.next_request(&request, &redirect(StatusCode::FOUND, "/y"), 1).unwrap().is_some());
+        assert!(matches!(policy.next_request(&request, &redirect(StatusCode::FOUND, "/y"), 2), Err(HypErr::Redirect(_))));
+        let same_origin = RedirectPolicy::new().with_same_origin_only(true);
+        assert!(same_origin.next_request(&request, &redirect(StatusCode::FOUND, "/y"), 0).unwrap().is_some());
+        assert!(matches!(same_origin.next_request(&request, &redirect(StatusCode::FOUND, "http://b.test/y"), 0), Err(HypErr::Redirect(_))));
+        // nothing to follow: not a redirect, no Location, or redirects turned off
+        assert!(policy.next_request(&request, &Response::new(Body::empty()), 0).unwrap().is_none());
+        let no_location = Response::builder().status(StatusCode::FOUND).body(Body::empty()).unwrap();
+        assert!(policy.next_request(&request, &no_location, 0).unwrap().is_none());
+        assert!(RedirectPolicy::none().next_request(&request, &redirect(StatusCode::FOUND, "/y"), 0).unwrap().is_none());
+    }
+
+    // serve /a redirecting with a 302 to /b, which redirects with a 307 to /c
+    async fn serve() -> SocketAddr {
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let resp = match req.uri().path() {
+                    "/a" => redirect(StatusCode::FOUND, "/b"),
+                    "/b" => redirect(StatusCode::TEMPORARY_REDIRECT, "/c"),
+                    _ => Response::new(Body::from("{}")),
+                };
+                Ok::<_, Infallible>(resp)
+            }))
+        });
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn final_url_after_a_chain() {
+        let addr = serve().await;
+        let resp = ApiClient::new().get_response(&format!("http://{}/a", addr)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(final_url(&resp).unwrap().as_str(), format!("http://{}/c", addr));
+        let resp = ApiClient::new().with_redirects(RedirectPolicy::none()).get_response(&format!("http://{}/a", addr)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        assert_eq!(final_url(&resp).unwrap().as_str(), format!("http://{}/a", addr));
+    }
+}
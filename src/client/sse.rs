@@ -0,0 +1,459 @@
+//! The sse module consumes Server-Sent Events (text/event-stream) feeds,
+//! reconnecting with backoff and resuming from the Last-Event-ID when the connection drops.
+
+
+// standard library
+use std::{collections::VecDeque, time::Duration};
+// crates.io
+use bytes::{Buf, BytesMut};
+use futures_util::{stream, Stream, TryStreamExt};
+use hyper::{client::HttpConnector, Request, Body, Method, Client};
+use serde::de::DeserializeOwned;
+// this crate
+use crate::err::HypErr;
+use super::get_api_key;
+
+
+/// An event received from a Server-Sent Events feed, with its data deserialized into T.
+#[derive(Debug, Clone)]
+pub struct SseEvent<T> {
+    /// the most recent id sent by the server, if any
+    pub id: Option<String>,
+    /// the event type, or None for the default "message" type
+    pub event: Option<String>,
+    pub data: T,
+}
+
+
+/// Reconnect controls how a subscription recovers when the connection drops.
+/// The delay doubles after each failed attempt, starting from initial_delay and capped at max_delay.
+/// A retry field sent by the server replaces initial_delay.
+#[derive(Debug, Clone)]
+pub struct Reconnect {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// give up after this many consecutive failed attempts, or never if None
+    pub max_attempts: Option<u32>,
+}
+
+impl Reconnect {
+    /// How long to wait before the given reconnection attempt, counting from 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_delay.saturating_mul(factor).min(self.max_delay)
+    }
+
+    /// Returns true once the given number of consecutive failed attempts has used up max_attempts
+    pub fn exhausted(&self, attempts: u32) -> bool {
+        self.max_attempts.is_some_and(|max| attempts >= max)
+    }
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Reconnect{
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            max_attempts: None,
+        }
+    }
+}
+
+
+/// Let T be any struct implementing serde::de::DeserializeOwned.
+/// Subscribe to a Server-Sent Events feed, deserializing the data of each event from JSON into T.
+/// Dropped connections are retried according to reconnect, sending the Last-Event-ID header
+/// so the server can resume the feed. The stream only ends once the server answers 204 No Content
+/// or the reconnect attempts are exhausted, in which case the last error is returned.
+/// A 4xx status other than 429 (HypErr::Status) or a Content-Type other than text/event-stream (HypErr::Format)
+/// ends the stream at once, since reconnecting would get the same answer.
+/// An event whose data cannot be deserialized yields an Err, but the subscription continues.
+/// # Examples:
+/// ```ignore
+/// let mut feed = sse::subscribe::<Price>("http://127.0.0.1:8080/prices", None, Reconnect::default());
+/// while let Some(price) = feed.next().await {
+///     println!("{:?}", price?.data);
+/// }
+/// ```
+pub fn subscribe<T: DeserializeOwned>(url: &str, optkey: Option<&str>, reconnect: Reconnect) -> impl Stream<Item = Result<SseEvent<T>, HypErr>> {
+    subscribe_raw(url, optkey, reconnect).and_then(|event| async move {
+        let data = serde_json::from_str::<T>(&event.data)?;
+        Ok(SseEvent{id: event.id, event: event.event, data})
+    })
+}
+
+
+/// Subscribe to a Server-Sent Events feed, returning the data of each event as a String.
+/// See subscribe for the reconnect behavior.
+pub fn subscribe_raw(url: &str, optkey: Option<&str>, reconnect: Reconnect) -> impl Stream<Item = Result<SseEvent<String>, HypErr>> {
+    let subscription = Subscription{
+        client: Client::new(),
+        url: url.to_string(),
+        x_api_key: get_api_key(optkey),
+        reconnect,
+        body: None,
+        parser: SseParser::new(),
+        pending: VecDeque::new(),
+        attempts: 0,
+        connected_once: false,
+        finished: false,
+    };
+    stream::unfold(subscription, |mut sub| async move {
+        let next = sub.next_event().await?;
+        Some((next, sub))
+    })
+}
+
+
+// the state of a subscription between events
+struct Subscription {
+    client: Client<HttpConnector>,
+    url: String,
+    x_api_key: String,
+    reconnect: Reconnect,
+    body: Option<Body>,
+    parser: SseParser,
+    pending: VecDeque<SseEvent<String>>,
+    // consecutive failed connection attempts
+    attempts: u32,
+    connected_once: bool,
+    finished: bool,
+}
+
+
+impl Subscription {
+    // return the next event, reconnecting as needed, or None once the subscription is over
+    async fn next_event(&mut self) -> Option<Result<SseEvent<String>, HypErr>> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(Ok(event))
+            }
+            if self.finished {
+                return None
+            }
+            let body = match self.body.as_mut() {
+                Some(body) => body,
+                None => {
+                    if let Err(err) = self.connect().await {
+                        self.attempts += 1;
+                        // connect marks the subscription finished when retrying cannot help
+                        if self.finished || self.reconnect.exhausted(self.attempts) {
+                            self.finished = true;
+                            return Some(Err(err))
+                        }
+                    }
+                    continue
+                }
+            };
+            match body.try_next().await {
+                Ok(Some(chunk)) => {
+                    self.parser.push(&chunk);
+                    while let Some(event) = self.parser.next_event() {
+                        self.pending.push_back(event);
+                    }
+                    if let Some(retry) = self.parser.retry.take() {
+                        self.reconnect.initial_delay = retry;
+                    }
+                },
+                // the connection dropped or the server closed it, so reconnect
+                Ok(None) | Err(_) => {
+                    self.body = None;
+                    self.parser.reset_connection();
+                },
+            }
+        }
+    }
+
+    // wait out the backoff delay, then open a new connection
+    async fn connect(&mut self) -> Result<(), HypErr> {
+        if self.connected_once || self.attempts > 0 {
+            tokio::time::sleep(self.reconnect.delay(self.attempts)).await;
+        }
+        let mut builder = Request::builder()
+            .method(Method::GET)
+            .uri(&self.url)
+            .header("accept", "text/event-stream")
+            .header("cache-control", "no-cache")
+            .header("X-Api-Key", &self.x_api_key);
+        if let Some(id) = self.parser.last_id.as_ref().filter(|id| !id.is_empty()) {
+            builder = builder.header("Last-Event-ID", id);
+        }
+        let request = builder.body(Body::empty())?;
+        let resp = self.client.request(request).await?;
+        let status = resp.status();
+        if status == hyper::StatusCode::NO_CONTENT {
+            // the server is telling us to stop reconnecting
+            self.finished = true;
+            return Ok(())
+        }
+        if !status.is_success() {
+            // a 4xx, i.e. 401 Unauthorized or 404 Not Found, will not go away by asking again,
+            // but 429 Too Many Requests asks us to back off and retry
+            self.finished = status.is_client_error() && status != hyper::StatusCode::TOO_MANY_REQUESTS;
+            return Err(HypErr::Status(status))
+        }
+        let content_type = resp.headers().get(hyper::header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or("");
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+        if !mime.eq_ignore_ascii_case("text/event-stream") {
+            self.finished = true;
+            return Err(HypErr::Format(format!("expected a text/event-stream, got {:?}", content_type)))
+        }
+        self.attempts = 0;
+        self.connected_once = true;
+        self.body = Some(resp.into_body());
+        Ok(())
+    }
+}
+
+
+/// The SseParser turns the bytes of a text/event-stream body into events.
+/// Push chunks in as they arrive and call next_event until it returns None.
+#[derive(Debug, Default)]
+pub struct SseParser {
+    buf: BytesMut,
+    data: String,
+    has_data: bool,
+    event: Option<String>,
+    /// the last event id seen, which persists across events and connections
+    pub last_id: Option<String>,
+    /// the most recent reconnection time sent by the server, if it has not been taken yet
+    pub retry: Option<Duration>,
+    started: bool,
+    // the last line ended in \r, so a \n at the start of the next chunk belongs to it
+    skip_lf: bool,
+}
+
+
+impl SseParser {
+    pub fn new() -> Self {
+        SseParser::default()
+    }
+
+    /// Append a chunk of the response body
+    pub fn push(&mut self, chunk: &[u8]) {
+        self.buf.extend_from_slice(chunk);
+    }
+
+    /// Forget any partial event when the connection is lost, keeping the last event id
+    pub fn reset_connection(&mut self) {
+        self.buf.clear();
+        self.data.clear();
+        self.has_data = false;
+        self.event = None;
+        self.started = false;
+        self.skip_lf = false;
+    }
+
+    /// Return the next complete event, or None if more input is needed
+    pub fn next_event(&mut self) -> Option<SseEvent<String>> {
+        while let Some(line) = self.next_line() {
+            if line.is_empty() {
+                // a blank line dispatches the event
+                let event = self.event.take();
+                if !self.has_data {
+                    continue
+                }
+                self.has_data = false;
+                let mut data = std::mem::take(&mut self.data);
+                if data.ends_with('\n') {
+                    data.pop();
+                }
+                return Some(SseEvent{id: self.last_id.clone(), event, data})
+            }
+            if line.starts_with(':') {
+                continue // a comment
+            }
+            let (field, value) = match line.split_once(':') {
+                Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+                None => (line.as_str(), ""),
+            };
+            match field {
+                "event" => self.event = Some(value.to_string()),
+                "data" => {
+                    self.data.push_str(value);
+                    self.data.push('\n');
+                    self.has_data = true;
+                },
+                "id" if !value.contains('\0') => self.last_id = Some(value.to_string()),
+                "retry" => {
+                    if let Ok(ms) = value.parse::<u64>() {
+                        self.retry = Some(Duration::from_millis(ms));
+                    }
+                },
+                _ => {}, // unknown fields are ignored
+            }
+        }
+        None
+    }
+
+    // split off the next line, which may end in \n, \r\n or \r.
+    // A \r ends the line at once, even at the end of a chunk, so the last line is never held back
+    fn next_line(&mut self) -> Option<String> {
+        if !self.started {
+            if self.buf.len() < 3 && b"\xEF\xBB\xBF".starts_with(&self.buf) {
+                return None // wait to see if this is a byte order mark
+            }
+            if self.buf.starts_with(b"\xEF\xBB\xBF") {
+                self.buf.advance(3);
+            }
+            self.started = true;
+        }
+        if self.skip_lf && !self.buf.is_empty() {
+            if self.buf[0] == b'\n' {
+                self.buf.advance(1);
+            }
+            self.skip_lf = false;
+        }
+        let end = self.buf.iter().position(|b| *b == b'\n' || *b == b'\r')?;
+        let terminator = match (self.buf[end], self.buf.get(end + 1)) {
+            (b'\r', Some(b'\n')) => 2,
+            (b'\r', None) => {
+                self.skip_lf = true;
+                1
+            },
+            _ => 1,
+        };
+        let line = self.buf.split_to(end);
+        self.buf.advance(terminator);
+        Some(String::from_utf8_lossy(&line).into_owned())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{convert::Infallible, net::SocketAddr, sync::{atomic::{AtomicU32, Ordering}, Arc}};
+    use futures_util::StreamExt;
+    use hyper::{header, service::{make_service_fn, service_fn}, Response, Server, StatusCode};
+
+    // answer every request with the given status, content type and body, counting the requests
+    async fn serve(status: StatusCode, content_type: &'static str, body: &'static str) -> (SocketAddr, Arc<AtomicU32>) {
+        let requests = Arc::new(AtomicU32::new(0));
+        let make_service = {
+            let requests = requests.clone();
+            make_service_fn(move |_| {
+                let requests = requests.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |_req: Request<Body>| {
+                        requests.fetch_add(1, Ordering::SeqCst);
+                        async move {
+                            Response::builder().status(status).header(header::CONTENT_TYPE, content_type).body(Body::from(body))
+                        }
+                    }))
+                }
+            })
+        };
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, requests)
+    }
+
+    fn fast_reconnect(max_attempts: Option<u32>) -> Reconnect {
+        Reconnect{initial_delay: Duration::from_millis(1), max_delay: Duration::from_millis(1), max_attempts}
+    }
+
+    #[tokio::test]
+    async fn client_errors_end_the_subscription_at_once() {
+        let (addr, requests) = serve(StatusCode::UNAUTHORIZED, "text/plain", "no").await;
+        let feed = subscribe_raw(&format!("http://{}/feed", addr), None, fast_reconnect(None));
+        let results: Vec<_> = feed.collect().await;
+        assert!(matches!(results.as_slice(), [Err(HypErr::Status(StatusCode::UNAUTHORIZED))]));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn other_content_types_end_the_subscription_at_once() {
+        let (addr, requests) = serve(StatusCode::OK, "text/html", "<html>").await;
+        let results: Vec<_> = subscribe_raw(&format!("http://{}/feed", addr), None, fast_reconnect(None)).collect().await;
+        assert!(matches!(results.as_slice(), [Err(HypErr::Format(_))]));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried_until_exhausted() {
+        let (addr, requests) = serve(StatusCode::SERVICE_UNAVAILABLE, "text/plain", "busy").await;
+        let results: Vec<_> = subscribe_raw(&format!("http://{}/feed", addr), None, fast_reconnect(Some(3))).collect().await;
+        assert!(matches!(results.as_slice(), [Err(HypErr::Status(StatusCode::SERVICE_UNAVAILABLE))]));
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_the_server_closes() {
+        let (addr, requests) = serve(StatusCode::OK, "text/event-stream; charset=utf-8", "data: {\"n\": 1}\n\n").await;
+        let feed = subscribe::<serde_json::Value>(&format!("http://{}/feed", addr), None, fast_reconnect(None));
+        let events: Vec<_> = feed.take(2).try_collect().await.unwrap();
+        assert_eq!(events.iter().map(|event| event.data["n"].as_i64()).collect::<Vec<_>>(), vec![Some(1), Some(1)]);
+        assert!(requests.load(Ordering::SeqCst) >= 2);
+    }
+
+    // feed the chunks one at a time, collecting (event type, data) of every event
+    fn parse(chunks: &[&[u8]]) -> Vec<(Option<String>, String)> {
+        let mut parser = SseParser::new();
+        let mut events = Vec::new();
+        for chunk in chunks {
+            parser.push(chunk);
+            while let Some(event) = parser.next_event() {
+                events.push((event.event, event.data));
+            }
+        }
+        events
+    }
+
+    fn data(events: &[(Option<String>, String)]) -> Vec<&str> {
+        events.iter().map(|(_, data)| data.as_str()).collect()
+    }
+
+    #[test]
+    fn parses_fields() {
+        let mut parser = SseParser::new();
+        parser.push(b": comment\nid: 42\nevent: price\nretry: 1500\ndata: a\ndata:b\n\n");
+        let event = parser.next_event().unwrap();
+        assert_eq!((event.id.as_deref(), event.event.as_deref(), event.data.as_str()), (Some("42"), Some("price"), "a\nb"));
+        assert_eq!(parser.retry, Some(Duration::from_millis(1500)));
+        assert!(parser.next_event().is_none());
+    }
+
+    #[test]
+    fn every_line_terminator() {
+        assert_eq!(data(&parse(&[b"data: 1\r\n\r\ndata: 2\r\rdata: 3\n\n"])), vec!["1", "2", "3"]);
+    }
+
+    #[test]
+    fn crlf_split_across_chunks() {
+        assert_eq!(data(&parse(&[b"data: 1\r", b"\n\r", b"\ndata: 2\r", b"\r", b"\n"])), vec!["1", "2"]);
+    }
+
+    #[test]
+    fn trailing_cr_is_not_held_back() {
+        assert_eq!(data(&parse(&[b"data: last\r\r"])), vec!["last"]);
+    }
+
+    #[test]
+    fn byte_order_mark_split_across_chunks() {
+        assert_eq!(data(&parse(&[b"\xEF", b"\xBB", b"\xBFdata: x\n", b"\n"])), vec!["x"]);
+    }
+
+    #[test]
+    fn lines_split_across_chunks() {
+        let events = parse(&[b"eve", b"nt: tick\nda", b"ta: {\"n\"", b": 1}\n", b"\n"]);
+        assert_eq!(events, vec![(Some("tick".to_string()), "{\"n\": 1}".to_string())]);
+    }
+
+    #[test]
+    fn events_without_data_are_not_dispatched() {
+        assert!(parse(&[b"event: ping\n\n"]).is_empty());
+    }
+
+    #[test]
+    fn reset_drops_partial_event_but_keeps_id() {
+        let mut parser = SseParser::new();
+        parser.push(b"id: 7\ndata: 1\n\ndata: partial\r");
+        assert!(parser.next_event().is_some());
+        parser.reset_connection();
+        parser.push(b"\ndata: 2\n\n");
+        let event = parser.next_event().unwrap();
+        assert_eq!((event.id.as_deref(), event.data.as_str()), (Some("7"), "2"));
+    }
+}
@@ -0,0 +1,685 @@
+//! The stream module lets the client consume large response bodies chunk by chunk,
+//! rather than buffering the whole body in memory the way client::get does.
+
+
+// standard library
+use std::{io::{self, Write}, path::Path, sync::{Arc, Mutex}};
+// crates.io
+use bytes::{Bytes, BytesMut};
+use flate2::write::{MultiGzDecoder, ZlibDecoder};
+use futures_util::{stream, Stream, TryStreamExt};
+use hyper::{header, Body, Method};
+use serde::de::{self, DeserializeOwned};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+// this crate
+use crate::err::HypErr;
+use crate::server::compress::Encoding;
+use super::ApiClient;
+
+
+/// A callback reporting (bytes_received_so_far, content_length) as a download progresses.
+/// The content_length is None if the server did not send a Content-Length header,
+/// or if the body is being decompressed, since it then counts the compressed bytes.
+pub type Progress<'a> = &'a mut (dyn FnMut(u64, Option<u64>) + Send);
+
+
+// a streaming decoder for one content coding, writing what it decodes to the next decoder in the chain
+trait Decode: Write + Send {
+    // flush the rest of the coding, failing if it was cut short
+    fn end(self: Box<Self>) -> io::Result<()>;
+}
+
+
+impl Decode for MultiGzDecoder<Box<dyn Decode>> {
+    fn end(self: Box<Self>) -> io::Result<()> {
+        self.finish()?.end()
+    }
+}
+
+
+impl Decode for ZlibDecoder<Box<dyn Decode>> {
+    fn end(self: Box<Self>) -> io::Result<()> {
+        self.finish()?.end()
+    }
+}
+
+
+impl Decode for brotli::DecompressorWriter<Box<dyn Decode>> {
+    fn end(self: Box<Self>) -> io::Result<()> {
+        match self.into_inner() {
+            Ok(inner) => inner.end(),
+            Err(_) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated brotli stream")),
+        }
+    }
+}
+
+
+// the end of the chain, collecting the decoded bytes of the current chunk.
+// A chunk decoding to more than limit bytes is an error, so one small chunk cannot expand without bound
+#[derive(Clone)]
+struct Decoded {
+    bytes: Arc<Mutex<Vec<u8>>>,
+    limit: usize,
+}
+
+
+impl Decoded {
+    // take what the chain has decoded so far, mapping a chunk past the limit to HypErr::BodyTooLarge
+    fn take(&self, result: io::Result<()>) -> Result<Bytes, HypErr> {
+        let mut bytes = self.bytes.lock().expect("decoded bytes lock poisoned");
+        match result {
+            Err(_) if bytes.len() > self.limit => Err(HypErr::BodyTooLarge(self.limit)),
+            Err(err) => Err(HypErr::Io(err)),
+            Ok(()) => Ok(Bytes::from(std::mem::take(&mut *bytes))),
+        }
+    }
+}
+
+
+impl Write for Decoded {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let mut bytes = self.bytes.lock().expect("decoded bytes lock poisoned");
+        bytes.extend_from_slice(data);
+        if bytes.len() > self.limit {
+            return Err(io::Error::other("decoded chunk too large"))
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+impl Decode for Decoded {
+    fn end(self: Box<Self>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+// decodes a response body chunk by chunk, according to the codings listed in its Content-Encoding
+struct StreamDecoder {
+    chain: Box<dyn Decode>,
+    decoded: Decoded,
+}
+
+
+impl StreamDecoder {
+    // returns None if the body is not encoded
+    fn new(content_encoding: &str, limit: usize) -> Result<Option<Self>, HypErr> {
+        let decoded = Decoded{bytes: Arc::new(Mutex::new(Vec::new())), limit};
+        let mut chain: Box<dyn Decode> = Box::new(decoded.clone());
+        let mut encoded = false;
+        // the codings are listed in the order they were applied, so the last one listed is undone first
+        for coding in content_encoding.split(',') {
+            chain = match Encoding::parse(coding).ok_or_else(|| HypErr::UnsupportedEncoding(coding.trim().to_string()))? {
+                Encoding::Brotli => Box::new(brotli::DecompressorWriter::new(chain, 4096)),
+                Encoding::Gzip => Box::new(MultiGzDecoder::new(chain)),
+                Encoding::Deflate => Box::new(ZlibDecoder::new(chain)),
+                Encoding::Identity => continue,
+            };
+            encoded = true;
+        }
+        Ok(encoded.then_some(StreamDecoder{chain, decoded}))
+    }
+
+    fn push(&mut self, chunk: &[u8]) -> Result<Bytes, HypErr> {
+        let result = self.chain.write_all(chunk).and_then(|_| self.chain.flush());
+        self.decoded.take(result)
+    }
+
+    fn finish(self) -> Result<Bytes, HypErr> {
+        let result = self.chain.end();
+        self.decoded.take(result)
+    }
+}
+
+
+// turn a response body into a stream of chunks, decoding it if decoder is Some
+fn body_stream(body: Body, decoder: Option<StreamDecoder>) -> impl Stream<Item = Result<Bytes, HypErr>> {
+    stream::try_unfold((body, decoder), |(mut body, mut decoder)| async move {
+        loop {
+            match (body.try_next().await?, decoder) {
+                (Some(chunk), None) => return Ok(Some((chunk, (body, None)))),
+                (Some(chunk), Some(mut active)) => {
+                    let decoded = active.push(&chunk)?;
+                    decoder = Some(active);
+                    if !decoded.is_empty() {
+                        return Ok(Some((decoded, (body, decoder))))
+                    }
+                },
+                (None, Some(active)) => {
+                    let decoded = active.finish()?;
+                    decoder = None;
+                    if !decoded.is_empty() {
+                        return Ok(Some((decoded, (body, decoder))))
+                    }
+                },
+                (None, None) => return Ok(None),
+            }
+        }
+    })
+}
+
+
+// write a body stream to writer as it arrives, returning the number of bytes written
+async fn write_body<S, W>(body: S, content_length: Option<u64>, writer: &mut W, mut progress: Option<Progress<'_>>) -> Result<u64, HypErr>
+where
+    S: Stream<Item = Result<Bytes, HypErr>>,
+    W: AsyncWrite + Unpin,
+{
+    futures_util::pin_mut!(body);
+    let mut written: u64 = 0;
+    while let Some(chunk) = body.try_next().await? {
+        writer.write_all(&chunk).await?;
+        written += chunk.len() as u64;
+        if let Some(callback) = progress.as_mut() {
+            callback(written, content_length);
+        }
+    }
+    writer.flush().await?;
+    Ok(written)
+}
+
+
+impl ApiClient {
+    // make a GET request through the client, returning the Content-Length of the body as it will be
+    // streamed (None when it is decompressed) and the stream itself.
+    // Anything but a 2xx status is an error, so error pages are never mistaken for the download
+    async fn get_body(&self, url: &str, accept: &str) -> Result<(Option<u64>, impl Stream<Item = Result<Bytes, HypErr>>), HypErr> {
+        let request = self.build_request(Method::GET, url, accept, None)?;
+        let resp = self.execute(request).await?;
+        if !resp.status().is_success() {
+            return Err(HypErr::Status(resp.status()))
+        }
+        let decoder = match resp.headers().get(header::CONTENT_ENCODING).and_then(|v| v.to_str().ok()) {
+            Some(coding) if self.decompress => StreamDecoder::new(coding, self.max_decompressed)?,
+            _ => None,
+        };
+        let content_length = match decoder {
+            Some(_) => None,
+            None => resp.headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok()),
+        };
+        Ok((content_length, body_stream(resp.into_body(), decoder)))
+    }
+
+    /// Make a GET request and return the response body as a stream of Bytes chunks, as they arrive.  
+    /// A response with a status other than 2xx returns HypErr::Status, here and in the other streaming methods.  
+    /// Compressed bodies are decompressed as they stream, with with_max_decompressed limiting what
+    /// a single chunk may expand to rather than the whole body, which is never held in memory.  
+    pub async fn get_stream(&self, url: &str) -> Result<impl Stream<Item = Result<Bytes, HypErr>>, HypErr> {
+        let (_, body) = self.get_body(url, "*/*").await?;
+        Ok(body)
+    }
+
+    /// Make a GET request and write the response body to any tokio AsyncWrite as it arrives,
+    /// returning the total number of bytes written.  
+    /// If a progress callback is provided, it is called after each chunk is written.  
+    pub async fn get_to_writer<W: AsyncWrite + Unpin>(&self, url: &str, writer: &mut W, progress: Option<Progress<'_>>) -> Result<u64, HypErr> {
+        let (content_length, body) = self.get_body(url, "*/*").await?;
+        write_body(body, content_length, writer, progress).await
+    }
+
+    /// Make a GET request and save the response body to the file at path, replacing it if it exists.  
+    /// The body is written to a temporary file next to path, which is renamed over path once the
+    /// download is complete, so a failed download leaves an existing file untouched.  
+    /// Returns the number of bytes written. See also get_to_writer.
+    pub async fn get_to_file<P: AsRef<Path>>(&self, url: &str, path: P, progress: Option<Progress<'_>>) -> Result<u64, HypErr> {
+        let path = path.as_ref();
+        let (content_length, body) = self.get_body(url, "*/*").await?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let temp_path = tempfile::Builder::new().prefix(".download-").tempfile_in(dir)?.into_temp_path();
+        let mut file = tokio::fs::File::create(&temp_path).await?;
+        let written = write_body(body, content_length, &mut file, progress).await?;
+        file.sync_all().await?;
+        drop(file);
+        temp_path.persist(path).map_err(|err| HypErr::Io(err.error))?;
+        Ok(written)
+    }
+
+    /// Let T be any struct implementing serde::de::DeserializeOwned.  
+    /// Make a GET request to an endpoint returning a top-level JSON array (i.e. [{...}, {...}, ...])
+    /// and deserialize it one element at a time, so the whole array never has to be held in memory.  
+    pub async fn get_json_array_stream<T: DeserializeOwned>(&self, url: &str) -> Result<impl Stream<Item = Result<T, HypErr>>, HypErr> {
+        let (_, body) = self.get_body(url, "application/json").await?;
+        let state = (Box::pin(body), JsonArrayDecoder::new(), false);
+        let elements = stream::try_unfold(state, |(mut body, mut decoder, mut eof)| async move {
+            loop {
+                if let Some(element) = decoder.next_element()? {
+                    let item = serde_json::from_slice::<T>(&element)?;
+                    return Ok(Some((item, (body, decoder, eof))))
+                }
+                // the body is read to the end even after the closing ']', so trailing garbage is caught
+                if eof {
+                    return match decoder.is_done() {
+                        true => Ok(None),
+                        false => Err(HypErr::from(json_error("unexpected end of JSON array"))),
+                    }
+                }
+                match body.try_next().await? {
+                    Some(chunk) => decoder.push(&chunk),
+                    None => eof = true,
+                }
+            }
+        });
+        Ok(elements)
+    }
+
+    /// Let T be any struct implementing serde::de::DeserializeOwned.  
+    /// Make a GET request to an endpoint returning newline-delimited JSON (application/x-ndjson)
+    /// and deserialize each line into T as it arrives. Blank lines are skipped, and a line that
+    /// does not deserialize is an error naming its line number, counting from 1.
+    pub async fn get_ndjson_stream<T: DeserializeOwned>(&self, url: &str) -> Result<impl Stream<Item = Result<T, HypErr>>, HypErr> {
+        let (_, body) = self.get_body(url, "application/x-ndjson").await?;
+        let state = (Box::pin(body), BytesMut::new(), false, 0);
+        let lines = stream::try_unfold(state, |(mut body, mut buf, mut eof, mut line_number)| async move {
+            loop {
+                let line = match buf.iter().position(|b| *b == b'\n') {
+                    Some(i) => Some(buf.split_to(i + 1)),
+                    // the last line does not need a trailing newline
+                    None if eof && !buf.is_empty() => Some(buf.split()),
+                    None if eof => return Ok(None),
+                    None => None,
+                };
+                if let Some(line) = line {
+                    line_number += 1;
+                    if line.iter().all(|b| b.is_ascii_whitespace()) {
+                        continue
+                    }
+                    let item = serde_json::from_slice::<T>(&line)
+                        .map_err(|err| json_error(&format!("line {}: {}", line_number, err)))?;
+                    return Ok(Some((item, (body, buf, eof, line_number))))
+                }
+                match body.try_next().await? {
+                    Some(chunk) => buf.extend_from_slice(&chunk),
+                    None => eof = true,
+                }
+            }
+        });
+        Ok(lines)
+    }
+}
+
+
+/// Make a GET request and return the response body as a stream of Bytes chunks, as they arrive.
+/// A response with a status other than 2xx returns HypErr::Status, here and in the other functions of this module.
+/// An optional X-Api-Key can be provided using optkey.
+/// If optkey is none, it will look for the environment variable X_API_KEY.
+/// See ApiClient::get_stream to stream through a configured client.
+pub async fn get_stream(url: &str, optkey: Option<&str>) -> Result<impl Stream<Item = Result<Bytes, HypErr>>, HypErr> {
+    ApiClient::from_optkey(optkey).get_stream(url).await
+}
+
+
+/// Make a GET request and write the response body to any tokio AsyncWrite as it arrives,
+/// returning the total number of bytes written.
+/// If a progress callback is provided, it is called after each chunk is written.
+pub async fn get_to_writer<W: AsyncWrite + Unpin>(url: &str, optkey: Option<&str>, writer: &mut W, progress: Option<Progress<'_>>) -> Result<u64, HypErr> {
+    ApiClient::from_optkey(optkey).get_to_writer(url, writer, progress).await
+}
+
+
+/// Make a GET request and save the response body to the file at path, replacing it if it exists.
+/// The body is written to a temporary file next to path, which is renamed over path once the
+/// download is complete, so a failed download leaves an existing file untouched.
+/// Returns the number of bytes written. See also get_to_writer.
+pub async fn get_to_file<P: AsRef<Path>>(url: &str, optkey: Option<&str>, path: P, progress: Option<Progress<'_>>) -> Result<u64, HypErr> {
+    ApiClient::from_optkey(optkey).get_to_file(url, path, progress).await
+}
+
+
+/// Let T be any struct implementing serde::de::DeserializeOwned.
+/// Make a GET request to an endpoint returning a top-level JSON array (i.e. [{...}, {...}, ...])
+/// and deserialize it one element at a time, so the whole array never has to be held in memory.
+/// # Examples:
+/// ```ignore
+/// let mut users = get_json_array_stream::<User>("http://127.0.0.1:8080/users", None).await?;
+/// while let Some(user) = users.try_next().await? {
+///     println!("{}", user.name);
+/// }
+/// ```
+pub async fn get_json_array_stream<T: DeserializeOwned>(url: &str, optkey: Option<&str>) -> Result<impl Stream<Item = Result<T, HypErr>>, HypErr> {
+    ApiClient::from_optkey(optkey).get_json_array_stream(url).await
+}
+
+
+/// Let T be any struct implementing serde::de::DeserializeOwned.
+/// Make a GET request to an endpoint returning newline-delimited JSON (application/x-ndjson)
+/// and deserialize each line into T as it arrives. Blank lines are skipped, and a line that
+/// does not deserialize is an error naming its line number, counting from 1.
+pub async fn get_ndjson_stream<T: DeserializeOwned>(url: &str, optkey: Option<&str>) -> Result<impl Stream<Item = Result<T, HypErr>>, HypErr> {
+    ApiClient::from_optkey(optkey).get_ndjson_stream(url).await
+}
+
+
+// build a serde_json::Error with a custom message
+fn json_error(msg: &str) -> serde_json::Error {
+    <serde_json::Error as de::Error>::custom(msg)
+}
+
+
+#[derive(Debug, PartialEq)]
+enum ArrayState {
+    BeforeArray,
+    // just after the '[', where the array may also end
+    FirstOrEnd,
+    // after a ',', where an element must follow
+    Element,
+    InElement,
+    CommaOrEnd,
+    Done,
+}
+
+
+/// The JsonArrayDecoder splits the bytes of a top-level JSON array into the raw bytes of its elements,
+/// without parsing them. Push chunks in as they arrive and call next_element until it returns None.
+/// Empty elements (i.e. "[1,,2]") and anything but whitespace after the closing ']' are errors.
+#[derive(Debug)]
+pub struct JsonArrayDecoder {
+    buf: BytesMut,
+    state: ArrayState,
+    // how far into buf the current element has been scanned
+    pos: usize,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+
+impl JsonArrayDecoder {
+    pub fn new() -> Self {
+        JsonArrayDecoder{
+            buf: BytesMut::new(),
+            state: ArrayState::BeforeArray,
+            pos: 0,
+            depth: 0,
+            in_string: false,
+            escaped: false,
+        }
+    }
+
+    /// Append a chunk of the response body
+    pub fn push(&mut self, chunk: &[u8]) {
+        self.buf.extend_from_slice(chunk);
+    }
+
+    /// Returns true once the closing ']' of the array has been reached
+    pub fn is_done(&self) -> bool {
+        self.state == ArrayState::Done
+    }
+
+    // drop leading whitespace and return the first byte after it, if it has arrived yet
+    fn skip_whitespace(&mut self) -> Option<u8> {
+        match self.buf.iter().position(|b| !b.is_ascii_whitespace()) {
+            Some(i) => {
+                let _ = self.buf.split_to(i);
+                Some(self.buf[0])
+            },
+            None => {
+                self.buf.clear();
+                None
+            },
+        }
+    }
+
+    /// Return the raw bytes of the next complete element, or None if more input is needed
+    pub fn next_element(&mut self) -> Result<Option<Bytes>, serde_json::Error> {
+        loop {
+            match self.state {
+                ArrayState::BeforeArray => match self.skip_whitespace() {
+                    None => return Ok(None),
+                    Some(b'[') => {
+                        let _ = self.buf.split_to(1);
+                        self.state = ArrayState::FirstOrEnd;
+                    },
+                    Some(_) => return Err(json_error("expected a top-level JSON array")),
+                },
+                ArrayState::FirstOrEnd | ArrayState::Element => match self.skip_whitespace() {
+                    None => return Ok(None),
+                    Some(b']') if self.state == ArrayState::FirstOrEnd => {
+                        let _ = self.buf.split_to(1);
+                        self.state = ArrayState::Done;
+                    },
+                    Some(b']') | Some(b',') => return Err(json_error("empty element in JSON array")),
+                    Some(_) => {
+                        self.state = ArrayState::InElement;
+                        self.pos = 0;
+                    },
+                },
+                ArrayState::InElement => return self.scan_element(),
+                ArrayState::CommaOrEnd => match self.skip_whitespace() {
+                    None => return Ok(None),
+                    Some(b',') => {
+                        let _ = self.buf.split_to(1);
+                        self.state = ArrayState::Element;
+                    },
+                    Some(b']') => {
+                        let _ = self.buf.split_to(1);
+                        self.state = ArrayState::Done;
+                    },
+                    Some(_) => return Err(json_error("expected ',' or ']' in JSON array")),
+                },
+                ArrayState::Done => return match self.skip_whitespace() {
+                    None => Ok(None),
+                    Some(_) => Err(json_error("trailing characters after JSON array")),
+                },
+            }
+        }
+    }
+
+    // scan the current element up to the ',' or ']' that ends it at depth 0
+    fn scan_element(&mut self) -> Result<Option<Bytes>, serde_json::Error> {
+        while self.pos < self.buf.len() {
+            let b = self.buf[self.pos];
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false;
+                } else if b == b'\\' {
+                    self.escaped = true;
+                } else if b == b'"' {
+                    self.in_string = false;
+                }
+            } else {
+                match b {
+                    b'"' => self.in_string = true,
+                    b'{' | b'[' => self.depth += 1,
+                    b'}' | b']' if self.depth > 0 => self.depth -= 1,
+                    b']' | b',' if self.depth == 0 => {
+                        let element = self.buf.split_to(self.pos).freeze();
+                        self.pos = 0;
+                        self.state = ArrayState::CommaOrEnd;
+                        return Ok(Some(element))
+                    },
+                    b'}' => return Err(json_error("unbalanced '}' in JSON array")),
+                    _ => {},
+                }
+            }
+            self.pos += 1;
+        }
+        Ok(None)
+    }
+}
+
+
+impl Default for JsonArrayDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{convert::Infallible, net::SocketAddr};
+    use hyper::{service::{make_service_fn, service_fn}, Request, Response, Server, StatusCode};
+    use crate::server::compress::encode;
+
+    // serve each path's body in chunks of the given sizes with its Content-Encoding, /old redirecting to /file
+    async fn serve(routes: Vec<(&'static str, &'static str, Vec<Vec<u8>>)>) -> SocketAddr {
+        let routes = Arc::new(routes);
+        let make_service = make_service_fn(move |_| {
+            let routes = routes.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let routes = routes.clone();
+                    async move {
+                        let builder = Response::builder();
+                        if req.uri().path() == "/old" {
+                            return builder.status(StatusCode::MOVED_PERMANENTLY).header(header::LOCATION, "/file").body(Body::empty())
+                        }
+                        match routes.iter().find(|(path, _, _)| *path == req.uri().path()) {
+                            Some((_, coding, chunks)) => {
+                                let chunks: Vec<Result<Vec<u8>, Infallible>> = chunks.iter().cloned().map(Ok).collect();
+                                builder.header(header::CONTENT_ENCODING, *coding).body(Body::wrap_stream(stream::iter(chunks)))
+                            },
+                            None => builder.status(StatusCode::NOT_FOUND).body(Body::from("not found")),
+                        }
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    // split data into chunks of size bytes
+    fn chunked(data: &[u8], size: usize) -> Vec<Vec<u8>> {
+        data.chunks(size).map(|chunk| chunk.to_vec()).collect()
+    }
+
+    #[tokio::test]
+    async fn downloads_follow_redirects_and_decompress_as_they_stream() {
+        let data = "streamed line\n".repeat(500).into_bytes();
+        let layered = encode(&encode(&data, Encoding::Gzip).unwrap(), Encoding::Brotli).unwrap();
+        let addr = serve(vec![("/file", "gzip, br", chunked(&layered, 7))]).await;
+        let mut reported = None;
+        let mut progress = |written, content_length| reported = Some((written, content_length));
+        let mut written = Vec::new();
+        let count = get_to_writer(&format!("http://{}/old", addr), None, &mut written, Some(&mut progress)).await.unwrap();
+        assert_eq!(written, data);
+        assert_eq!(count, data.len() as u64);
+        // the Content-Length would count compressed bytes, so it is not reported
+        assert_eq!(reported, Some((data.len() as u64, None)));
+
+        let raw = ApiClient::new().with_decompression(false).get_stream(&format!("http://{}/file", addr)).await.unwrap();
+        let raw: Vec<Bytes> = raw.try_collect().await.unwrap();
+        assert_eq!(raw.concat(), layered);
+    }
+
+    #[tokio::test]
+    async fn downloads_fail_on_error_statuses_and_truncated_bodies() {
+        let gzipped = encode(b"cut short", Encoding::Gzip).unwrap();
+        let addr = serve(vec![("/cut", "gzip", vec![gzipped[..gzipped.len() - 4].to_vec()])]).await;
+        let missing = get_stream(&format!("http://{}/missing", addr), None).await;
+        assert!(matches!(missing, Err(HypErr::Status(StatusCode::NOT_FOUND))));
+        let cut = get_stream(&format!("http://{}/cut", addr), None).await.unwrap();
+        assert!(cut.try_collect::<Vec<Bytes>>().await.is_err());
+    }
+
+    // collect the NDJSON served in the given chunks
+    async fn ndjson(chunks: &[&str]) -> Result<Vec<serde_json::Value>, HypErr> {
+        let chunks = chunks.iter().map(|chunk| chunk.as_bytes().to_vec()).collect();
+        let addr = serve(vec![("/lines", "identity", chunks)]).await;
+        get_ndjson_stream(&format!("http://{}/lines", addr), None).await?.try_collect().await
+    }
+
+    #[tokio::test]
+    async fn ndjson_lines() {
+        let values = ndjson(&["{\"a\":1}\n\n  \n{\"a\":", "2}\r\n{\"a\":3}"]).await.unwrap();
+        assert_eq!(values, vec![serde_json::json!({"a": 1}), serde_json::json!({"a": 2}), serde_json::json!({"a": 3})]);
+        assert!(ndjson(&["\n", " \r\n"]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn ndjson_errors_name_the_line() {
+        let err = ndjson(&["1\n\n", "{\"a\":}\n3\n"]).await.unwrap_err();
+        assert!(err.to_string().contains("line 3"), "{}", err);
+    }
+
+    #[test]
+    fn limits_what_one_chunk_decodes_to() {
+        let gzipped = encode(&[0; 4096], Encoding::Gzip).unwrap();
+        let mut decoder = StreamDecoder::new("gzip", 1024).unwrap().unwrap();
+        assert!(matches!(decoder.push(&gzipped), Err(HypErr::BodyTooLarge(1024))));
+        assert!(StreamDecoder::new("identity", 1024).unwrap().is_none());
+        assert!(matches!(StreamDecoder::new("zstd", 1024), Err(HypErr::UnsupportedEncoding(_))));
+    }
+
+    // feed the input one chunk at a time, collecting every element until the decoder needs more
+    fn decode(chunks: &[&str]) -> Result<(Vec<String>, bool), serde_json::Error> {
+        let mut decoder = JsonArrayDecoder::new();
+        let mut elements = Vec::new();
+        for chunk in chunks {
+            decoder.push(chunk.as_bytes());
+            while let Some(element) = decoder.next_element()? {
+                elements.push(String::from_utf8(element.to_vec()).unwrap().trim().to_string());
+            }
+        }
+        Ok((elements, decoder.is_done()))
+    }
+
+    #[test]
+    fn splits_elements() {
+        let (elements, done) = decode(&[r#" [1, "a,]", {"b": [2, 3]}, [], null ] "#]).unwrap();
+        assert_eq!(elements, vec!["1", r#""a,]""#, r#"{"b": [2, 3]}"#, "[]", "null"]);
+        assert!(done);
+    }
+
+    #[test]
+    fn empty_array() {
+        assert_eq!(decode(&["[ ]"]).unwrap(), (vec![], true));
+    }
+
+    #[test]
+    fn elements_split_across_chunks() {
+        let (elements, done) = decode(&["[{\"a\":", " \"x\\", "\"y\"}", ",", "2", "]"]).unwrap();
+        assert_eq!(elements, vec![r#"{"a": "x\"y"}"#, "2"]);
+        assert!(done);
+    }
+
+    #[test]
+    fn one_byte_chunks() {
+        let input = r#"[{"a": "]"}, 12, "\\"]"#;
+        let chunks: Vec<String> = input.chars().map(|c| c.to_string()).collect();
+        let chunks: Vec<&str> = chunks.iter().map(|c| c.as_str()).collect();
+        let (elements, done) = decode(&chunks).unwrap();
+        assert_eq!(elements, vec![r#"{"a": "]"}"#, "12", r#""\\""#]);
+        assert!(done);
+    }
+
+    #[test]
+    fn incomplete_array_is_not_done() {
+        assert_eq!(decode(&["[1, 2"]).unwrap(), (vec!["1".to_string()], false));
+    }
+
+    #[test]
+    fn rejects_empty_elements() {
+        assert!(decode(&["[,1]"]).is_err());
+        assert!(decode(&["[1,,2]"]).is_err());
+        assert!(decode(&["[1,]"]).is_err());
+    }
+
+    #[test]
+    fn rejects_trailing_characters() {
+        assert!(decode(&["[1]", " x"]).is_err());
+        assert!(decode(&["[1] \n"]).is_ok());
+    }
+
+    #[test]
+    fn rejects_other_top_level_values() {
+        assert!(decode(&[r#"{"a": 1}"#]).is_err());
+        assert!(decode(&["[1 2]"]).is_ok()); // left to serde_json to reject the element "1 2"
+        assert!(decode(&["[1}"]).is_err());
+    }
+}
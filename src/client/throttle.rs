@@ -0,0 +1,311 @@
+//! The throttle module keeps an ApiClient within the limits of the servers it calls.
+//! Each host gets a token bucket and a cap on requests in flight, so callers wait their turn
+//! instead of getting 429s, and the limits tighten when responses say the server is running out.
+
+
+// standard library
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, Instant, SystemTime}};
+// crates.io
+use hyper::{header::{self, HeaderMap}, StatusCode};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+// this crate
+use crate::util::unix_now;
+
+
+// never pause a host for longer than this, whatever its headers say
+const MAX_PAUSE: Duration = Duration::from_secs(300);
+
+// how long to pause a host that sent 429 without saying for how long
+const DEFAULT_PAUSE: Duration = Duration::from_secs(1);
+
+// reset headers above this are unix timestamps rather than a number of seconds
+const EPOCH_THRESHOLD: u64 = 1_000_000_000;
+
+
+/// The limits applied to one host
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    /// allow this many requests per duration, in bursts of up to that many
+    pub rate: Option<(u32, Duration)>,
+    /// allow at most this many requests in flight at once
+    pub max_in_flight: Option<usize>,
+}
+
+
+impl Limits {
+    /// No limits, until some are added
+    pub fn new() -> Self {
+        Limits::default()
+    }
+
+    pub fn rate(mut self, requests: u32, per: Duration) -> Self {
+        self.rate = Some((requests.max(1), per));
+        self
+    }
+
+    pub fn per_second(self, requests: u32) -> Self {
+        self.rate(requests, Duration::from_secs(1))
+    }
+
+    pub fn per_minute(self, requests: u32) -> Self {
+        self.rate(requests, Duration::from_secs(60))
+    }
+
+    pub fn max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = Some(max_in_flight.max(1));
+        self
+    }
+}
+
+
+// the token bucket of one host, along with any pause requested by the server
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    paused_until: Option<Instant>,
+}
+
+
+impl Bucket {
+    // take a token, or return how long to wait before trying again
+    fn take(&mut self, rate: Option<(u32, Duration)>, now: Instant) -> Option<Duration> {
+        if let Some(until) = self.paused_until {
+            if now < until {
+                return Some(until - now)
+            }
+            self.paused_until = None;
+        }
+        let (requests, per) = rate?;
+        let capacity = f64::from(requests);
+        let refill_rate = capacity / per.as_secs_f64().max(f64::EPSILON); // tokens per second
+        self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * refill_rate).min(capacity);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return None
+        }
+        Some(Duration::from_secs_f64((1.0 - self.tokens) / refill_rate))
+    }
+
+    fn pause(&mut self, delay: Duration, now: Instant) {
+        let until = now + delay.min(MAX_PAUSE);
+        self.paused_until = Some(self.paused_until.map_or(until, |current| current.max(until)));
+    }
+}
+
+
+#[derive(Debug)]
+struct HostState {
+    limits: Limits,
+    semaphore: Option<Arc<Semaphore>>,
+    bucket: Mutex<Bucket>,
+}
+
+
+/// Held while a request is in flight. Dropping it lets the next queued request for the host go
+#[derive(Debug)]
+pub struct ThrottlePermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+
+/// A Throttle applies Limits per host (the authority of the url, i.e. "api.partner.com:8443").
+/// The buckets belong to the Throttle, not the client, so every ApiClient calling the same hosts
+/// should hold the same one; two Throttles would each spend the server's full allowance.
+/// # Examples:
+/// ```ignore
+/// let throttle = Throttle::new(Limits::new().per_second(20).max_in_flight(8))
+///     .with_host("slow.partner.com", Limits::new().per_second(2).max_in_flight(1));
+/// let client = ApiClient::new().with_throttle(Arc::new(throttle));
+/// ```
+#[derive(Debug)]
+pub struct Throttle {
+    default_limits: Limits,
+    host_limits: HashMap<String, Limits>,
+    retries: u32,
+    hosts: Mutex<HashMap<String, Arc<HostState>>>,
+}
+
+
+impl Throttle {
+    /// Apply these limits to every host without limits of its own
+    pub fn new(default_limits: Limits) -> Self {
+        Throttle{default_limits, host_limits: HashMap::new(), retries: 1, hosts: Mutex::new(HashMap::new())}
+    }
+
+    /// Apply these limits to a host, given with or without its port
+    pub fn with_host(mut self, host: &str, limits: Limits) -> Self {
+        self.host_limits.insert(host.to_ascii_lowercase(), limits);
+        self
+    }
+
+    /// Retry a request this many times when it still gets 429 Too Many Requests (once by default).
+    /// Each retry waits for the Retry-After the server sent
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    // find or create the state of a host, using its own limits if it has some
+    fn host(&self, host: &str) -> Arc<HostState> {
+        let host = host.to_ascii_lowercase();
+        let mut hosts = self.hosts.lock().expect("throttle lock poisoned");
+        hosts.entry(host.clone()).or_insert_with(|| {
+            let hostname = host.rsplit_once(':').map_or(host.as_str(), |(name, _)| name);
+            let limits = self.host_limits.get(&host)
+                .or_else(|| self.host_limits.get(hostname))
+                .copied()
+                .unwrap_or(self.default_limits);
+            let tokens = limits.rate.map_or(0.0, |(requests, _)| f64::from(requests));
+            Arc::new(HostState{
+                limits,
+                semaphore: limits.max_in_flight.map(|max| Arc::new(Semaphore::new(max))),
+                bucket: Mutex::new(Bucket{tokens, updated: Instant::now(), paused_until: None}),
+            })
+        }).clone()
+    }
+
+    /// Wait until a request to host is allowed, returning a permit to hold while it is in flight
+    pub async fn acquire(&self, host: &str) -> ThrottlePermit {
+        let state = self.host(host);
+        let permit = match &state.semaphore {
+            // the semaphore is never closed, so acquiring only fails if it was
+            Some(semaphore) => semaphore.clone().acquire_owned().await.ok(),
+            None => None,
+        };
+        loop {
+            let wait = state.bucket.lock().expect("throttle lock poisoned").take(state.limits.rate, Instant::now());
+            match wait {
+                Some(wait) => tokio::time::sleep(wait).await,
+                None => break,
+            }
+        }
+        ThrottlePermit{_permit: permit}
+    }
+
+    /// Adapt to a response from host. Retry-After on a 429 or 503 pauses the host, as does
+    /// X-RateLimit-Remaining (or RateLimit-Remaining) reaching 0 until X-RateLimit-Reset.
+    /// A lower remaining count than the bucket holds shrinks the bucket to match
+    pub fn observe(&self, host: &str, status: StatusCode, headers: &HeaderMap) {
+        let state = self.host(host);
+        let now = Instant::now();
+        let mut bucket = state.bucket.lock().expect("throttle lock poisoned");
+        if status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE {
+            match retry_after(headers) {
+                Some(delay) => bucket.pause(delay, now),
+                // without a Retry-After, back off for a moment rather than retrying straight away
+                None if status == StatusCode::TOO_MANY_REQUESTS => bucket.pause(DEFAULT_PAUSE, now),
+                None => {},
+            }
+        }
+        let remaining = header_u64(headers, "X-RateLimit-Remaining").or_else(|| header_u64(headers, "RateLimit-Remaining"));
+        match remaining {
+            Some(0) => {
+                let reset = header_u64(headers, "X-RateLimit-Reset").or_else(|| header_u64(headers, "RateLimit-Reset"));
+                match reset {
+                    Some(reset) => bucket.pause(reset_delay(reset), now),
+                    None => bucket.tokens = 0.0,
+                }
+            },
+            Some(remaining) => bucket.tokens = bucket.tokens.min(remaining as f64),
+            None => {},
+        }
+    }
+}
+
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self::new(Limits::default())
+    }
+}
+
+
+/// Parse a Retry-After header, given either in seconds or as an http date
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs))
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO))
+}
+
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.trim().parse().ok()
+}
+
+
+// a reset header is either a number of seconds or, from some servers, a unix timestamp
+fn reset_delay(reset: u64) -> Duration {
+    if reset < EPOCH_THRESHOLD {
+        return Duration::from_secs(reset)
+    }
+    Duration::from_secs(reset.saturating_sub(unix_now()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bucket(tokens: f64, now: Instant) -> Bucket {
+        Bucket{tokens, updated: now, paused_until: None}
+    }
+
+    #[test]
+    fn bucket_waits_for_the_next_token() {
+        let now = Instant::now();
+        let rate = Some((2, Duration::from_secs(1)));
+        let mut bucket = bucket(2.0, now);
+        assert_eq!(bucket.take(rate, now), None);
+        assert_eq!(bucket.take(rate, now), None);
+        assert_eq!(bucket.take(rate, now), Some(Duration::from_millis(500)));
+        assert_eq!(bucket.take(rate, now + Duration::from_millis(500)), None);
+        // without a rate only pauses hold requests back
+        assert_eq!(bucket.take(None, now), None);
+    }
+
+    #[test]
+    fn pauses_keep_the_latest_deadline() {
+        let now = Instant::now();
+        let mut bucket = bucket(1.0, now);
+        bucket.pause(Duration::from_secs(5), now);
+        bucket.pause(Duration::from_secs(2), now);
+        assert_eq!(bucket.take(None, now), Some(Duration::from_secs(5)));
+        bucket.pause(Duration::from_secs(24 * 60 * 60), now);
+        assert_eq!(bucket.take(None, now), Some(MAX_PAUSE));
+        assert_eq!(bucket.take(Some((1, Duration::from_secs(1))), now + MAX_PAUSE), None);
+    }
+
+    #[test]
+    fn observe_shrinks_and_pauses() {
+        let throttle = Throttle::new(Limits::new().per_second(10));
+        let mut headers = HeaderMap::new();
+        headers.insert("X-RateLimit-Remaining", "3".parse().unwrap());
+        throttle.observe("h", StatusCode::OK, &headers);
+        assert_eq!(throttle.host("h").bucket.lock().unwrap().tokens, 3.0);
+        headers.insert("X-RateLimit-Remaining", "0".parse().unwrap());
+        headers.insert("X-RateLimit-Reset", "30".parse().unwrap());
+        throttle.observe("h", StatusCode::OK, &headers);
+        assert!(throttle.host("h").bucket.lock().unwrap().paused_until.is_some());
+    }
+
+    #[test]
+    fn parses_retry_after_and_reset() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RETRY_AFTER, "120".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(120)));
+        headers.insert(header::RETRY_AFTER, "Thu, 01 Jan 1970 00:00:00 GMT".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::ZERO));
+        assert_eq!(reset_delay(30), Duration::from_secs(30));
+        let delay = reset_delay(unix_now() + 60).as_secs();
+        assert!((59..=60).contains(&delay));
+    }
+}
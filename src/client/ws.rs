@@ -0,0 +1,265 @@
+//! The ws module connects to ws:// endpoints and exchanges JSON messages over them,
+//! optionally reconnecting whenever the connection drops.
+
+
+// crates.io
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{MaybeTlsStream, tungstenite::{
+    client::IntoClientRequest, error::ProtocolError, http::HeaderValue, Error as WsError,
+}};
+// this crate
+use crate::err::HypErr;
+use crate::server::ws::WebSocket;
+use super::{get_api_key, sse::Reconnect};
+
+
+/// The connection type returned by connect
+pub type ClientWebSocket = WebSocket<MaybeTlsStream<TcpStream>>;
+
+
+/// Open a WebSocket connection to the url, i.e. "ws://127.0.0.1:8080/ws".
+/// An optional X-Api-Key can be provided using optkey.
+/// If optkey is none, it will look for the environment variable X_API_KEY.
+/// The returned WebSocket has the same send_json/recv_json helpers used by server::ws.
+pub async fn connect(url: &str, optkey: Option<&str>) -> Result<ClientWebSocket, HypErr> {
+    let x_api_key = get_api_key(optkey);
+    connect_with_key(url, &x_api_key).await
+}
+
+
+async fn connect_with_key(url: &str, x_api_key: &str) -> Result<ClientWebSocket, HypErr> {
+    let mut request = url.into_client_request()?;
+    let key = HeaderValue::from_str(x_api_key).map_err(|e| WsError::HttpFormat(e.into()))?;
+    request.headers_mut().insert("X-Api-Key", key);
+    let (inner, _resp) = tokio_tungstenite::connect_async(request).await?;
+    Ok(WebSocket::new(inner))
+}
+
+
+// can the error be cured by reconnecting? That is when the connection dropped or was closed,
+// or the server failed the upgrade with a 5xx, but not when it refused it (i.e. 401 or 404)
+// or the peer broke the protocol or sent a message too large, which would happen again
+fn is_transient(err: &HypErr) -> bool {
+    let err = match err {
+        HypErr::WebSocket(err) => err.as_ref(),
+        _ => return false,
+    };
+    match err {
+        WsError::ConnectionClosed | WsError::AlreadyClosed | WsError::Io(_) => true,
+        WsError::Protocol(ProtocolError::ResetWithoutClosingHandshake) => true,
+        WsError::Http(resp) => resp.status().is_server_error(),
+        _ => false,
+    }
+}
+
+
+/// A ReconnectingWebSocket transparently reopens its connection, with backoff, whenever it drops.
+/// The connection is opened lazily by the first send or receive.
+/// Messages the server sent while the connection was down are lost,
+/// so the protocol should let the client resubscribe or catch up after reconnecting.
+/// Only dropped or closed connections are retried: a refused upgrade (i.e. 401 Unauthorized),
+/// a protocol error or a message too large is returned at once.
+/// # Examples:
+/// ```ignore
+/// let mut socket = ws::ReconnectingWebSocket::new("ws://127.0.0.1:8080/ws", None, Reconnect::default());
+/// socket.send_json(&Subscribe{topic: "prices"}).await?;
+/// loop {
+///     let price: Price = socket.recv_json().await?;
+///     println!("{:?}", price);
+/// }
+/// ```
+pub struct ReconnectingWebSocket {
+    url: String,
+    x_api_key: String,
+    reconnect: Reconnect,
+    socket: Option<ClientWebSocket>,
+    // consecutive failed connection attempts
+    attempts: u32,
+}
+
+
+impl ReconnectingWebSocket {
+    pub fn new(url: &str, optkey: Option<&str>, reconnect: Reconnect) -> Self {
+        ReconnectingWebSocket{
+            url: url.to_string(),
+            x_api_key: get_api_key(optkey),
+            reconnect,
+            socket: None,
+            attempts: 0,
+        }
+    }
+
+    /// Returns true if the connection is currently open
+    pub fn is_connected(&self) -> bool {
+        self.socket.is_some()
+    }
+
+    // return the open connection, connecting (and backing off) until it succeeds or attempts are exhausted
+    async fn socket(&mut self) -> Result<&mut ClientWebSocket, HypErr> {
+        while self.socket.is_none() {
+            if self.attempts > 0 {
+                tokio::time::sleep(self.reconnect.delay(self.attempts)).await;
+            }
+            match connect_with_key(&self.url, &self.x_api_key).await {
+                Ok(socket) => {
+                    self.socket = Some(socket);
+                    self.attempts = 0;
+                },
+                Err(err) => {
+                    self.attempts += 1;
+                    if !is_transient(&err) || self.reconnect.exhausted(self.attempts) {
+                        self.attempts = 0;
+                        return Err(err)
+                    }
+                },
+            }
+        }
+        Ok(self.socket.as_mut().expect("socket was just connected"))
+    }
+
+    /// Send any serializable struct as a JSON text message.
+    /// If the connection turns out to be broken, it is reopened and the message is sent once more.
+    pub async fn send_json<T: Serialize>(&mut self, payload: &T) -> Result<(), HypErr> {
+        let socket = self.socket().await?;
+        match socket.send_json(payload).await {
+            Err(err) if is_transient(&err) => {
+                self.socket = None;
+                self.attempts = 1;
+                self.socket().await?.send_json(payload).await
+            },
+            Err(err @ HypErr::WebSocket(_)) => {
+                self.socket = None;
+                Err(err)
+            },
+            other => other,
+        }
+    }
+
+    /// Let T be any struct implementing serde::de::DeserializeOwned.
+    /// Wait for the next message and deserialize it from JSON, reconnecting if the connection drops.
+    /// Returns an error if the message cannot be deserialized, the connection fails in a way
+    /// reconnecting cannot cure, or the reconnect attempts are exhausted.
+    pub async fn recv_json<T: DeserializeOwned>(&mut self) -> Result<T, HypErr> {
+        loop {
+            let socket = self.socket().await?;
+            match socket.recv_data().await {
+                Ok(Some(data)) => return Ok(serde_json::from_slice::<T>(&data)?),
+                // the server closed the connection or it dropped, so reconnect after a delay
+                Ok(None) => self.attempts = 1,
+                Err(err) if is_transient(&err) => self.attempts = 1,
+                // the connection cannot be used after a protocol error, so the next call opens a new one
+                Err(err) => {
+                    self.socket = None;
+                    return Err(err)
+                },
+            }
+            self.socket = None;
+        }
+    }
+
+    /// Close the current connection, if any. The next send or receive opens a new one
+    pub async fn close(&mut self) -> Result<(), HypErr> {
+        match self.socket.take() {
+            Some(socket) => socket.close().await,
+            None => Ok(()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{convert::Infallible, net::SocketAddr, sync::{atomic::{AtomicU32, Ordering}, Arc}, time::Duration};
+    use futures_util::{SinkExt, StreamExt};
+    use hyper::{service::{make_service_fn, service_fn}, Body, Request, Response, Server, StatusCode};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::Message;
+
+    fn fast_reconnect(max_attempts: Option<u32>) -> Reconnect {
+        Reconnect{initial_delay: Duration::from_millis(1), max_delay: Duration::from_millis(1), max_attempts}
+    }
+
+    // accept WebSocket connections, handing each to handle along with its number (counting from 1)
+    async fn serve<F, Fut>(handle: F) -> (SocketAddr, Arc<AtomicU32>)
+    where
+        F: Fn(u32, TcpStream) -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicU32::new(0));
+        let count = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let n = count.fetch_add(1, Ordering::SeqCst) + 1;
+                tokio::spawn(handle(n, stream));
+            }
+        });
+        (addr, connections)
+    }
+
+    #[tokio::test]
+    async fn round_trips_json() {
+        let (addr, _) = serve(|_, stream| async move {
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(Ok(msg)) = socket.next().await {
+                if msg.is_text() {
+                    socket.send(msg).await.unwrap();
+                }
+            }
+        }).await;
+        let mut socket = ReconnectingWebSocket::new(&format!("ws://{}/ws", addr), None, fast_reconnect(Some(3)));
+        socket.send_json(&serde_json::json!({"n": 1})).await.unwrap();
+        assert_eq!(socket.recv_json::<serde_json::Value>().await.unwrap(), serde_json::json!({"n": 1}));
+        assert!(socket.is_connected());
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_the_server_closes() {
+        let (addr, connections) = serve(|n, stream| async move {
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            socket.send(Message::Text(format!("{{\"connection\": {}}}", n))).await.unwrap();
+            socket.close(None).await.ok();
+        }).await;
+        let mut socket = ReconnectingWebSocket::new(&format!("ws://{}/ws", addr), None, fast_reconnect(Some(3)));
+        assert_eq!(socket.recv_json::<serde_json::Value>().await.unwrap()["connection"], 1);
+        assert_eq!(socket.recv_json::<serde_json::Value>().await.unwrap()["connection"], 2);
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        // every connection is dropped before the handshake completes
+        let (addr, connections) = serve(|_, stream| async move { drop(stream) }).await;
+        let mut socket = ReconnectingWebSocket::new(&format!("ws://{}/ws", addr), None, fast_reconnect(Some(3)));
+        assert!(socket.recv_json::<serde_json::Value>().await.is_err());
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+        assert!(!socket.is_connected());
+    }
+
+    #[tokio::test]
+    async fn refused_upgrades_are_not_retried() {
+        let requests = Arc::new(AtomicU32::new(0));
+        let make_service = {
+            let requests = requests.clone();
+            make_service_fn(move |_| {
+                let requests = requests.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |_req: Request<Body>| {
+                        requests.fetch_add(1, Ordering::SeqCst);
+                        async { Response::builder().status(StatusCode::UNAUTHORIZED).body(Body::empty()) }
+                    }))
+                }
+            })
+        };
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        let mut socket = ReconnectingWebSocket::new(&format!("ws://{}/ws", addr), None, fast_reconnect(None));
+        let err = socket.recv_json::<serde_json::Value>().await.unwrap_err();
+        assert!(matches!(&err, HypErr::WebSocket(err) if matches!(err.as_ref(), WsError::Http(resp) if resp.status() == 401)));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+}
@@ -18,14 +18,104 @@ pub enum ApiKeyError {
 }
 
 
+/// This error captures a missing or rejected bearer token
+#[derive(Debug)]
+pub enum TokenError {
+    /// Return this variant when the request has no Authorization: Bearer header
+    Missing,
+    /// Return this variant when the token could not be parsed
+    Malformed(String),
+    /// Return this variant when no configured key verifies the signature
+    InvalidSignature,
+    /// Return this variant when the token's exp claim has passed
+    Expired,
+    /// Return this variant when the token's nbf claim has not been reached yet
+    NotYetValid,
+    /// Return this variant when the token's iss claim is not the expected issuer
+    InvalidIssuer,
+    /// Return this variant when the token's aud claim does not include the expected audience
+    InvalidAudience,
+    /// Return this variant when a configured key (i.e. a PEM or JWKS file) could not be loaded
+    InvalidKey(String),
+}
+
+
+impl std::error::Error for TokenError {}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenError::Missing => write!(f, "No bearer token was provided"),
+            TokenError::Malformed(reason) => write!(f, "The bearer token is malformed: {}", reason),
+            TokenError::InvalidSignature => write!(f, "The bearer token signature is invalid"),
+            TokenError::Expired => write!(f, "The bearer token has expired"),
+            TokenError::NotYetValid => write!(f, "The bearer token is not valid yet"),
+            TokenError::InvalidIssuer => write!(f, "The bearer token has the wrong issuer"),
+            TokenError::InvalidAudience => write!(f, "The bearer token has the wrong audience"),
+            TokenError::InvalidKey(reason) => write!(f, "A token verification key could not be loaded: {}", reason),
+        }
+    }
+}
+
+
+/// This error captures a missing or rejected webhook signature
+#[derive(Debug)]
+pub enum SignatureError {
+    /// Return this variant when the signature or timestamp header is missing or unreadable
+    Missing,
+    /// Return this variant when the signature does not match the body
+    Invalid,
+    /// Return this variant when the timestamp is too far from the current time, i.e. a replayed request
+    Stale,
+}
+
+
+impl std::error::Error for SignatureError {}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignatureError::Missing => write!(f, "No webhook signature was provided"),
+            SignatureError::Invalid => write!(f, "The webhook signature is invalid"),
+            SignatureError::Stale => write!(f, "The webhook timestamp is too old or too far in the future"),
+        }
+    }
+}
+
+
 /// This error captures several things that can go wrong when responding to a request 
 #[derive(Debug)]
 pub enum HypErr {
     ApiKey(ApiKeyError),
+    /// Return this variant when a bearer token is missing or was rejected
+    Token(TokenError),
+    /// Return this variant when a webhook signature is missing or was rejected
+    Signature(SignatureError),
     Arg(ArgError),
     SerdeJSON(serde_json::Error),
     Hyper(hyper::Error),
     HyperHTTP(hyper::http::Error),
+    /// Return this variant when reading or writing a file or socket failed
+    Io(std::io::Error),
+    /// Return this variant when the server answered with an unexpected status code
+    Status(hyper::StatusCode),
+    /// Return this variant when a body uses a Content-Encoding this crate cannot decode
+    UnsupportedEncoding(String),
+    /// Return this variant when a body grows past the size limit (in bytes) it was read with
+    BodyTooLarge(usize),
+    /// Return this variant when a body could not be encoded or decoded in a format other than JSON,
+    /// i.e. MessagePack, CBOR or an application/x-www-form-urlencoded form
+    Format(String),
+    /// Return this variant when a multipart/form-data body is malformed
+    Multipart(String),
+    /// Return this variant when a redirect could not be followed, i.e. after too many hops
+    Redirect(String),
+    /// Return this variant when a request was refused without being sent, because the circuit
+    /// breaker for its host (given here) is open
+    CircuitOpen(String),
+    /// Return this variant when a websocket handshake or message failed.
+    /// The tungstenite error is boxed because it is much larger than the other variants
+    WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
 }
 
 impl std::error::Error for HypErr {}
@@ -49,6 +139,18 @@ impl From<ApiKeyError> for HypErr {
     }
 }
 
+impl From<TokenError> for HypErr {
+    fn from(err: TokenError) -> Self {
+        HypErr::Token(err)
+    }
+}
+
+impl From<SignatureError> for HypErr {
+    fn from(err: SignatureError) -> Self {
+        HypErr::Signature(err)
+    }
+}
+
 impl From<MalformedArg> for HypErr {
     fn from(err: MalformedArg) -> Self {
         let argerr = ArgError::from(err);
@@ -83,6 +185,33 @@ impl From<hyper::http::Error> for HypErr {
     }
 }
 
+impl From<tokio_tungstenite::tungstenite::Error> for HypErr {
+    fn from(err: tokio_tungstenite::tungstenite::Error) -> Self {
+        HypErr::WebSocket(Box::new(err))
+    }
+}
+
+impl From<multer::Error> for HypErr {
+    fn from(err: multer::Error) -> Self {
+        match err {
+            multer::Error::StreamSizeExceeded{limit} => HypErr::BodyTooLarge(limit as usize),
+            multer::Error::FieldSizeExceeded{limit, ..} => HypErr::BodyTooLarge(limit as usize),
+            // multer wraps the whole stream limit in the error of the stream it reads
+            multer::Error::StreamReadFailed(err) => match err.downcast::<multer::Error>() {
+                Ok(err) => HypErr::from(*err),
+                Err(err) => HypErr::Multipart(format!("failed to read stream: {}", err)),
+            },
+            err => HypErr::Multipart(err.to_string()),
+        }
+    }
+}
+
+impl From<std::io::Error> for HypErr {
+    fn from(err: std::io::Error) -> Self {
+        HypErr::Io(err)
+    }
+}
+
 
 
 /// The MissingArg error indicates that a required url argument (i.e. "&key=val" etc.) was not
@@ -167,4 +296,3 @@ impl fmt::Display for ArgError {
         }
     }
 }
-
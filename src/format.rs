@@ -0,0 +1,79 @@
+//! The format module lets bodies be encoded as something other than JSON.
+//! JSON is always available, while the binary formats, which are smaller and faster to parse for
+//! high-volume internal traffic, are behind the cargo features "msgpack" and "cbor".
+//! Pick a format with a type parameter, i.e. server::get_payload_as::<MsgPack, _>(req)
+//! or client.post_as::<Cbor, _, _>(url, &payload).
+
+
+// crates.io
+use serde::{de::DeserializeOwned, Serialize};
+// this crate
+use crate::err::HypErr;
+
+
+/// A serde data format and the media type it is sent with
+pub trait Format {
+    /// The media type sent as Content-Type and Accept
+    const CONTENT_TYPE: &'static str;
+
+    fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, HypErr>;
+
+    fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, HypErr>;
+}
+
+
+/// JSON, as in the rest of this crate. Errors are returned as HypErr::SerdeJSON
+#[derive(Debug, Clone, Copy)]
+pub struct Json;
+
+impl Format for Json {
+    const CONTENT_TYPE: &'static str = "application/json";
+
+    fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, HypErr> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, HypErr> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+
+/// MessagePack, with structs encoded as maps so fields can be added without breaking older readers
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy)]
+pub struct MsgPack;
+
+#[cfg(feature = "msgpack")]
+impl Format for MsgPack {
+    const CONTENT_TYPE: &'static str = "application/msgpack";
+
+    fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, HypErr> {
+        rmp_serde::to_vec_named(value).map_err(|e| HypErr::Format(e.to_string()))
+    }
+
+    fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, HypErr> {
+        rmp_serde::from_slice(bytes).map_err(|e| HypErr::Format(e.to_string()))
+    }
+}
+
+
+/// CBOR (RFC 8949)
+#[cfg(feature = "cbor")]
+#[derive(Debug, Clone, Copy)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Format for Cbor {
+    const CONTENT_TYPE: &'static str = "application/cbor";
+
+    fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, HypErr> {
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(value, &mut bytes).map_err(|e| HypErr::Format(e.to_string()))?;
+        Ok(bytes)
+    }
+
+    fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, HypErr> {
+        ciborium::de::from_reader(bytes).map_err(|e| HypErr::Format(e.to_string()))
+    }
+}
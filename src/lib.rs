@@ -6,3 +6,5 @@
 pub mod client;
 pub mod server;
 pub mod err;
+pub mod format;
+mod util;
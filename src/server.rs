@@ -5,24 +5,132 @@
 use std::{collections::HashMap};
 // crates.io
 use url::Url;
+use futures_util::{Stream, StreamExt};
 use serde::{Serialize, Deserialize, de::DeserializeOwned};
-use hyper::{header, body::Buf, Body, Request, Response, StatusCode};
+use bytes::{Bytes, BytesMut};
+use hyper::{header, body::HttpBody, Body, Request, Response, StatusCode};
 // this crate 
 use crate::err::{ArgError, HypErr, MissingArg, MalformedArg};
+use crate::format::Format;
+
+pub mod auth;
+pub mod compress;
+pub mod cookies;
+pub mod etag;
+pub mod files;
+mod form;
+pub mod jwt;
+pub mod multipart;
+pub mod negotiate;
+pub mod rate_limit;
+pub mod session;
+pub mod sse;
+pub mod webhook;
+pub mod ws;
+
+
+const MSG_NOT_FOUND: &str = "ITEM NOT FOUND";
+const APPLICATION_JSON: &str = "application/json";
+const APPLICATION_NDJSON: &str = "application/x-ndjson";
+
+
+/// By default, a request body may not be larger than this many bytes (16 MiB), before or after decompression
+pub const MAX_DECOMPRESSED_PAYLOAD: usize = 16 * 1024 * 1024;
+
+
+// read a whole body, failing as soon as it grows past max_size bytes
+pub(crate) async fn read_body_limited(mut body: Body, max_size: usize) -> Result<Bytes, HypErr> {
+    // a Content-Length over the limit is refused before anything is read
+    if HttpBody::size_hint(&body).lower() > max_size as u64 {
+        return Err(HypErr::BodyTooLarge(max_size))
+    }
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if buf.len() + chunk.len() > max_size {
+            return Err(HypErr::BodyTooLarge(max_size))
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf.freeze())
+}
 
 
-const MSG_NOT_FOUND: &'static str = "ITEM NOT FOUND";
-const APPLICATION_JSON: &'static str = "application/json";
+/// Read the whole body of a request, decompressing it according to its Content-Encoding header.
+/// The body as sent and the decompressed body may each be at most max_size bytes, past which
+/// HypErr::BodyTooLarge is returned, which guards against huge uploads and "zip bombs" alike.
+/// An unknown encoding returns HypErr::UnsupportedEncoding. These map naturally to 413 and 415 responses.
+pub async fn read_decoded_body(req: Request<Body>, max_size: usize) -> Result<Bytes, HypErr> {
+    let (parts, body) = req.into_parts();
+    let bytes = read_body_limited(body, max_size).await?;
+    decode_body(&parts.headers, bytes, max_size)
+}
+
+
+// decompress a body read with read_body_limited according to its Content-Encoding header
+fn decode_body(headers: &header::HeaderMap, bytes: Bytes, max_size: usize) -> Result<Bytes, HypErr> {
+    match headers.get(header::CONTENT_ENCODING).and_then(|v| v.to_str().ok()) {
+        Some(coding) => Ok(Bytes::from(compress::decode_content(&bytes, coding, max_size)?)),
+        None => Ok(bytes),
+    }
+}
 
 
 /// Aggregate the body of a request in a buffer and deserialize it.
+/// Bodies sent with a Content-Encoding of gzip, br or deflate are decompressed first.
+/// Bodies are limited to MAX_DECOMPRESSED_PAYLOAD bytes, see get_payload_limited.
 pub async fn get_payload<T: DeserializeOwned>(req: Request<Body>) -> Result<T, HypErr> {
-	let whole_body = hyper::body::aggregate(req).await?;
-	let req_payload: T =  serde_json::from_reader(whole_body.reader())?;
+    get_payload_limited(req, MAX_DECOMPRESSED_PAYLOAD).await
+}
+
+
+/// Aggregate the body of a request in a buffer and deserialize it, decompressing it according to
+/// its Content-Encoding header. Reading stops with HypErr::BodyTooLarge as soon as the body, as sent
+/// or once decompressed, grows past max_size bytes. See read_decoded_body for the other errors.
+pub async fn get_payload_limited<T: DeserializeOwned>(req: Request<Body>, max_size: usize) -> Result<T, HypErr> {
+    let bytes = read_decoded_body(req, max_size).await?;
+	let req_payload: T = serde_json::from_slice(&bytes)?;
 	Ok(req_payload)
 }
 
 
+/// Aggregate the body of a request in a buffer and deserialize it with the format F,
+/// i.e. get_payload_as::<MsgPack, Order>(req). Compressed bodies are handled as in get_payload.
+pub async fn get_payload_as<F: Format, T: DeserializeOwned>(req: Request<Body>) -> Result<T, HypErr> {
+    get_payload_as_limited::<F, T>(req, MAX_DECOMPRESSED_PAYLOAD).await
+}
+
+
+/// Aggregate the body of a request in a buffer and deserialize it with the format F,
+/// decompressing it up to max_size bytes as in get_payload_limited.
+pub async fn get_payload_as_limited<F: Format, T: DeserializeOwned>(req: Request<Body>, max_size: usize) -> Result<T, HypErr> {
+    let bytes = read_decoded_body(req, max_size).await?;
+    F::from_slice(&bytes)
+}
+
+
+/// Aggregate an application/x-www-form-urlencoded body, i.e. from an HTML form, and deserialize it.
+/// A field that is missing or does not parse returns the same ArgError as get_query_param.
+/// Fields may be strings, numbers, bools, options and unit enums, and repeated keys keep the last value.
+/// Bools also accept "on"/"off" and "1"/"0", and an empty value is None for an option.
+/// Bodies are limited to MAX_DECOMPRESSED_PAYLOAD bytes, as in get_payload.
+/// # Examples:
+/// ```ignore
+/// #[derive(Deserialize)]
+/// struct Signup { email: String, age: u32, newsletter: Option<bool> }
+///
+/// let signup: Signup = match get_form(req).await {
+///     Ok(signup) => signup,
+///     Err(HypErr::Arg(err)) => return bad_request_resp(&err),
+///     Err(err) => return Err(err),
+/// };
+/// ```
+pub async fn get_form<T: DeserializeOwned>(req: Request<Body>) -> Result<T, HypErr> {
+    let bytes = read_decoded_body(req, MAX_DECOMPRESSED_PAYLOAD).await?;
+    form::from_form_bytes(&bytes)
+}
+
+
 /// Send a simple 200 status code response with a message as a string.
 pub fn build_response_200_message(message: &str) -> Result<Response<Body>, HypErr> {
     let response = Response::builder()
@@ -42,6 +150,18 @@ pub fn build_response_json<T: Serialize>(resp_payload: &T) -> Result<Response<Bo
 }
 
 
+/// Build a response out of any serializable struct encoded with the format F, adding its Content-Type,
+/// i.e. build_response_as::<Cbor, _>(&readings)
+pub fn build_response_as<F: Format, T: Serialize>(resp_payload: &T) -> Result<Response<Body>, HypErr> {
+    let bytes = F::to_vec(resp_payload)?;
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, F::CONTENT_TYPE)
+        .body(Body::from(bytes))?;
+    Ok(response)
+}
+
+
 /// build a response out of any serializable struct, returning 404 if None was provided 
 pub fn build_response_json_404<T: Serialize>(opt_payload: &Option<T>) -> Result<Response<Body>, HypErr> {
     match opt_payload {
@@ -70,18 +190,44 @@ pub fn build_response_json_cors<T: Serialize>(resp_payload: &T) -> Result<Respon
 
 
 
+/// Build a chunked newline-delimited JSON (application/x-ndjson) response out of a stream of serializable structs.
+/// Each item is serialized and sent on its own line as soon as the stream yields it,
+/// so large datasets never have to be collected in memory.
+/// # Examples:
+/// ```ignore
+/// let users = futures_util::stream::iter(all_users);
+/// build_response_ndjson(users)
+/// ```
+pub fn build_response_ndjson<S, T>(items: S) -> Result<Response<Body>, HypErr>
+where
+    S: Stream<Item = T> + Send + 'static,
+    T: Serialize,
+{
+    let lines = items.map(|item| {
+        let mut line = serde_json::to_vec(&item)?;
+        line.push(b'\n');
+        Ok::<_, HypErr>(line)
+    });
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, APPLICATION_NDJSON)
+        .body(Body::wrap_stream(lines))?;
+    Ok(response)
+}
+
+
 /// Look for the specified in a given request, returning Some(value) if it is present
 pub fn get_header(req: &Request<Body>, header: &str) -> Option<String> {
     // Get a specific header from a request
     let header_lower = header.to_lowercase();
     for (k, v) in req.headers() {
         let key = k.as_str();
-        if key != &header_lower {
+        if key != header_lower {
             continue
         }
         match String::from_utf8(v.as_bytes().to_owned()) {
             Ok(val) => {
-                if val.len() > 0 {
+                if !val.is_empty() {
                     return Some(val)
                 } 
             },
@@ -89,7 +235,7 @@ pub fn get_header(req: &Request<Body>, header: &str) -> Option<String> {
         }
     }
     // if you reach this point, you never found the header you were looking for
-    return None
+    None
 }
 
 /// Return the CommonHeaders from a request
@@ -102,13 +248,15 @@ pub fn get_common_headers(req: &Request<Body>) -> CommonHeaders {
 }
 
 
-/// CommonHeaders is intended to capture the most frequently used request headers
+/// CommonHeaders is intended to capture the most frequently used request headers.
+/// Cookies are read with cookies::get_cookie and cookies::get_cookies
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CommonHeaders {
     pub user_agent: Option<String>,
     pub x_api_key: Option<String>,
     pub host: Option<String>,
-    pub accept: Option<String>
+    /// the raw Accept header, see the negotiate module to choose a representation with it
+    pub accept: Option<String>,
 }
 
 
@@ -138,7 +286,7 @@ pub fn get_query(req: &Request<Body>) -> HashMap<String, String> {
 
 /// Look for the value contained in a query parameter and convert it to a struct implementing std::str::FromStr
 /// # Examples:
-/// ```
+/// ```ignore
 /// let user_id: i32 = get_query_param(&req, "user_id").await?;
 /// ```
 pub fn get_query_param<T: std::str::FromStr>(req: &Request<Body>, key: &str) -> Result<T, ArgError> {
@@ -150,7 +298,7 @@ pub fn get_query_param<T: std::str::FromStr>(req: &Request<Body>, key: &str) ->
 
 /// Look for the value contained in a query parameter and convert it to an Opt<struct> implementing std::str::FromStr
 /// # Examples:
-/// ```
+/// ```ignore
 /// let page_no: Option<i32> = get_query_opt_param(&req, "page_no").await?;
 /// ```
 pub fn get_query_opt_param<T: std::str::FromStr>(req: &Request<Body>, key: &str) -> Result<Option<T>, MalformedArg> {
@@ -160,9 +308,9 @@ pub fn get_query_opt_param<T: std::str::FromStr>(req: &Request<Body>, key: &str)
         Some(val) => val,
         None => return Ok(None)
     };
-    let val = match T::from_str(&s) {
+    let val = match T::from_str(s) {
         Ok(x) => x,
-        Err(_) => return Err(MalformedArg::new(key, &s, &std::any::type_name::<T>())),
+        Err(_) => return Err(MalformedArg::new(key, s, std::any::type_name::<T>())),
     };
     Ok(Some(val))
 }
@@ -171,7 +319,7 @@ pub fn get_query_opt_param<T: std::str::FromStr>(req: &Request<Body>, key: &str)
 /// Apply CORS preflight headers.  
 /// If you want to allow CORS, Google Chrome looks for headers on BOTH the request and the preflight.  
 /// # Examples:
-/// ```
+/// ```ignore
 /// // Consider routing like this withing a server block
 /// match (req.method(), req.uri().path()) {
 ///     (&Method::OPTIONS, _) => preflight(req).await,
@@ -215,19 +363,77 @@ pub fn nginx_real_ip_only(ip_addresses: &str) -> Option<String> {
         .filter(|ip| !ip.starts_with("172."))
         .map(|ip| ip.to_string())
         .collect::<Vec<String>>();
-    match sp.get(0) {
-        Some(val) => Some(val.to_owned()),
-        None => None
-    }
+    sp.first().map(|val| val.to_owned())
 }
 
 /// this constant is used for an unknown ipv4 address but some downstream function expects a string
-pub const UNKNOWN_IP: &'static str = "?.?.?.?";
+pub const UNKNOWN_IP: &str = "?.?.?.?";
 
 
 /// This is a conveneint way for getting the ip address for an NGINX instance running in Docker
 /// using the X-Forwarded-For header. See also the  nginx_real_ip_only method
 pub fn nginx_get_ip(req: &Request<Body>) -> String {
-    let ip_addresses = get_header(&req, "X-Forwarded-For").unwrap_or(UNKNOWN_IP.to_string());
+    let ip_addresses = get_header(req, "X-Forwarded-For").unwrap_or(UNKNOWN_IP.to_string());
     nginx_real_ip_only(&ip_addresses).unwrap_or(UNKNOWN_IP.to_string())
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compress::{encode, Encoding};
+
+    fn request(body: Vec<u8>, content_encoding: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder().method("POST").uri("/");
+        if let Some(coding) = content_encoding {
+            builder = builder.header(header::CONTENT_ENCODING, coding);
+        }
+        builder.body(Body::from(body)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reads_plain_and_compressed_bodies() {
+        assert_eq!(read_decoded_body(request(b"plain".to_vec(), None), 5).await.unwrap(), "plain");
+        let gzipped = encode(b"compressed", Encoding::Gzip).unwrap();
+        assert_eq!(read_decoded_body(request(gzipped, Some("gzip")), 64).await.unwrap(), "compressed");
+    }
+
+    #[tokio::test]
+    async fn limits_the_body_as_sent() {
+        let result = read_decoded_body(request(vec![b'a'; 11], None), 10).await;
+        assert!(matches!(result, Err(HypErr::BodyTooLarge(10))));
+        let streamed = Body::wrap_stream(futures_util::stream::iter((0..2).map(|_| Ok::<_, std::io::Error>(vec![b'a'; 6]))));
+        let result = read_decoded_body(Request::new(streamed), 10).await;
+        assert!(matches!(result, Err(HypErr::BodyTooLarge(10))));
+    }
+
+    #[tokio::test]
+    async fn limits_the_decompressed_body() {
+        let bomb = encode(&vec![0u8; 100_000], Encoding::Gzip).unwrap();
+        assert!(bomb.len() < 1000);
+        let result = read_decoded_body(request(bomb, Some("gzip")), 1000).await;
+        assert!(matches!(result, Err(HypErr::BodyTooLarge(1000))));
+    }
+
+    #[tokio::test]
+    async fn get_payload_limited_applies_to_plain_json() {
+        let json = serde_json::to_vec(&vec![1; 100]).unwrap();
+        let result: Result<Vec<u8>, _> = get_payload_limited(request(json.clone(), None), json.len() - 1).await;
+        assert!(matches!(result, Err(HypErr::BodyTooLarge(_))));
+        let result: Vec<u8> = get_payload_limited(request(json.clone(), None), json.len()).await.unwrap();
+        assert_eq!(result.len(), 100);
+    }
+
+    #[tokio::test]
+    async fn every_payload_reader_is_limited() {
+        let json = serde_json::to_vec(&vec![1; 100]).unwrap();
+        let result: Result<Vec<u8>, _> = get_payload_as_limited::<crate::format::Json, _>(request(json.clone(), None), json.len() - 1).await;
+        assert!(matches!(result, Err(HypErr::BodyTooLarge(_))));
+        let gzipped = encode(&json, Encoding::Gzip).unwrap();
+        let result: Vec<u8> = get_payload_as_limited::<crate::format::Json, _>(request(gzipped, Some("gzip")), json.len()).await.unwrap();
+        assert_eq!(result.len(), 100);
+        let form = vec![b'a'; MAX_DECOMPRESSED_PAYLOAD + 1];
+        let result: Result<HashMap<String, String>, _> = get_form(request(form, None)).await;
+        assert!(matches!(result, Err(HypErr::BodyTooLarge(MAX_DECOMPRESSED_PAYLOAD))));
+    }
+}
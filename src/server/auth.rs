@@ -0,0 +1,584 @@
+//! The auth module protects endpoints with a username and password, i.e. internal admin pages.
+//! It supports HTTP Basic and Digest authentication against a pluggable CredentialStore, and
+//! PasswordStore keeps plain, bcrypt or argon2 passwords loaded from an htpasswd-style file.
+
+
+// standard library
+use std::{collections::HashMap, future::Future, path::Path, time::Duration};
+// crates.io
+use argon2::{Argon2, PasswordVerifier};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use hyper::{header, Body, Request, Response, StatusCode};
+use md5::Md5;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+// this crate
+use crate::{err::HypErr, util::{to_hex, unix_now}};
+use super::get_header;
+
+
+/// The username and password of an `Authorization: Basic` header
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // never log the password
+        f.debug_struct("Credentials").field("username", &self.username).finish()
+    }
+}
+
+
+/// Return the credentials of an `Authorization: Basic` header, if there is a well formed one
+pub fn get_basic_credentials(req: &Request<Body>) -> Option<Credentials> {
+    let authorization = get_header(req, "Authorization")?;
+    let (scheme, encoded) = authorization.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None
+    }
+    let decoded = STANDARD.decode(encoded.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some(Credentials{username: username.to_string(), password: password.to_string()})
+}
+
+
+/// The hash functions Digest authentication can use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestAlgorithm {
+    Md5,
+    Sha256,
+}
+
+
+impl DigestAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DigestAlgorithm::Md5 => "MD5",
+            DigestAlgorithm::Sha256 => "SHA-256",
+        }
+    }
+
+    pub fn parse(algorithm: &str) -> Option<Self> {
+        match algorithm.to_ascii_uppercase().as_str() {
+            "MD5" => Some(DigestAlgorithm::Md5),
+            "SHA-256" => Some(DigestAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    /// Hash a string, returning lowercase hex as Digest authentication expects
+    pub fn hash(&self, data: &str) -> String {
+        match self {
+            DigestAlgorithm::Md5 => to_hex(&Md5::digest(data.as_bytes())),
+            DigestAlgorithm::Sha256 => to_hex(&Sha256::digest(data.as_bytes())),
+        }
+    }
+}
+
+
+/// A CredentialStore decides whether a username and password are right.
+/// Implement it to check passwords against a database or directory service.
+pub trait CredentialStore: Send + Sync {
+    /// Return true if password is the password of username
+    fn verify(&self, username: &str, password: &str) -> impl Future<Output = Result<bool, HypErr>> + Send;
+
+    /// Return H(username:realm:password) for Digest authentication, or None if the store cannot,
+    /// i.e. because it only keeps bcrypt or argon2 hashes. By default Digest is not supported
+    fn digest_ha1(&self, _username: &str, _realm: &str, _algorithm: DigestAlgorithm) -> impl Future<Output = Result<Option<String>, HypErr>> + Send {
+        std::future::ready(Ok(None))
+    }
+}
+
+
+// the salt and hash used to check the passwords of unknown users, so they take as long as known ones
+const DUMMY_BCRYPT: &str = "GzQGzngwH/UjyL2EjH6kt.vV18TTwBabdQjqAB4AKjQrjhp4jmUy6";
+const DUMMY_ARGON2: &str = "ZHVtbXlzYWx0ZHVtbXlzYQ$e7S59MSbwmbs9/ayCmL5NRBY4Al8A4IklYiaTX/jg8c";
+
+
+/// A password as kept by PasswordStore
+#[derive(Clone)]
+pub enum StoredPassword {
+    /// the password itself, which is the only kind that works with Digest authentication
+    Plain(String),
+    /// a bcrypt hash, i.e. "$2b$12$..."
+    Bcrypt(String),
+    /// an argon2 hash in PHC format, i.e. "$argon2id$v=19$..."
+    Argon2(String),
+}
+
+
+impl StoredPassword {
+    /// Recognize a hash by its prefix. Plain passwords must be written as "{PLAIN}password"
+    pub fn parse(stored: &str) -> Option<Self> {
+        if let Some(plain) = stored.strip_prefix("{PLAIN}") {
+            return Some(StoredPassword::Plain(plain.to_string()))
+        }
+        if ["$2a$", "$2b$", "$2x$", "$2y$"].iter().any(|prefix| stored.starts_with(prefix)) {
+            return Some(StoredPassword::Bcrypt(stored.to_string()))
+        }
+        if stored.starts_with("$argon2") {
+            return Some(StoredPassword::Argon2(stored.to_string()))
+        }
+        None
+    }
+
+    /// Return true if password matches. Hashes are slow on purpose, so call this off the async runtime
+    pub fn matches(&self, password: &str) -> bool {
+        match self {
+            StoredPassword::Plain(plain) => bool::from(plain.as_bytes().ct_eq(password.as_bytes())),
+            StoredPassword::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            StoredPassword::Argon2(hash) => match argon2::PasswordHash::new(hash) {
+                Ok(parsed) => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+                Err(_) => false,
+            },
+        }
+    }
+
+    // a hash of no one's password, with the same algorithm and cost as this one
+    fn dummy(&self) -> StoredPassword {
+        match self {
+            StoredPassword::Plain(_) => StoredPassword::Plain(DUMMY_BCRYPT.to_string()),
+            StoredPassword::Bcrypt(hash) => StoredPassword::Bcrypt(format!("{}{}", hash.get(..7).unwrap_or("$2b$12$"), DUMMY_BCRYPT)),
+            // keep the "$argon2id$v=19$m=...,t=...,p=..." parameters, replacing the salt and hash
+            StoredPassword::Argon2(hash) => {
+                let params = hash.rsplitn(3, '$').nth(2).unwrap_or(hash);
+                StoredPassword::Argon2(format!("{}${}", params, DUMMY_ARGON2))
+            },
+        }
+    }
+}
+
+
+impl std::fmt::Debug for StoredPassword {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StoredPassword::Plain(_) => write!(f, "Plain"),
+            StoredPassword::Bcrypt(_) => write!(f, "Bcrypt"),
+            StoredPassword::Argon2(_) => write!(f, "Argon2"),
+        }
+    }
+}
+
+
+/// PasswordStore keeps users and their passwords in memory
+#[derive(Debug, Clone, Default)]
+pub struct PasswordStore {
+    users: HashMap<String, StoredPassword>,
+    // checked instead when the username is unknown, so response times do not reveal which users exist
+    dummy: Option<StoredPassword>,
+}
+
+
+impl PasswordStore {
+    pub fn new() -> Self {
+        PasswordStore::default()
+    }
+
+    pub fn with_user(mut self, username: &str, password: StoredPassword) -> Self {
+        self.insert(username, password);
+        self
+    }
+
+    fn insert(&mut self, username: &str, password: StoredPassword) {
+        // a hashed password is slower to check than a plain one, so prefer it as the dummy
+        if self.dummy.is_none() || matches!(self.dummy, Some(StoredPassword::Plain(_))) {
+            self.dummy = Some(password.dummy());
+        }
+        self.users.insert(username.to_string(), password);
+    }
+
+    /// Load users from an htpasswd-style file with one "username:hash" per line, where hash is a
+    /// bcrypt hash, an argon2 hash, or "{PLAIN}password". Blank lines and lines starting with # are skipped
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, HypErr> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut store = PasswordStore::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue
+            }
+            let password = line.split_once(':').and_then(|(username, stored)| Some((username, StoredPassword::parse(stored)?)));
+            match password {
+                Some((username, password)) => store.insert(username, password),
+                None => {
+                    let msg = format!("line {}: expected username:hash with a bcrypt, argon2 or {{PLAIN}} password", number + 1);
+                    return Err(HypErr::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, msg)))
+                },
+            };
+        }
+        Ok(store)
+    }
+}
+
+
+impl CredentialStore for PasswordStore {
+    fn verify(&self, username: &str, password: &str) -> impl Future<Output = Result<bool, HypErr>> + Send {
+        let (stored, known) = match self.users.get(username) {
+            Some(stored) => (Some(stored.clone()), true),
+            None => (self.dummy.clone(), false),
+        };
+        let password = password.to_string();
+        async move {
+            match stored {
+                // bcrypt and argon2 take long enough to stall other requests, so hash on a blocking thread
+                Some(stored) => Ok(tokio::task::spawn_blocking(move || stored.matches(&password)).await.unwrap_or(false) && known),
+                None => Ok(false),
+            }
+        }
+    }
+
+    fn digest_ha1(&self, username: &str, realm: &str, algorithm: DigestAlgorithm) -> impl Future<Output = Result<Option<String>, HypErr>> + Send {
+        let ha1 = match self.users.get(username) {
+            Some(StoredPassword::Plain(password)) => Some(algorithm.hash(&format!("{}:{}:{}", username, realm, password))),
+            _ => None,
+        };
+        std::future::ready(Ok(ha1))
+    }
+}
+
+
+/// The outcome of checking a request's credentials
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthOutcome {
+    /// the credentials were right for this username
+    Authenticated(String),
+    /// the credentials were missing or wrong
+    Rejected,
+    /// a Digest response used an expired nonce, so the client may retry with a new one without asking the user again
+    Stale,
+}
+
+
+impl AuthOutcome {
+    pub fn username(&self) -> Option<&str> {
+        match self {
+            AuthOutcome::Authenticated(username) => Some(username),
+            _ => None,
+        }
+    }
+}
+
+
+/// The authentication schemes an HttpAuth accepts and challenges with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    Basic,
+    Digest,
+}
+
+
+/// How long a Digest nonce stays valid by default
+pub const DEFAULT_NONCE_LIFETIME: Duration = Duration::from_secs(300);
+
+
+/// HttpAuth checks requests against a CredentialStore and builds the 401 challenges.
+/// # Examples:
+/// ```ignore
+/// // created once at startup: each HttpAuth signs its nonces with its own random key
+/// let auth = HttpAuth::basic("admin", PasswordStore::from_file("/etc/myservice/htpasswd")?);
+///
+/// let outcome = auth.check(&req).await?;
+/// if outcome.username().is_none() {
+///     return auth.build_response_401(&outcome)
+/// }
+/// ```
+/// Basic sends the password with every request, so only use it over https.
+/// Digest never sends the password, but needs a store that can produce H(username:realm:password).
+/// Digest nonces are signed and expire, but are not tracked, so a captured response can be replayed
+/// until its nonce expires.
+pub struct HttpAuth<S: CredentialStore> {
+    realm: String,
+    store: S,
+    scheme: Scheme,
+    nonce_key: [u8; 32],
+    nonce_lifetime: Duration,
+}
+
+
+impl<S: CredentialStore> HttpAuth<S> {
+    fn with_scheme(realm: &str, store: S, scheme: Scheme) -> Self {
+        let mut nonce_key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut nonce_key);
+        HttpAuth{realm: realm.to_string(), store, scheme, nonce_key, nonce_lifetime: DEFAULT_NONCE_LIFETIME}
+    }
+
+    /// Accept `Authorization: Basic` credentials for this realm
+    pub fn basic(realm: &str, store: S) -> Self {
+        HttpAuth::with_scheme(realm, store, Scheme::Basic)
+    }
+
+    /// Accept `Authorization: Digest` responses for this realm, with SHA-256 or MD5
+    pub fn digest(realm: &str, store: S) -> Self {
+        HttpAuth::with_scheme(realm, store, Scheme::Digest)
+    }
+
+    /// Issue Digest nonces valid for this long
+    pub fn with_nonce_lifetime(mut self, nonce_lifetime: Duration) -> Self {
+        self.nonce_lifetime = nonce_lifetime;
+        self
+    }
+
+    /// Check the Authorization header of a request
+    pub async fn check(&self, req: &Request<Body>) -> Result<AuthOutcome, HypErr> {
+        match self.scheme {
+            Scheme::Basic => {
+                let credentials = match get_basic_credentials(req) {
+                    Some(credentials) => credentials,
+                    None => return Ok(AuthOutcome::Rejected),
+                };
+                if self.store.verify(&credentials.username, &credentials.password).await? {
+                    return Ok(AuthOutcome::Authenticated(credentials.username))
+                }
+                Ok(AuthOutcome::Rejected)
+            },
+            Scheme::Digest => self.check_digest(req).await,
+        }
+    }
+
+    async fn check_digest(&self, req: &Request<Body>) -> Result<AuthOutcome, HypErr> {
+        let params = match get_header(req, "Authorization").and_then(|v| parse_digest_header(&v)) {
+            Some(params) => params,
+            None => return Ok(AuthOutcome::Rejected),
+        };
+        let param = |name: &str| params.get(name).map(|v| v.as_str());
+        let (username, nonce, uri, response) = match (param("username"), param("nonce"), param("uri"), param("response")) {
+            (Some(username), Some(nonce), Some(uri), Some(response)) => (username, nonce, uri, response),
+            _ => return Ok(AuthOutcome::Rejected),
+        };
+        let algorithm = match DigestAlgorithm::parse(param("algorithm").unwrap_or("MD5")) {
+            Some(algorithm) => algorithm,
+            None => return Ok(AuthOutcome::Rejected),
+        };
+        let request_target = req.uri().path_and_query().map_or("/", |pq| pq.as_str());
+        if param("realm") != Some(self.realm.as_str()) || uri != request_target {
+            return Ok(AuthOutcome::Rejected)
+        }
+        let issued = match self.nonce_issued(nonce) {
+            Some(issued) => issued,
+            None => return Ok(AuthOutcome::Rejected),
+        };
+        let ha1 = match self.store.digest_ha1(username, &self.realm, algorithm).await? {
+            Some(ha1) => ha1,
+            None => return Ok(AuthOutcome::Rejected),
+        };
+        let ha2 = algorithm.hash(&format!("{}:{}", req.method(), uri));
+        let expected = match (param("qop"), param("nc"), param("cnonce")) {
+            (Some("auth"), Some(nc), Some(cnonce)) => algorithm.hash(&format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2)),
+            (None, _, _) => algorithm.hash(&format!("{}:{}:{}", ha1, nonce, ha2)), // RFC 2069 clients
+            _ => return Ok(AuthOutcome::Rejected),
+        };
+        if !bool::from(expected.as_bytes().ct_eq(response.to_ascii_lowercase().as_bytes())) {
+            return Ok(AuthOutcome::Rejected)
+        }
+        // only report a stale nonce once the response proves the client knows the password
+        if unix_now().saturating_sub(issued) > self.nonce_lifetime.as_secs() {
+            return Ok(AuthOutcome::Stale)
+        }
+        Ok(AuthOutcome::Authenticated(username.to_string()))
+    }
+
+    // a nonce is the time it was issued, followed by a mac of that time so clients cannot forge one
+    fn new_nonce(&self) -> String {
+        let issued = format!("{:016x}", unix_now());
+        format!("{}{}", issued, self.nonce_mac(&issued))
+    }
+
+    fn nonce_mac(&self, issued: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.nonce_key).expect("hmac accepts any key length");
+        mac.update(issued.as_bytes());
+        to_hex(&mac.finalize().into_bytes()[..16])
+    }
+
+    // return when a nonce was issued, if this server issued it
+    fn nonce_issued(&self, nonce: &str) -> Option<u64> {
+        if nonce.len() != 48 || !nonce.is_ascii() {
+            return None
+        }
+        let (issued, mac) = nonce.split_at(16);
+        if !bool::from(self.nonce_mac(issued).as_bytes().ct_eq(mac.as_bytes())) {
+            return None
+        }
+        u64::from_str_radix(issued, 16).ok()
+    }
+
+    /// Build a 401 Unauthorized response with the WWW-Authenticate challenge for the scheme.
+    /// Digest offers SHA-256 and MD5, since some clients only support MD5
+    pub fn build_response_401(&self, outcome: &AuthOutcome) -> Result<Response<Body>, HypErr> {
+        let realm = self.realm.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut builder = Response::builder().status(StatusCode::UNAUTHORIZED);
+        match self.scheme {
+            Scheme::Basic => {
+                builder = builder.header(header::WWW_AUTHENTICATE, format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm));
+            },
+            Scheme::Digest => {
+                let nonce = self.new_nonce();
+                let stale = if *outcome == AuthOutcome::Stale { ", stale=true" } else { "" };
+                for algorithm in [DigestAlgorithm::Sha256, DigestAlgorithm::Md5] {
+                    let challenge = format!(
+                        "Digest realm=\"{}\", qop=\"auth\", algorithm={}, nonce=\"{}\"{}",
+                        realm, algorithm.as_str(), nonce, stale,
+                    );
+                    builder = builder.header(header::WWW_AUTHENTICATE, challenge);
+                }
+            },
+        }
+        Ok(builder.body(Body::from("UNAUTHORIZED"))?)
+    }
+}
+
+
+impl<S: CredentialStore + std::fmt::Debug> std::fmt::Debug for HttpAuth<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // the nonce key is left out, since anyone holding it could forge nonces
+        f.debug_struct("HttpAuth")
+            .field("realm", &self.realm)
+            .field("store", &self.store)
+            .field("scheme", &self.scheme)
+            .field("nonce_lifetime", &self.nonce_lifetime)
+            .finish()
+    }
+}
+
+
+// parse the comma separated name=value pairs of an `Authorization: Digest` header,
+// where values may be quoted strings containing commas and backslash escapes
+fn parse_digest_header(authorization: &str) -> Option<HashMap<String, String>> {
+    let (scheme, rest) = authorization.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("digest") {
+        return None
+    }
+    let mut params = HashMap::new();
+    let mut chars = rest.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace() || *c == ',') {
+            chars.next();
+        }
+        let name: String = chars.by_ref().take_while(|c| *c != '=').collect();
+        if name.is_empty() {
+            break
+        }
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next()),
+                    '"' => break,
+                    c => value.push(c),
+                }
+            }
+        } else {
+            value = chars.by_ref().take_while(|c| *c != ',').collect::<String>().trim().to_string();
+        }
+        params.insert(name.trim().to_ascii_lowercase(), value);
+    }
+    Some(params)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REALM: &str = "admin";
+
+    fn digest_request(auth: &HttpAuth<PasswordStore>, nonce: &str, password: &str) -> Request<Body> {
+        let algorithm = DigestAlgorithm::Sha256;
+        let ha1 = algorithm.hash(&format!("alice:{}:{}", REALM, password));
+        let ha2 = algorithm.hash("GET:/admin?page=2");
+        let response = algorithm.hash(&format!("{}:{}:00000001:xyz:auth:{}", ha1, nonce, ha2));
+        let authorization = format!(
+            "Digest username=\"alice\", realm=\"{}\", nonce=\"{}\", uri=\"/admin?page=2\", qop=auth, nc=00000001, cnonce=\"xyz\", algorithm=SHA-256, response=\"{}\"",
+            auth.realm, nonce, response,
+        );
+        Request::builder().uri("/admin?page=2").header("Authorization", authorization).body(Body::empty()).unwrap()
+    }
+
+    fn digest_auth() -> HttpAuth<PasswordStore> {
+        HttpAuth::digest(REALM, PasswordStore::new().with_user("alice", StoredPassword::Plain("secret".to_string())))
+    }
+
+    #[test]
+    fn parses_quoted_and_bare_values() {
+        let params = parse_digest_header(r#"Digest username="a\"b", Realm="x, y",qop=auth , nc=01"#).unwrap();
+        assert_eq!(params["username"], "a\"b");
+        assert_eq!(params["realm"], "x, y");
+        assert_eq!(params["qop"], "auth");
+        assert_eq!(params["nc"], "01");
+        assert!(parse_digest_header("Basic abc").is_none());
+        assert!(parse_digest_header("Digest").is_none());
+    }
+
+    #[tokio::test]
+    async fn digest_accepts_the_right_password() {
+        let auth = digest_auth();
+        let nonce = auth.new_nonce();
+        let outcome = auth.check(&digest_request(&auth, &nonce, "secret")).await.unwrap();
+        assert_eq!(outcome, AuthOutcome::Authenticated("alice".to_string()));
+        let outcome = auth.check(&digest_request(&auth, &nonce, "wrong")).await.unwrap();
+        assert_eq!(outcome, AuthOutcome::Rejected);
+    }
+
+    #[tokio::test]
+    async fn expired_nonces_are_stale() {
+        let auth = digest_auth().with_nonce_lifetime(Duration::from_secs(60));
+        let issued = format!("{:016x}", unix_now() - 61);
+        let nonce = format!("{}{}", issued, auth.nonce_mac(&issued));
+        assert_eq!(auth.check(&digest_request(&auth, &nonce, "secret")).await.unwrap(), AuthOutcome::Stale);
+        // a stale nonce is only reported once the password is right
+        assert_eq!(auth.check(&digest_request(&auth, &nonce, "wrong")).await.unwrap(), AuthOutcome::Rejected);
+        let challenge = auth.build_response_401(&AuthOutcome::Stale).unwrap();
+        assert!(challenge.headers()[header::WWW_AUTHENTICATE].to_str().unwrap().ends_with("stale=true"));
+    }
+
+    #[tokio::test]
+    async fn forged_nonces_are_rejected() {
+        let auth = digest_auth();
+        let other = digest_auth();
+        let forged = other.new_nonce();
+        assert_eq!(auth.check(&digest_request(&auth, &forged, "secret")).await.unwrap(), AuthOutcome::Rejected);
+        assert_eq!(auth.nonce_issued("0000"), None);
+    }
+
+    #[tokio::test]
+    async fn unknown_users_are_checked_against_a_dummy() {
+        let store = PasswordStore::new()
+            .with_user("plain", StoredPassword::Plain("secret".to_string()))
+            .with_user("bcrypt", StoredPassword::parse("$2b$04$GzQGzngwH/UjyL2EjH6kt.vV18TTwBabdQjqAB4AKjQrjhp4jmUy6").unwrap());
+        let dummy = match &store.dummy {
+            Some(StoredPassword::Bcrypt(hash)) => hash.clone(),
+            other => panic!("expected a bcrypt dummy, got {:?}", other),
+        };
+        assert!(dummy.starts_with("$2b$04$"));
+        assert!(bcrypt::verify("dummy password", &dummy).is_ok());
+        assert!(store.verify("bcrypt", "dummy password").await.unwrap());
+        assert!(!store.verify("nobody", "dummy password").await.unwrap());
+        assert!(!PasswordStore::new().verify("nobody", "").await.unwrap());
+    }
+
+    #[test]
+    fn argon2_dummy_keeps_the_parameters() {
+        let stored = StoredPassword::Argon2("$argon2id$v=19$m=8192,t=3,p=1$c2FsdHNhbHQ$aGFzaGhhc2hoYXNo".to_string());
+        let dummy = match stored.dummy() {
+            StoredPassword::Argon2(hash) => hash,
+            other => panic!("expected an argon2 dummy, got {:?}", other),
+        };
+        assert!(dummy.starts_with("$argon2id$v=19$m=8192,t=3,p=1$ZHVtbXlzYWx0ZHVtbXlzYQ$"));
+        assert!(argon2::PasswordHash::new(&dummy).is_ok());
+    }
+
+    #[test]
+    fn basic_credentials() {
+        let encoded = STANDARD.encode("alice:pa:ss");
+        let req = Request::builder().header("Authorization", format!("Basic {}", encoded)).body(Body::empty()).unwrap();
+        let credentials = get_basic_credentials(&req).unwrap();
+        assert_eq!((credentials.username.as_str(), credentials.password.as_str()), ("alice", "pa:ss"));
+    }
+}
@@ -0,0 +1,292 @@
+//! The compress module compresses response bodies with gzip, brotli or deflate,
+//! picking whichever encoding the client prefers in its Accept-Encoding header.
+
+
+// standard library
+use std::io::{Read, Write};
+// crates.io
+use flate2::{Compression as Level, read::{MultiGzDecoder, ZlibDecoder}, write::{GzEncoder, ZlibEncoder}};
+use hyper::{header, body::HttpBody, Body, Request, Response};
+use serde::Serialize;
+// this crate
+use crate::err::HypErr;
+use super::{build_response_json, get_header};
+
+
+/// Bodies smaller than this many bytes are not worth compressing
+pub const DEFAULT_MIN_SIZE: usize = 1024;
+
+
+/// The content codings this crate can produce, in the order preferred when the client rates them equally
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+    Identity,
+}
+
+
+impl Encoding {
+    /// The value used for this encoding in the Accept-Encoding and Content-Encoding headers
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Identity => "identity",
+        }
+    }
+
+    /// Parse a Content-Encoding value, returning None for codings this crate does not support
+    pub fn parse(coding: &str) -> Option<Encoding> {
+        match coding.trim().to_ascii_lowercase().as_str() {
+            "br" => Some(Encoding::Brotli),
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            "identity" | "" => Some(Encoding::Identity),
+            _ => None,
+        }
+    }
+}
+
+
+/// Pick the best encoding the client accepts, given the value of its Accept-Encoding header.
+/// Codings are ranked by their q-value, and ties go to brotli, then gzip, then deflate.
+/// Returns Identity if the client accepts none of them.
+/// # Examples:
+/// ```ignore
+/// assert_eq!(negotiate_encoding("gzip;q=0.8, br;q=0.9"), Encoding::Brotli);
+/// assert_eq!(negotiate_encoding("*;q=0.5, br;q=0"), Encoding::Gzip);
+/// ```
+pub fn negotiate_encoding(accept_encoding: &str) -> Encoding {
+    let mut ranked = Vec::<(String, f32)>::new();
+    for item in accept_encoding.to_ascii_lowercase().split(',') {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or("").trim().to_string();
+        if coding.is_empty() {
+            continue
+        }
+        let q = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        ranked.push((coding, q));
+    }
+    let quality = |names: &[&str]| -> f32 {
+        ranked.iter().find(|(coding, _)| names.contains(&coding.as_str()))
+            .or_else(|| ranked.iter().find(|(coding, _)| coding == "*"))
+            .map_or(0.0, |(_, q)| *q)
+    };
+    let candidates = [
+        (Encoding::Brotli, quality(&["br"])),
+        (Encoding::Gzip, quality(&["gzip", "x-gzip"])),
+        (Encoding::Deflate, quality(&["deflate"])),
+    ];
+    let mut best = (Encoding::Identity, 0.0);
+    for (encoding, q) in candidates {
+        if q > best.1 {
+            best = (encoding, q);
+        }
+    }
+    best.0
+}
+
+
+/// Compress bytes with the given encoding
+pub fn encode(data: &[u8], encoding: Encoding) -> Result<Vec<u8>, HypErr> {
+    let encoded = match encoding {
+        Encoding::Brotli => {
+            let mut writer = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+            writer.write_all(data)?;
+            writer.into_inner()
+        },
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Level::default());
+            encoder.write_all(data)?;
+            encoder.finish()?
+        },
+        // in http, "deflate" means the zlib format
+        Encoding::Deflate => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Level::default());
+            encoder.write_all(data)?;
+            encoder.finish()?
+        },
+        Encoding::Identity => data.to_vec(),
+    };
+    Ok(encoded)
+}
+
+
+/// Decompress bytes with the given encoding, returning HypErr::BodyTooLarge if the decompressed
+/// data would exceed max_size bytes. The limit guards against "zip bombs", where a small compressed body
+/// expands to gigabytes.
+pub fn decode(data: &[u8], encoding: Encoding, max_size: usize) -> Result<Vec<u8>, HypErr> {
+    let reader: Box<dyn Read + '_> = match encoding {
+        Encoding::Brotli => Box::new(brotli::Decompressor::new(data, 4096)),
+        Encoding::Gzip => Box::new(MultiGzDecoder::new(data)),
+        Encoding::Deflate => Box::new(ZlibDecoder::new(data)),
+        Encoding::Identity => Box::new(data),
+    };
+    let mut decoded = Vec::new();
+    // read one byte past the limit to tell whether it was exceeded
+    reader.take((max_size as u64).saturating_add(1)).read_to_end(&mut decoded)?;
+    if decoded.len() > max_size {
+        return Err(HypErr::BodyTooLarge(max_size))
+    }
+    Ok(decoded)
+}
+
+
+/// Decompress bytes according to the value of a Content-Encoding header,
+/// which may list several codings in the order they were applied, i.e. "gzip, br".
+pub fn decode_content(data: &[u8], content_encoding: &str, max_size: usize) -> Result<Vec<u8>, HypErr> {
+    let mut decoded = data.to_vec();
+    for coding in content_encoding.rsplit(',') {
+        let encoding = Encoding::parse(coding).ok_or_else(|| HypErr::UnsupportedEncoding(coding.trim().to_string()))?;
+        if encoding != Encoding::Identity {
+            decoded = decode(&decoded, encoding, max_size)?;
+        }
+    }
+    Ok(decoded)
+}
+
+
+// is the content type one that is already compressed, so compressing it again would be wasted effort?
+fn is_precompressed(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    if mime == "image/svg+xml" {
+        return false
+    }
+    mime.starts_with("image/") || mime.starts_with("video/") || mime.starts_with("audio/")
+        || mime.starts_with("font/woff")
+        || matches!(mime.as_str(), "application/zip" | "application/gzip" | "application/x-gzip"
+            | "application/x-brotli" | "application/zstd" | "application/octet-stream" | "application/pdf")
+}
+
+
+/// Compression holds the settings used to compress responses
+#[derive(Debug, Clone)]
+pub struct Compression {
+    /// bodies smaller than this many bytes are sent uncompressed
+    pub min_size: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression{min_size: DEFAULT_MIN_SIZE}
+    }
+}
+
+
+impl Compression {
+    /// Compress a response according to the client's Accept-Encoding header (pass None if it was absent).
+    /// Responses that are streamed, already have a Content-Encoding, have an already-compressed
+    /// content type, or are smaller than min_size are returned unchanged.
+    /// Any response that could have been compressed gets a "Vary: Accept-Encoding" header, so caches
+    /// keep the encoded and plain versions apart.
+    pub async fn compress(&self, accept_encoding: Option<&str>, resp: Response<Body>) -> Result<Response<Body>, HypErr> {
+        if resp.headers().contains_key(header::CONTENT_ENCODING) {
+            return Ok(resp)
+        }
+        let precompressed = resp.headers().get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(is_precompressed);
+        // streamed bodies, i.e. NDJSON or Server-Sent Events, have no exact size and must not be buffered
+        let size = resp.body().size_hint().exact();
+        let size = match size {
+            Some(size) if !precompressed => size,
+            _ => return Ok(resp),
+        };
+        let (mut parts, body) = resp.into_parts();
+        parts.headers.append(header::VARY, header::HeaderValue::from_static("accept-encoding"));
+        let encoding = negotiate_encoding(accept_encoding.unwrap_or(""));
+        if size < self.min_size as u64 || encoding == Encoding::Identity {
+            return Ok(Response::from_parts(parts, body))
+        }
+        let bytes = hyper::body::to_bytes(body).await?;
+        let encoded = encode(&bytes, encoding)?;
+        parts.headers.insert(header::CONTENT_ENCODING, header::HeaderValue::from_static(encoding.as_str()));
+        parts.headers.remove(header::CONTENT_LENGTH);
+        Ok(Response::from_parts(parts, Body::from(encoded)))
+    }
+}
+
+
+/// Compress a response according to the client's Accept-Encoding header using the default settings.
+/// Read the header before the request is consumed, then wrap your router's response:
+/// # Examples:
+/// ```ignore
+/// let accept_encoding = server::get_header(&req, "Accept-Encoding");
+/// let resp = request_router(req, ip_address).await?;
+/// compress::compress_response(accept_encoding.as_deref(), resp).await
+/// ```
+pub async fn compress_response(accept_encoding: Option<&str>, resp: Response<Body>) -> Result<Response<Body>, HypErr> {
+    Compression::default().compress(accept_encoding, resp).await
+}
+
+
+/// Build a response out of any serializeable struct like build_response_json,
+/// compressing it if the request's Accept-Encoding header allows.
+pub async fn build_response_json_compressed<T: Serialize>(req: &Request<Body>, resp_payload: &T) -> Result<Response<Body>, HypErr> {
+    let accept_encoding = get_header(req, "Accept-Encoding");
+    let response = build_response_json(resp_payload)?;
+    compress_response(accept_encoding.as_deref(), response).await
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_by_q_value() {
+        assert_eq!(negotiate_encoding("gzip;q=0.8, br;q=0.9"), Encoding::Brotli);
+        assert_eq!(negotiate_encoding("br;q=0.5, deflate"), Encoding::Deflate);
+        assert_eq!(negotiate_encoding("GZIP"), Encoding::Gzip);
+        assert_eq!(negotiate_encoding("x-gzip;q=0.3"), Encoding::Gzip);
+    }
+
+    #[test]
+    fn negotiate_ties_prefer_brotli() {
+        assert_eq!(negotiate_encoding("deflate, gzip, br"), Encoding::Brotli);
+        assert_eq!(negotiate_encoding("deflate, gzip"), Encoding::Gzip);
+    }
+
+    #[test]
+    fn negotiate_wildcard_and_refusals() {
+        assert_eq!(negotiate_encoding("*;q=0.5, br;q=0"), Encoding::Gzip);
+        assert_eq!(negotiate_encoding("*"), Encoding::Brotli);
+        assert_eq!(negotiate_encoding("br;q=0, gzip;q=0, deflate;q=0"), Encoding::Identity);
+    }
+
+    #[test]
+    fn negotiate_nothing_supported() {
+        assert_eq!(negotiate_encoding(""), Encoding::Identity);
+        assert_eq!(negotiate_encoding("identity"), Encoding::Identity);
+        assert_eq!(negotiate_encoding("zstd, compress"), Encoding::Identity);
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let data = b"hello hello hello hello hello".repeat(10);
+        for encoding in [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate, Encoding::Identity] {
+            let encoded = encode(&data, encoding).unwrap();
+            assert_eq!(decode(&encoded, encoding, data.len()).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn decode_stops_at_the_limit() {
+        let encoded = encode(&vec![0u8; 100_000], Encoding::Gzip).unwrap();
+        assert!(matches!(decode(&encoded, Encoding::Gzip, 99_999), Err(HypErr::BodyTooLarge(99_999))));
+    }
+
+    #[test]
+    fn decode_content_applies_codings_in_reverse() {
+        let data = b"layered".to_vec();
+        let encoded = encode(&encode(&data, Encoding::Gzip).unwrap(), Encoding::Brotli).unwrap();
+        assert_eq!(decode_content(&encoded, "gzip, br", 1024).unwrap(), data);
+        assert!(matches!(decode_content(&data, "zstd", 1024), Err(HypErr::UnsupportedEncoding(_))));
+    }
+}
@@ -0,0 +1,92 @@
+//! The cookies module reads the Cookie header of requests and adds Set-Cookie headers to responses.
+//! Cookies are the typed Cookie of the cookie crate, built with SameSite, Secure, HttpOnly and expiry.
+//! Signed cookies can be read but not forged by the client, and private cookies can be neither read
+//! nor forged. Both need a Key, which should come from configuration so it survives restarts.
+
+
+// crates.io
+use cookie::CookieJar;
+use hyper::{header::{self, HeaderValue}, Body, Request, Response};
+// this crate
+use crate::err::HypErr;
+
+pub use cookie::{time, Cookie, CookieBuilder, Expiration, Key, SameSite};
+
+
+/// Parse every cookie sent with a request. Malformed pairs are skipped
+pub fn get_cookies(req: &Request<Body>) -> Vec<Cookie<'static>> {
+    req.headers().get_all(header::COOKIE).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| Cookie::split_parse(v.to_string()))
+        .filter_map(|cookie| cookie.ok())
+        .collect()
+}
+
+
+/// Return the cookie with this name, if the request sent one
+pub fn get_cookie(req: &Request<Body>, name: &str) -> Option<Cookie<'static>> {
+    get_cookies(req).into_iter().find(|cookie| cookie.name() == name)
+}
+
+
+/// Return the signed cookie with this name, if the request sent one and its signature is valid.
+/// The returned cookie has the original value, without the signature
+pub fn get_signed_cookie(req: &Request<Body>, name: &str, key: &Key) -> Option<Cookie<'static>> {
+    let cookie = get_cookie(req, name)?;
+    CookieJar::new().signed(key).verify(cookie)
+}
+
+
+/// Return the private cookie with this name, if the request sent one and it decrypts with key.
+/// The returned cookie has the decrypted value
+pub fn get_private_cookie(req: &Request<Body>, name: &str, key: &Key) -> Option<Cookie<'static>> {
+    let cookie = get_cookie(req, name)?;
+    CookieJar::new().private(key).decrypt(cookie)
+}
+
+
+/// Sign the value of a cookie with HMAC-SHA256, so the client cannot change it without it being noticed
+pub fn sign_cookie(cookie: Cookie<'static>, key: &Key) -> Cookie<'static> {
+    let name = cookie.name().to_string();
+    let mut jar = CookieJar::new();
+    jar.signed_mut(key).add(cookie);
+    jar.get(&name).cloned().expect("the cookie was just added")
+}
+
+
+/// Encrypt the value of a cookie with AES-256-GCM, so the client can neither read nor change it
+pub fn encrypt_cookie(cookie: Cookie<'static>, key: &Key) -> Cookie<'static> {
+    let name = cookie.name().to_string();
+    let mut jar = CookieJar::new();
+    jar.private_mut(key).add(cookie);
+    jar.get(&name).cloned().expect("the cookie was just added")
+}
+
+
+/// Add a Set-Cookie header to a response, keeping any added before
+/// # Examples:
+/// ```ignore
+/// let cookie = Cookie::build(("theme", "dark"))
+///     .path("/")
+///     .secure(true)
+///     .http_only(true)
+///     .same_site(SameSite::Lax)
+///     .max_age(cookies::time::Duration::days(30))
+///     .build();
+/// let mut response = build_response_json(&prefs)?;
+/// cookies::add_cookie(&mut response, &cookie)?;
+/// ```
+pub fn add_cookie(resp: &mut Response<Body>, cookie: &Cookie) -> Result<(), HypErr> {
+    let value = HeaderValue::from_str(&cookie.to_string()).map_err(hyper::http::Error::from)?;
+    resp.headers_mut().append(header::SET_COOKIE, value);
+    Ok(())
+}
+
+
+/// Add a Set-Cookie header telling the client to delete a cookie.
+/// The path (and domain, if any) must match the ones the cookie was set with
+pub fn remove_cookie(resp: &mut Response<Body>, name: &str, path: &str) -> Result<(), HypErr> {
+    let mut cookie = Cookie::build((name.to_string(), "")).path(path.to_string()).build();
+    cookie.make_removal();
+    add_cookie(resp, &cookie)
+}
@@ -0,0 +1,163 @@
+//! The etag module supports conditional requests for JSON resources.
+//! GET handlers can answer 304 Not Modified when the client's copy is current,
+//! and PUT/PATCH handlers can refuse with 412 Precondition Failed when the client edited a stale copy.
+
+
+// crates.io
+use hyper::{header, Body, Request, Response, StatusCode};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+// this crate
+use crate::{err::HypErr, util::to_hex};
+use super::{get_header, APPLICATION_JSON};
+
+
+/// Returns true if an If-None-Match or If-Match header value (a list of ETags, or "*") includes the etag.
+/// Weak comparison is used, so W/"abc" matches "abc".
+pub fn etag_list_matches(header_value: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    header_value.split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+
+// strong comparison, where weak ETags never match
+fn etag_list_matches_strong(header_value: &str, etag: &str) -> bool {
+    if etag.starts_with("W/") {
+        return false
+    }
+    header_value.split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+
+/// Compute a strong ETag (quoted, as it appears in the header) from the hash of some bytes
+pub fn etag_for_bytes(bytes: &[u8]) -> String {
+    let digest = Sha256::digest(bytes);
+    // half of the sha256 digest is plenty to tell versions of a resource apart
+    let hex = to_hex(&digest[..16]);
+    format!("\"{}\"", hex)
+}
+
+
+// rebuild a JSON value with the keys of every object in sorted order.
+// serde_json's Map is a BTreeMap, already sorted, unless some crate in the build enables serde_json's
+// preserve_order feature, which features unify across the whole dependency graph. It then becomes an
+// IndexMap in insertion order, and a HashMap would give a different ETag each time without this
+fn sort_keys(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(map) => {
+            let mut entries: Vec<_> = map.into_iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            serde_json::Value::Object(entries.into_iter().map(|(k, v)| (k, sort_keys(v))).collect())
+        },
+        serde_json::Value::Array(items) => serde_json::Value::Array(items.into_iter().map(sort_keys).collect()),
+        value => value,
+    }
+}
+
+
+/// Compute the strong ETag of any serializable struct, matching the one build_response_json_etag sends.
+/// Use it in PUT/PATCH handlers to get the ETag of the current version of a resource.
+/// The hash is taken over the JSON with its object keys sorted, so payloads holding a HashMap,
+/// which serializes in a different order every time, still get the same ETag for the same data.
+pub fn json_etag<T: Serialize>(payload: &T) -> Result<String, HypErr> {
+    let canonical = serde_json::to_vec(&sort_keys(serde_json::to_value(payload)?))?;
+    Ok(etag_for_bytes(&canonical))
+}
+
+
+/// Build a response out of any serializeable struct like build_response_json, adding a strong ETag
+/// computed with json_etag. If the request's If-None-Match header already lists that ETag,
+/// a 304 Not Modified response with no body is returned instead.
+pub fn build_response_json_etag<T: Serialize>(req: &Request<Body>, resp_payload: &T) -> Result<Response<Body>, HypErr> {
+    let etag = json_etag(resp_payload)?;
+    let json = serde_json::to_vec(resp_payload)?;
+    if let Some(if_none_match) = get_header(req, "If-None-Match") {
+        if etag_list_matches(&if_none_match, &etag) {
+            let response = Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header(header::ETAG, etag)
+                .body(Body::empty())?;
+            return Ok(response)
+        }
+    }
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, APPLICATION_JSON)
+        .header(header::ETAG, etag)
+        .body(Body::from(json))?;
+    Ok(response)
+}
+
+
+/// Returns true if a PUT/PATCH may go ahead: either the request has no If-Match header,
+/// or the header lists the current ETag of the resource (or "*").
+/// # Examples:
+/// ```ignore
+/// let current = db.load_user(user_id).await?;
+/// if !etag::if_match_satisfied(&req, &etag::json_etag(&current)?) {
+///     return etag::build_response_412()
+/// }
+/// let update: User = server::get_payload(req).await?;
+/// ```
+pub fn if_match_satisfied(req: &Request<Body>, current_etag: &str) -> bool {
+    match get_header(req, "If-Match") {
+        Some(if_match) => etag_list_matches_strong(&if_match, current_etag),
+        None => true,
+    }
+}
+
+
+/// Send a 412 Precondition Failed response, for when If-Match does not match the current ETag
+pub fn build_response_412() -> Result<Response<Body>, HypErr> {
+    let response = Response::builder()
+        .status(StatusCode::PRECONDITION_FAILED)
+        .body(Body::from("PRECONDITION FAILED: the resource has changed"))?;
+    Ok(response)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn list_matching() {
+        assert!(etag_list_matches(r#""a", "b""#, r#""b""#));
+        assert!(etag_list_matches(r#"W/"b""#, r#""b""#));
+        assert!(etag_list_matches("*", r#""b""#));
+        assert!(!etag_list_matches(r#""a""#, r#""b""#));
+        assert!(!etag_list_matches_strong(r#"W/"b""#, r#""b""#));
+        assert!(!etag_list_matches_strong(r#""b""#, r#"W/"b""#));
+        assert!(etag_list_matches_strong(r#""a", "b""#, r#""b""#));
+    }
+
+    #[test]
+    fn json_etag_ignores_map_order() {
+        let entries: Vec<(String, u32)> = (0..50).map(|i| (format!("key{}", i), i)).collect();
+        let forward: HashMap<String, u32> = entries.iter().cloned().collect();
+        let backward: HashMap<String, u32> = entries.iter().rev().cloned().collect();
+        assert_eq!(json_etag(&forward).unwrap(), json_etag(&backward).unwrap());
+        assert_eq!(json_etag(&vec![forward]).unwrap(), json_etag(&vec![backward]).unwrap());
+    }
+
+    #[test]
+    fn json_etag_changes_with_the_data() {
+        assert_ne!(json_etag(&[1, 2]).unwrap(), json_etag(&[2, 1]).unwrap());
+        assert_ne!(json_etag(&"a").unwrap(), json_etag(&"b").unwrap());
+    }
+
+    #[test]
+    fn not_modified_when_etag_matches() {
+        let etag = json_etag(&"payload").unwrap();
+        let req = Request::builder().header("If-None-Match", &etag).body(Body::empty()).unwrap();
+        assert_eq!(build_response_json_etag(&req, &"payload").unwrap().status(), StatusCode::NOT_MODIFIED);
+        let req = Request::builder().header("If-None-Match", "\"stale\"").body(Body::empty()).unwrap();
+        assert_eq!(build_response_json_etag(&req, &"payload").unwrap().status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,355 @@
+//! The files module serves static files from a directory, i.e. a small front-end hosted next to an API.
+//! It guesses MIME types, emits ETag and Last-Modified headers, and answers Range and conditional requests.
+
+
+// standard library
+use std::{io::SeekFrom, path::{Component, Path, PathBuf}, time::{Duration, SystemTime, UNIX_EPOCH}};
+// crates.io
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use percent_encoding::percent_decode_str;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+// this crate
+use crate::err::HypErr;
+use super::{get_header, etag::etag_list_matches};
+
+
+/// StaticFiles maps the urls under a prefix to the files under a directory.
+/// # Examples:
+/// ```ignore
+/// let files = StaticFiles::new("/app", "./frontend/dist");
+/// match (req.method(), req.uri().path()) {
+///     (&Method::GET, _) | (&Method::HEAD, _) if files.matches(&req) => files.serve(&req).await,
+///     ...
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct StaticFiles {
+    prefix: String,
+    root: PathBuf,
+    index: String,
+    cache_control: Option<String>,
+}
+
+
+/// The file a request path resolved to, along with its metadata
+struct ResolvedFile {
+    path: PathBuf,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+
+impl StaticFiles {
+    /// Serve the files under the root directory at the urls under prefix, i.e. "/static"
+    pub fn new<P: Into<PathBuf>>(prefix: &str, root: P) -> Self {
+        StaticFiles{
+            prefix: prefix.trim_end_matches('/').to_string(),
+            root: root.into(),
+            index: "index.html".to_string(),
+            cache_control: None,
+        }
+    }
+
+    /// Serve this file when a directory is requested (index.html by default)
+    pub fn with_index(mut self, index: &str) -> Self {
+        self.index = index.to_string();
+        self
+    }
+
+    /// Send this Cache-Control header with every file, i.e. "public, max-age=3600"
+    pub fn with_cache_control(mut self, cache_control: &str) -> Self {
+        self.cache_control = Some(cache_control.to_string());
+        self
+    }
+
+    /// Returns true if the request path falls under the prefix
+    pub fn matches(&self, req: &Request<Body>) -> bool {
+        self.relative_path(req.uri().path()).is_some()
+    }
+
+    // strip the prefix from a url path, returning None if the path is not under it
+    fn relative_path<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let rest = path.strip_prefix(&self.prefix)?;
+        if rest.is_empty() || rest.starts_with('/') {
+            Some(rest)
+        } else {
+            None // i.e. "/statics" does not fall under "/static"
+        }
+    }
+
+    // map a url path to a file under root, refusing anything that would escape it
+    async fn resolve(&self, url_path: &str) -> Option<ResolvedFile> {
+        let rest = self.relative_path(url_path)?;
+        let decoded = percent_decode_str(rest).decode_utf8().ok()?;
+        let mut path = self.root.clone();
+        for segment in decoded.split('/') {
+            if segment.is_empty() || segment == "." {
+                continue
+            }
+            // only plain file names are allowed, never "..", drive prefixes or nested separators
+            let mut components = Path::new(segment).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(name)), None) if !segment.contains('\\') => path.push(name),
+                _ => return None,
+            }
+        }
+        let mut meta = tokio::fs::metadata(&path).await.ok()?;
+        if meta.is_dir() {
+            path.push(&self.index);
+            meta = tokio::fs::metadata(&path).await.ok()?;
+        }
+        if !meta.is_file() {
+            return None
+        }
+        // a symlink inside root could still point outside of it
+        let root = tokio::fs::canonicalize(&self.root).await.ok()?;
+        if !tokio::fs::canonicalize(&path).await.ok()?.starts_with(root) {
+            return None
+        }
+        Some(ResolvedFile{path, len: meta.len(), modified: meta.modified().ok()})
+    }
+
+    /// Serve the file a GET or HEAD request points to.
+    /// Returns 404 if there is no such file, 405 for other methods, 304 if the client's copy is
+    /// still fresh, 206 for a satisfiable Range request and 416 for an unsatisfiable one.
+    pub async fn serve(&self, req: &Request<Body>) -> Result<Response<Body>, HypErr> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            let response = Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .header(header::ALLOW, "GET, HEAD")
+                .body(Body::empty())?;
+            return Ok(response)
+        }
+        let file = match self.resolve(req.uri().path()).await {
+            Some(file) => file,
+            None => {
+                let response = Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::from("Not Found"))?;
+                return Ok(response)
+            }
+        };
+        let etag = file_etag(&file);
+        // whole seconds, since that is all an http date can express
+        let modified = file.modified.map(truncate_to_secs);
+
+        let mut builder = Response::builder()
+            .header(header::ETAG, &etag)
+            .header(header::ACCEPT_RANGES, "bytes");
+        if let Some(modified) = modified {
+            builder = builder.header(header::LAST_MODIFIED, httpdate::fmt_http_date(modified));
+        }
+        if let Some(cache_control) = &self.cache_control {
+            builder = builder.header(header::CACHE_CONTROL, cache_control);
+        }
+        if is_not_modified(req, &etag, modified) {
+            return Ok(builder.status(StatusCode::NOT_MODIFIED).body(Body::empty())?)
+        }
+
+        let mime = mime_guess::from_path(&file.path).first_or_octet_stream();
+        builder = builder.header(header::CONTENT_TYPE, mime.as_ref());
+        let range = match get_header(req, "Range") {
+            Some(range) if if_range_matches(req, &etag, modified) => parse_range(&range, file.len),
+            _ => RangeRequest::Full,
+        };
+        let (start, len) = match range {
+            RangeRequest::Full => {
+                builder = builder.status(StatusCode::OK);
+                (0, file.len)
+            },
+            RangeRequest::Partial(start, end) => {
+                builder = builder
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, file.len));
+                (start, end - start + 1)
+            },
+            RangeRequest::Unsatisfiable => {
+                let response = builder
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(header::CONTENT_RANGE, format!("bytes */{}", file.len))
+                    .body(Body::empty())?;
+                return Ok(response)
+            },
+        };
+        builder = builder.header(header::CONTENT_LENGTH, len);
+        if req.method() == Method::HEAD {
+            return Ok(builder.body(Body::empty())?)
+        }
+        let mut handle = tokio::fs::File::open(&file.path).await?;
+        if start > 0 {
+            handle.seek(SeekFrom::Start(start)).await?;
+        }
+        let body = Body::wrap_stream(ReaderStream::new(handle.take(len)));
+        Ok(builder.body(body)?)
+    }
+}
+
+
+// build an ETag from the size and modification time, so it changes whenever the file does
+fn file_etag(file: &ResolvedFile) -> String {
+    let nanos = file.modified
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos());
+    format!("\"{:x}-{:x}\"", file.len, nanos)
+}
+
+
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => UNIX_EPOCH + Duration::from_secs(since.as_secs()),
+        Err(_) => time,
+    }
+}
+
+
+// should the client be told its cached copy is still good?
+fn is_not_modified(req: &Request<Body>, etag: &str, modified: Option<SystemTime>) -> bool {
+    // If-None-Match takes precedence over If-Modified-Since when both are sent
+    if let Some(if_none_match) = get_header(req, "If-None-Match") {
+        return etag_list_matches(&if_none_match, etag)
+    }
+    let since = get_header(req, "If-Modified-Since").and_then(|v| httpdate::parse_http_date(&v).ok());
+    match (since, modified) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+
+// a Range header only applies if If-Range is absent or still matches the current file
+fn if_range_matches(req: &Request<Body>, etag: &str, modified: Option<SystemTime>) -> bool {
+    let if_range = match get_header(req, "If-Range") {
+        Some(val) => val,
+        None => return true,
+    };
+    if if_range.starts_with('"') {
+        // If-Range requires a strong comparison
+        return if_range == etag
+    }
+    match (httpdate::parse_http_date(&if_range), modified) {
+        (Ok(date), Some(modified)) => date == modified,
+        _ => false,
+    }
+}
+
+
+#[derive(Debug, PartialEq)]
+enum RangeRequest {
+    Full,
+    /// the first and last byte positions, inclusive
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+
+// parse a Range header for a single byte range. Multiple ranges fall back to sending the whole file
+fn parse_range(range: &str, len: u64) -> RangeRequest {
+    let spec = match range.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return RangeRequest::Full,
+    };
+    let (first, last) = match spec.split_once('-') {
+        Some(pair) => pair,
+        None => return RangeRequest::Full,
+    };
+    let (first, last) = (first.trim(), last.trim());
+    if first.is_empty() {
+        // "bytes=-500" is the last 500 bytes
+        return match last.parse::<u64>() {
+            Ok(0) => RangeRequest::Unsatisfiable,
+            Ok(_) if len == 0 => RangeRequest::Unsatisfiable,
+            Ok(suffix) => RangeRequest::Partial(len.saturating_sub(suffix), len - 1),
+            Err(_) => RangeRequest::Full,
+        }
+    }
+    let first = match first.parse::<u64>() {
+        Ok(first) => first,
+        Err(_) => return RangeRequest::Full,
+    };
+    if first >= len {
+        return RangeRequest::Unsatisfiable
+    }
+    if last.is_empty() {
+        return RangeRequest::Partial(first, len - 1)
+    }
+    match last.parse::<u64>() {
+        Ok(last) if last >= first => RangeRequest::Partial(first, last.min(len - 1)),
+        _ => RangeRequest::Full,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_single_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), RangeRequest::Partial(0, 99));
+        assert_eq!(parse_range("bytes=500-", 1000), RangeRequest::Partial(500, 999));
+        assert_eq!(parse_range("bytes=-200", 1000), RangeRequest::Partial(800, 999));
+        assert_eq!(parse_range("bytes=-2000", 1000), RangeRequest::Partial(0, 999));
+        assert_eq!(parse_range("bytes=900-5000", 1000), RangeRequest::Partial(900, 999));
+    }
+
+    #[test]
+    fn parse_unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=1000-", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-5", 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn unsupported_ranges_send_the_whole_file() {
+        assert_eq!(parse_range("bytes=0-1, 5-6", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("items=0-1", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=5-1", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=a-b", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=7", 1000), RangeRequest::Full);
+    }
+
+    // a root with index.html, a file in a subdirectory, and a secret next to the root
+    fn site() -> (tempfile::TempDir, StaticFiles) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("public");
+        std::fs::create_dir_all(root.join("css")).unwrap();
+        std::fs::write(root.join("index.html"), "<html>").unwrap();
+        std::fs::write(root.join("css").join("site.css"), "body {}").unwrap();
+        std::fs::write(dir.path().join("secret.txt"), "secret").unwrap();
+        let files = StaticFiles::new("/static", &root);
+        (dir, files)
+    }
+
+    #[tokio::test]
+    async fn resolves_files_and_indexes() {
+        let (_dir, files) = site();
+        assert!(files.resolve("/static/css/site.css").await.unwrap().path.ends_with("css/site.css"));
+        assert!(files.resolve("/static/").await.unwrap().path.ends_with("index.html"));
+        assert!(files.resolve("/static").await.unwrap().path.ends_with("index.html"));
+        assert!(files.resolve("/static/./css//site.css").await.is_some());
+        assert!(files.resolve("/static/missing.css").await.is_none());
+        assert!(files.resolve("/statics/css/site.css").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn refuses_traversal() {
+        let (_dir, files) = site();
+        assert!(files.resolve("/static/../secret.txt").await.is_none());
+        assert!(files.resolve("/static/css/../../secret.txt").await.is_none());
+        assert!(files.resolve("/static/%2e%2e/secret.txt").await.is_none());
+        assert!(files.resolve("/static/%2E%2E%2Fsecret.txt").await.is_none());
+        assert!(files.resolve("/static/..%5Csecret.txt").await.is_none());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn refuses_symlinks_out_of_root() {
+        let (dir, files) = site();
+        let root = dir.path().join("public");
+        std::os::unix::fs::symlink(dir.path().join("secret.txt"), root.join("link.txt")).unwrap();
+        std::os::unix::fs::symlink(root.join("css").join("site.css"), root.join("inside.css")).unwrap();
+        assert!(files.resolve("/static/link.txt").await.is_none());
+        assert!(files.resolve("/static/inside.css").await.is_some());
+    }
+}
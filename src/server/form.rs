@@ -0,0 +1,280 @@
+//! The form module deserializes application/x-www-form-urlencoded bodies for server::get_form.
+//! Each value remembers its key, so a field that does not parse is reported as a MalformedArg
+//! and a missing one as a MissingArg, just like get_query_param.
+
+
+// standard library
+use std::{collections::HashMap, fmt};
+// crates.io
+use serde::de::{self, value::MapDeserializer, Deserializer, IntoDeserializer, Visitor};
+// this crate
+use crate::err::{ArgError, HypErr, MalformedArg, MissingArg};
+
+
+// what went wrong while deserializing a form, turned into a HypErr once deserialization is over
+#[derive(Debug)]
+enum FormError {
+    Arg(ArgError),
+    Custom(String),
+}
+
+
+impl std::error::Error for FormError {}
+
+impl fmt::Display for FormError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FormError::Arg(err) => write!(f, "{}", err),
+            FormError::Custom(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+
+impl de::Error for FormError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        FormError::Custom(msg.to_string())
+    }
+
+    // report what was expected, which FormValue turns into the dtype of a MalformedArg
+    fn invalid_type(_unexpected: de::Unexpected, expected: &dyn de::Expected) -> Self {
+        FormError::Custom(expected.to_string())
+    }
+
+    fn invalid_value(_unexpected: de::Unexpected, expected: &dyn de::Expected) -> Self {
+        FormError::Custom(expected.to_string())
+    }
+
+    fn missing_field(field: &'static str) -> Self {
+        FormError::Arg(ArgError::Missing(MissingArg{missing_key: field.to_string()}))
+    }
+
+    fn unknown_field(field: &str, _expected: &'static [&'static str]) -> Self {
+        FormError::Arg(ArgError::Malformed(MalformedArg::new(field, "", "a known field")))
+    }
+}
+
+
+impl From<FormError> for HypErr {
+    fn from(err: FormError) -> Self {
+        match err {
+            FormError::Arg(err) => HypErr::Arg(err),
+            // errors about one value were given its key by FormValue, so this is about the form as a whole,
+            // i.e. a custom validation in the Deserialize impl of the struct
+            FormError::Custom(msg) => HypErr::Arg(ArgError::Malformed(MalformedArg::new("", "", &msg))),
+        }
+    }
+}
+
+
+// one value of a form, along with its key for error messages
+struct FormValue {
+    key: String,
+    value: String,
+}
+
+
+impl FormValue {
+    fn malformed(&self, dtype: &str) -> FormError {
+        FormError::Arg(ArgError::Malformed(MalformedArg::new(&self.key, &self.value, dtype)))
+    }
+
+    fn parse<T: std::str::FromStr>(&self) -> Result<T, FormError> {
+        self.value.parse().map_err(|_| self.malformed(std::any::type_name::<T>()))
+    }
+
+    // errors raised by the visitor, i.e. a validation in a Deserialize impl, are about this key
+    fn keyed<T>(&self, result: Result<T, FormError>) -> Result<T, FormError> {
+        result.map_err(|err| match err {
+            FormError::Custom(msg) => self.malformed(&msg),
+            err => err,
+        })
+    }
+}
+
+
+impl<'de> IntoDeserializer<'de, FormError> for FormValue {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, FormError> {
+                self.keyed(visitor.$visit(self.parse()?))
+            }
+        )*
+    };
+}
+
+
+impl<'de> Deserializer<'de> for FormValue {
+    type Error = FormError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, FormError> {
+        self.keyed(visitor.visit_str(&self.value))
+    }
+
+    // checkboxes send "on" and other forms often send 1 or 0
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, FormError> {
+        let value = match self.value.to_ascii_lowercase().as_str() {
+            "true" | "on" | "1" => true,
+            "false" | "off" | "0" => false,
+            _ => return Err(self.malformed("bool")),
+        };
+        self.keyed(visitor.visit_bool(value))
+    }
+
+    deserialize_parsed! {
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+
+    // an empty value is None, as browsers send empty inputs as "key="
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, FormError> {
+        if self.value.is_empty() {
+            return self.keyed(visitor.visit_none())
+        }
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, FormError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    // unit variants only, i.e. the value of a <select>
+    fn deserialize_enum<V: Visitor<'de>>(self, name: &'static str, _variants: &'static [&'static str], visitor: V) -> Result<V::Value, FormError> {
+        let value: de::value::StrDeserializer<FormError> = self.value.as_str().into_deserializer();
+        visitor.visit_enum(value).map_err(|_| self.malformed(name))
+    }
+
+    serde::forward_to_deserialize_any! {
+        str string bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+
+/// Deserialize a form body into T. Repeated keys keep the last value
+pub(super) fn from_form_bytes<T: de::DeserializeOwned>(body: &[u8]) -> Result<T, HypErr> {
+    // the pairs keep the order keys first appeared in, and the index finds a repeated key
+    let mut pairs: Vec<(String, FormValue)> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    for (key, value) in url::form_urlencoded::parse(body) {
+        let value = FormValue{key: key.to_string(), value: value.into_owned()};
+        match index.get(key.as_ref()) {
+            Some(&i) => pairs[i].1 = value,
+            None => {
+                index.insert(key.to_string(), pairs.len());
+                pairs.push((key.into_owned(), value));
+            },
+        }
+    }
+    let deserializer: MapDeserializer<_, FormError> = MapDeserializer::new(pairs.into_iter());
+    Ok(T::deserialize(deserializer)?)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    enum Plan {
+        Free,
+        Pro,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Signup {
+        email: String,
+        age: u32,
+        newsletter: Option<bool>,
+        plan: Option<Plan>,
+        referrer: Option<String>,
+    }
+
+    fn malformed_key(result: Result<Signup, HypErr>) -> String {
+        match result {
+            Err(HypErr::Arg(ArgError::Malformed(err))) => err.key,
+            other => panic!("expected a malformed argument, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_fields() {
+        let signup: Signup = from_form_bytes(b"email=a%40b.com&age=30&newsletter=on&plan=pro&referrer=a+friend").unwrap();
+        assert_eq!(signup.email, "a@b.com");
+        assert_eq!(signup.age, 30);
+        assert_eq!(signup.newsletter, Some(true));
+        assert_eq!(signup.plan, Some(Plan::Pro));
+        assert_eq!(signup.referrer.as_deref(), Some("a friend"));
+    }
+
+    #[test]
+    fn bools_accept_checkbox_values() {
+        for (value, expected) in [("on", true), ("1", true), ("TRUE", true), ("off", false), ("0", false), ("false", false)] {
+            let signup: Signup = from_form_bytes(format!("email=e&age=1&newsletter={}", value).as_bytes()).unwrap();
+            assert_eq!(signup.newsletter, Some(expected), "{}", value);
+        }
+        assert_eq!(malformed_key(from_form_bytes(b"email=e&age=1&newsletter=yes please")), "newsletter");
+    }
+
+    #[test]
+    fn empty_values_are_none() {
+        let signup: Signup = from_form_bytes(b"email=&age=1&newsletter=&plan=&referrer=").unwrap();
+        assert_eq!(signup.email, "");
+        assert_eq!((signup.newsletter, signup.plan, signup.referrer), (None, None, None));
+        let signup: Signup = from_form_bytes(b"email=e&age=1").unwrap();
+        assert_eq!(signup.newsletter, None);
+    }
+
+    #[test]
+    fn repeated_keys_keep_the_last_value() {
+        let signup: Signup = from_form_bytes(b"age=1&email=first&age=2&email=last").unwrap();
+        assert_eq!((signup.email.as_str(), signup.age), ("last", 2));
+    }
+
+    #[test]
+    fn errors_name_the_field() {
+        assert_eq!(malformed_key(from_form_bytes(b"email=e&age=old")), "age");
+        assert_eq!(malformed_key(from_form_bytes(b"email=e&age=1&plan=gold")), "plan");
+        match from_form_bytes::<Signup>(b"age=1") {
+            Err(HypErr::Arg(ArgError::Missing(err))) => assert_eq!(err.missing_key, "email"),
+            other => panic!("expected a missing argument, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn custom_errors_are_malformed_args() {
+        #[derive(Debug, Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Strict {
+            #[serde(rename = "ids")]
+            _ids: Vec<u32>,
+        }
+        match from_form_bytes::<Strict>(b"ids=1") {
+            Err(HypErr::Arg(ArgError::Malformed(err))) => assert_eq!((err.key.as_str(), err.dtype.as_str()), ("ids", "a sequence")),
+            other => panic!("expected a malformed argument, got {:?}", other),
+        }
+        match from_form_bytes::<Strict>(b"extra=2&ids=1") {
+            Err(HypErr::Arg(ArgError::Malformed(err))) => assert_eq!(err.key, "extra"),
+            other => panic!("expected a malformed argument, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,327 @@
+//! The jwt module authenticates requests carrying an `Authorization: Bearer` JSON Web Token.
+//! Signatures are verified with HS256, RS256 or ES256 keys, configured directly or loaded from a
+//! JWKS file, and the exp, nbf, iss and aud claims are checked before the claims reach a handler.
+
+
+// standard library
+use std::{path::Path, time::Duration};
+// crates.io
+use hyper::{header, Body, Request, Response, StatusCode};
+use jsonwebtoken::{errors::ErrorKind, jwk::{AlgorithmParameters, Jwk, JwkSet, KeyAlgorithm}, Algorithm, DecodingKey, Validation};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+// this crate
+use crate::err::{HypErr, TokenError};
+use super::get_header;
+
+
+/// The registered claims checked by JwtValidator, along with the application's own claims in T
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims<T> {
+    pub sub: Option<String>,
+    pub iss: Option<String>,
+    pub exp: Option<u64>,
+    pub nbf: Option<u64>,
+    pub iat: Option<u64>,
+    #[serde(flatten)]
+    pub custom: T,
+}
+
+
+// a key along with the one algorithm it may be used with, so a token cannot pick another
+struct VerifyingKey {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+
+/// A JwtValidator holds the keys and expectations tokens are checked against.
+/// Build it once at startup and share it with the router.
+/// # Examples:
+/// ```ignore
+/// let validator = JwtValidator::new()
+///     .with_jwks_file("/etc/myservice/jwks.json")?
+///     .with_issuer("https://auth.example.com")
+///     .with_audience("orders-api");
+///
+/// #[derive(Deserialize)]
+/// struct Scopes { scope: String }
+///
+/// let claims: Claims<Scopes> = match validator.authenticate(&req) {
+///     Ok(claims) => claims,
+///     Err(err) => return jwt::build_response_401(&err),
+/// };
+/// ```
+pub struct JwtValidator {
+    keys: Vec<VerifyingKey>,
+    issuers: Vec<String>,
+    audiences: Vec<String>,
+    leeway: Duration,
+}
+
+
+impl JwtValidator {
+    /// Create a validator with no keys, allowing 60 seconds of clock skew
+    pub fn new() -> Self {
+        JwtValidator{keys: Vec::new(), issuers: Vec::new(), audiences: Vec::new(), leeway: Duration::from_secs(60)}
+    }
+
+    /// Accept HS256 tokens signed with this shared secret
+    pub fn with_hs256_secret(mut self, secret: &[u8]) -> Self {
+        self.keys.push(VerifyingKey{kid: None, algorithm: Algorithm::HS256, key: DecodingKey::from_secret(secret)});
+        self
+    }
+
+    /// Accept RS256 tokens signed by the private key matching this PEM encoded public key
+    pub fn with_rs256_pem(mut self, pem: &[u8]) -> Result<Self, TokenError> {
+        let key = DecodingKey::from_rsa_pem(pem).map_err(|e| TokenError::InvalidKey(e.to_string()))?;
+        self.keys.push(VerifyingKey{kid: None, algorithm: Algorithm::RS256, key});
+        Ok(self)
+    }
+
+    /// Accept ES256 tokens signed by the private key matching this PEM encoded public key
+    pub fn with_es256_pem(mut self, pem: &[u8]) -> Result<Self, TokenError> {
+        let key = DecodingKey::from_ec_pem(pem).map_err(|e| TokenError::InvalidKey(e.to_string()))?;
+        self.keys.push(VerifyingKey{kid: None, algorithm: Algorithm::ES256, key});
+        Ok(self)
+    }
+
+    /// Accept tokens signed by the HS256, RS256 or ES256 keys of a JWKS document.
+    /// Keys with a "kid" are only used for tokens naming that kid
+    pub fn with_jwks(mut self, jwks: &str) -> Result<Self, TokenError> {
+        let set: JwkSet = serde_json::from_str(jwks).map_err(|e| TokenError::InvalidKey(e.to_string()))?;
+        for jwk in &set.keys {
+            let algorithm = match jwk_algorithm(jwk) {
+                Some(algorithm) => algorithm,
+                None => continue, // i.e. an encryption key, or an algorithm this validator does not support
+            };
+            let key = DecodingKey::from_jwk(jwk).map_err(|e| TokenError::InvalidKey(e.to_string()))?;
+            self.keys.push(VerifyingKey{kid: jwk.common.key_id.clone(), algorithm, key});
+        }
+        Ok(self)
+    }
+
+    /// Read a JWKS document from a file, see with_jwks
+    pub fn with_jwks_file<P: AsRef<Path>>(self, path: P) -> Result<Self, HypErr> {
+        let jwks = std::fs::read_to_string(path)?;
+        Ok(self.with_jwks(&jwks)?)
+    }
+
+    /// Require the iss claim to be this issuer, or any other added the same way
+    pub fn with_issuer(mut self, issuer: &str) -> Self {
+        self.issuers.push(issuer.to_string());
+        self
+    }
+
+    /// Require the aud claim to include this audience, or any other added the same way
+    pub fn with_audience(mut self, audience: &str) -> Self {
+        self.audiences.push(audience.to_string());
+        self
+    }
+
+    /// Tolerate this much clock skew when checking exp and nbf
+    pub fn with_leeway(mut self, leeway: Duration) -> Self {
+        self.leeway = leeway;
+        self
+    }
+
+    fn validation(&self, algorithm: Algorithm) -> Validation {
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = self.leeway.as_secs();
+        validation.validate_nbf = true;
+        validation.validate_aud = !self.audiences.is_empty();
+        // jsonwebtoken only checks iss and aud when the token has them, so a required one must be present
+        let mut required = vec!["exp"];
+        if !self.audiences.is_empty() {
+            validation.set_audience(&self.audiences);
+            required.push("aud");
+        }
+        if !self.issuers.is_empty() {
+            validation.set_issuer(&self.issuers);
+            required.push("iss");
+        }
+        validation.set_required_spec_claims(&required);
+        validation
+    }
+
+    /// Verify a token and deserialize its claims into T, i.e. Claims<MyClaims>
+    pub fn validate<T: DeserializeOwned>(&self, token: &str) -> Result<T, TokenError> {
+        let token_header = jsonwebtoken::decode_header(token).map_err(|e| TokenError::Malformed(e.to_string()))?;
+        let candidates = self.keys.iter()
+            .filter(|key| key.algorithm == token_header.alg)
+            .filter(|key| match (&key.kid, &token_header.kid) {
+                (Some(kid), Some(token_kid)) => kid == token_kid,
+                (Some(_), None) => false,
+                (None, _) => true,
+            });
+        for candidate in candidates {
+            match jsonwebtoken::decode::<T>(token, &candidate.key, &self.validation(candidate.algorithm)) {
+                Ok(data) => return Ok(data.claims),
+                // another key with the same algorithm might still verify it
+                Err(err) if *err.kind() == ErrorKind::InvalidSignature => continue,
+                Err(err) => return Err(token_error(err.kind())),
+            }
+        }
+        Err(TokenError::InvalidSignature)
+    }
+
+    /// Verify the bearer token of a request and deserialize its claims into T
+    pub fn authenticate<T: DeserializeOwned>(&self, req: &Request<Body>) -> Result<T, TokenError> {
+        let token = get_bearer_token(req).ok_or(TokenError::Missing)?;
+        self.validate(&token)
+    }
+}
+
+
+impl Default for JwtValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+impl std::fmt::Debug for JwtValidator {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // the keys themselves are left out, since a secret should never end up in a log
+        f.debug_struct("JwtValidator")
+            .field("keys", &self.keys.len())
+            .field("issuers", &self.issuers)
+            .field("audiences", &self.audiences)
+            .field("leeway", &self.leeway)
+            .finish()
+    }
+}
+
+
+// the algorithm a JWKS key is meant for, from its "alg" or else its key type
+fn jwk_algorithm(jwk: &Jwk) -> Option<Algorithm> {
+    match (jwk.common.key_algorithm, &jwk.algorithm) {
+        (Some(KeyAlgorithm::HS256), _) => Some(Algorithm::HS256),
+        (Some(KeyAlgorithm::RS256), _) => Some(Algorithm::RS256),
+        (Some(KeyAlgorithm::ES256), _) => Some(Algorithm::ES256),
+        (Some(_), _) => None,
+        (None, AlgorithmParameters::OctetKey(_)) => Some(Algorithm::HS256),
+        (None, AlgorithmParameters::RSA(_)) => Some(Algorithm::RS256),
+        (None, AlgorithmParameters::EllipticCurve(_)) => Some(Algorithm::ES256),
+        (None, AlgorithmParameters::OctetKeyPair(_)) => None,
+    }
+}
+
+
+fn token_error(kind: &ErrorKind) -> TokenError {
+    match kind {
+        ErrorKind::InvalidSignature => TokenError::InvalidSignature,
+        ErrorKind::ExpiredSignature => TokenError::Expired,
+        ErrorKind::ImmatureSignature => TokenError::NotYetValid,
+        ErrorKind::InvalidIssuer => TokenError::InvalidIssuer,
+        ErrorKind::InvalidAudience => TokenError::InvalidAudience,
+        ErrorKind::MissingRequiredClaim(claim) if claim == "iss" => TokenError::InvalidIssuer,
+        ErrorKind::MissingRequiredClaim(claim) if claim == "aud" => TokenError::InvalidAudience,
+        other => TokenError::Malformed(format!("{:?}", other)),
+    }
+}
+
+
+/// Return the token of an `Authorization: Bearer <token>` header, if there is one
+pub fn get_bearer_token(req: &Request<Body>) -> Option<String> {
+    let authorization = get_header(req, "Authorization")?;
+    let (scheme, token) = authorization.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None
+    }
+    let token = token.trim();
+    (!token.is_empty()).then(|| token.to_string())
+}
+
+
+// a fixed description per error, so the client learns nothing about the parser or the keys
+fn error_description(err: &TokenError) -> &'static str {
+    match err {
+        TokenError::Missing => "No bearer token was provided",
+        TokenError::Malformed(_) => "The token is malformed",
+        TokenError::InvalidSignature => "The token signature is invalid",
+        TokenError::Expired => "The token has expired",
+        TokenError::NotYetValid => "The token is not valid yet",
+        TokenError::InvalidIssuer => "The token has the wrong issuer",
+        TokenError::InvalidAudience => "The token has the wrong audience",
+        TokenError::InvalidKey(_) => "The token could not be verified",
+    }
+}
+
+
+/// Build a 401 Unauthorized response for a rejected token, with a WWW-Authenticate challenge
+pub fn build_response_401(err: &TokenError) -> Result<Response<Body>, HypErr> {
+    let description = error_description(err);
+    let challenge = match err {
+        TokenError::Missing => "Bearer".to_string(),
+        _ => format!("Bearer error=\"invalid_token\", error_description=\"{}\"", description),
+    };
+    let response = Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(header::WWW_AUTHENTICATE, challenge)
+        .body(Body::from(format!("UNAUTHORIZED: {}", description)))?;
+    Ok(response)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use crate::util::unix_now;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Scope {
+        scope: String,
+    }
+
+    fn token(secret: &[u8], exp: u64) -> String {
+        let claims = Claims{sub: None, iss: Some("issuer".to_string()), exp: Some(exp), nbf: None, iat: None, custom: Scope{scope: "read".to_string()}};
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    #[test]
+    fn validates_hs256_tokens() {
+        let validator = JwtValidator::new().with_hs256_secret(b"secret").with_issuer("issuer");
+        let claims: Claims<Scope> = validator.validate(&token(b"secret", unix_now() + 60)).unwrap();
+        assert_eq!(claims.custom.scope, "read");
+        assert!(matches!(validator.validate::<Claims<Scope>>(&token(b"other", unix_now() + 60)), Err(TokenError::InvalidSignature)));
+        assert!(matches!(validator.validate::<Claims<Scope>>(&token(b"secret", 1)), Err(TokenError::Expired)));
+        assert!(matches!(validator.validate::<Claims<Scope>>("not.a.token"), Err(TokenError::Malformed(_))));
+    }
+
+    #[test]
+    fn requires_the_configured_issuer_and_audience() {
+        let exp = unix_now() + 60;
+        let sign = |claims: serde_json::Value| encode(&Header::default(), &claims, &EncodingKey::from_secret(b"secret")).unwrap();
+        let validator = JwtValidator::new().with_hs256_secret(b"secret").with_issuer("issuer").with_audience("api");
+        let validate = |claims| validator.validate::<serde_json::Value>(&sign(claims));
+        assert!(validate(serde_json::json!({"exp": exp, "iss": "issuer", "aud": "api"})).is_ok());
+        assert!(matches!(validate(serde_json::json!({"exp": exp, "aud": "api"})), Err(TokenError::InvalidIssuer)));
+        assert!(matches!(validate(serde_json::json!({"exp": exp, "iss": "issuer"})), Err(TokenError::InvalidAudience)));
+        assert!(matches!(validate(serde_json::json!({"exp": exp, "iss": "other", "aud": "api"})), Err(TokenError::InvalidIssuer)));
+        assert!(matches!(validate(serde_json::json!({"iss": "issuer", "aud": "api"})), Err(TokenError::Malformed(_))));
+        // without an issuer or audience configured, neither claim is needed
+        let open = JwtValidator::new().with_hs256_secret(b"secret");
+        assert!(open.validate::<serde_json::Value>(&sign(serde_json::json!({"exp": exp}))).is_ok());
+    }
+
+    #[test]
+    fn bearer_token_from_header() {
+        let req = Request::builder().header("Authorization", "bearer  abc ").body(Body::empty()).unwrap();
+        assert_eq!(get_bearer_token(&req).as_deref(), Some("abc"));
+        let req = Request::builder().header("Authorization", "Basic abc").body(Body::empty()).unwrap();
+        assert_eq!(get_bearer_token(&req), None);
+    }
+
+    #[tokio::test]
+    async fn response_401_hides_error_details() {
+        let response = build_response_401(&TokenError::Malformed("Base64(InvalidByte(3, 46))".to_string())).unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer error=\"invalid_token\", error_description=\"The token is malformed\"");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "UNAUTHORIZED: The token is malformed");
+        let response = build_response_401(&TokenError::Missing).unwrap();
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+    }
+}
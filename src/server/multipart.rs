@@ -0,0 +1,260 @@
+//! The multipart module reads multipart/form-data uploads without holding whole files in memory.
+//! Text fields are kept in memory up to a small limit, while file parts are streamed to temporary
+//! files that are deleted when dropped, unless the handler persists them somewhere.
+
+
+// standard library
+use std::path::{Path, PathBuf};
+// crates.io
+use hyper::{Body, Request};
+use multer::{Constraints, Multipart, SizeLimit};
+use serde::de::DeserializeOwned;
+use tempfile::TempPath;
+use tokio::io::AsyncWriteExt;
+// this crate
+use crate::err::HypErr;
+use super::get_header;
+
+
+/// The size limits applied while reading a multipart body
+#[derive(Debug, Clone)]
+pub struct MultipartLimits {
+    /// the most bytes a text field may hold
+    pub max_field_size: usize,
+    /// the most bytes a single file may hold
+    pub max_file_size: u64,
+    /// the most bytes the whole body may hold
+    pub max_total_size: u64,
+    /// the most parts (fields and files) the body may have
+    pub max_parts: usize,
+    /// where files are spooled, the system temporary directory if None
+    pub spool_dir: Option<PathBuf>,
+}
+
+
+impl Default for MultipartLimits {
+    /// 64 KiB text fields, 100 MiB files and bodies, and 100 parts
+    fn default() -> Self {
+        MultipartLimits{
+            max_field_size: 64 * 1024,
+            max_file_size: 100 * 1024 * 1024,
+            max_total_size: 100 * 1024 * 1024,
+            max_parts: 100,
+            spool_dir: None,
+        }
+    }
+}
+
+
+/// A file part, spooled to a temporary file
+#[derive(Debug)]
+pub struct UploadedFile {
+    /// the name of the form field
+    pub field_name: String,
+    /// the file name sent by the client. Never use it as a path without sanitizing it
+    pub file_name: Option<String>,
+    pub content_type: Option<String>,
+    pub size: u64,
+    path: TempPath,
+}
+
+
+impl UploadedFile {
+    /// The temporary file holding the upload, deleted when the UploadedFile is dropped
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Move the upload to a permanent location on the same filesystem, so it is not deleted
+    pub fn persist<P: AsRef<Path>>(self, path: P) -> Result<(), HypErr> {
+        self.path.persist(path).map_err(|err| HypErr::Io(err.error))?;
+        Ok(())
+    }
+}
+
+
+/// The text fields and files of a multipart/form-data body, in the order they were sent
+#[derive(Debug, Default)]
+pub struct MultipartForm {
+    pub fields: Vec<(String, String)>,
+    pub files: Vec<UploadedFile>,
+}
+
+
+impl MultipartForm {
+    /// Return the first text field with this name
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields.iter().find(|(field, _)| field == name).map(|(_, value)| value.as_str())
+    }
+
+    /// Deserialize the first text field with this name as JSON, i.e. metadata sent along with files
+    pub fn json<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>, HypErr> {
+        match self.field(name) {
+            Some(value) => Ok(Some(serde_json::from_str(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Return the first file with this field name
+    pub fn file(&self, name: &str) -> Option<&UploadedFile> {
+        self.files.iter().find(|file| file.field_name == name)
+    }
+
+    /// Take the first file with this field name out of the form, i.e. to persist it
+    pub fn take_file(&mut self, name: &str) -> Option<UploadedFile> {
+        let index = self.files.iter().position(|file| file.field_name == name)?;
+        Some(self.files.remove(index))
+    }
+}
+
+
+/// Read a multipart/form-data body, streaming file parts to temporary files.
+/// Exceeding a limit returns HypErr::BodyTooLarge, and a malformed body HypErr::Multipart,
+/// which map naturally to 413 and 400 responses. Files already spooled are deleted on error.
+/// # Examples:
+/// ```ignore
+/// let mut form = get_multipart(req, &MultipartLimits::default()).await?;
+/// let meta: Option<UploadMeta> = form.json("meta")?;
+/// if let Some(file) = form.take_file("document") {
+///     file.persist(storage_dir.join(uuid))?;
+/// }
+/// ```
+pub async fn get_multipart(req: Request<Body>, limits: &MultipartLimits) -> Result<MultipartForm, HypErr> {
+    let content_type = get_header(&req, "Content-Type").unwrap_or_default();
+    let boundary = multer::parse_boundary(&content_type)?;
+    let constraints = Constraints::new().size_limit(SizeLimit::new().whole_stream(limits.max_total_size));
+    let mut multipart = Multipart::with_constraints(req.into_body(), boundary, constraints);
+    let mut form = MultipartForm::default();
+    let spool_dir = limits.spool_dir.clone().unwrap_or_else(std::env::temp_dir);
+
+    while let Some(mut field) = multipart.next_field().await? {
+        if form.fields.len() + form.files.len() >= limits.max_parts {
+            return Err(HypErr::Multipart(format!("more than {} parts", limits.max_parts)))
+        }
+        let field_name = field.name().unwrap_or_default().to_string();
+        let file_name = field.file_name().map(|name| name.to_string());
+        if file_name.is_none() {
+            let mut value = Vec::new();
+            while let Some(chunk) = field.chunk().await? {
+                if value.len() + chunk.len() > limits.max_field_size {
+                    return Err(HypErr::BodyTooLarge(limits.max_field_size))
+                }
+                value.extend_from_slice(&chunk);
+            }
+            let value = String::from_utf8(value).map_err(|_| HypErr::Multipart(format!("field {} is not UTF-8", field_name)))?;
+            form.fields.push((field_name, value));
+            continue
+        }
+        let content_type = field.content_type().map(|mime| mime.to_string());
+        let path = tempfile::Builder::new().prefix("upload-").tempfile_in(&spool_dir)?.into_temp_path();
+        let mut file = tokio::fs::File::create(&path).await?;
+        let mut size = 0;
+        while let Some(chunk) = field.chunk().await? {
+            size += chunk.len() as u64;
+            if size > limits.max_file_size {
+                return Err(HypErr::BodyTooLarge(limits.max_file_size as usize))
+            }
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        form.files.push(UploadedFile{field_name, file_name, content_type, size, path});
+    }
+    Ok(form)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use crate::client::multipart::MultipartBuilder;
+
+    // a request carrying the form as built by the client
+    fn request(form: MultipartBuilder) -> Request<Body> {
+        Request::builder()
+            .header("Content-Type", form.content_type())
+            .body(Body::from(form.to_bytes().unwrap()))
+            .unwrap()
+    }
+
+    // limits that spool to dir, so tests can check what is left behind
+    fn limits(dir: &Path) -> MultipartLimits {
+        MultipartLimits{spool_dir: Some(dir.to_path_buf()), ..MultipartLimits::default()}
+    }
+
+    fn spooled(dir: &Path) -> usize {
+        std::fs::read_dir(dir).unwrap().count()
+    }
+
+    #[tokio::test]
+    async fn reads_what_the_builder_sends() {
+        let dir = tempfile::tempdir().unwrap();
+        let form = MultipartBuilder::new()
+            .text("folder", "invoices")
+            .json("meta", &serde_json::json!({"n": 1})).unwrap()
+            .bytes("doc", "a\"b.pdf", "application/pdf", Bytes::from("%PDF"));
+        let mut form = get_multipart(request(form), &limits(dir.path())).await.unwrap();
+        assert_eq!(form.field("folder"), Some("invoices"));
+        assert_eq!(form.json::<serde_json::Value>("meta").unwrap(), Some(serde_json::json!({"n": 1})));
+        let file = form.take_file("doc").unwrap();
+        assert_eq!((file.file_name.as_deref(), file.content_type.as_deref(), file.size), (Some("a%22b.pdf"), Some("application/pdf"), 4));
+        assert_eq!(std::fs::read(file.path()).unwrap(), b"%PDF");
+        assert!(form.file("doc").is_none());
+        drop(file);
+        assert_eq!(spooled(dir.path()), 0);
+    }
+
+    #[tokio::test]
+    async fn persists_uploads() {
+        let dir = tempfile::tempdir().unwrap();
+        let form = MultipartBuilder::new().bytes("doc", "x.txt", "text/plain", Bytes::from("kept"));
+        let mut form = get_multipart(request(form), &limits(dir.path())).await.unwrap();
+        let target = dir.path().join("stored.txt");
+        form.take_file("doc").unwrap().persist(&target).unwrap();
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "kept");
+        assert_eq!(spooled(dir.path()), 1);
+    }
+
+    #[tokio::test]
+    async fn enforces_each_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let field = MultipartLimits{max_field_size: 4, ..limits(dir.path())};
+        let err = get_multipart(request(MultipartBuilder::new().text("a", "12345")), &field).await.unwrap_err();
+        assert!(matches!(err, HypErr::BodyTooLarge(4)));
+        assert!(get_multipart(request(MultipartBuilder::new().text("a", "1234")), &field).await.is_ok());
+
+        let file = MultipartLimits{max_file_size: 4, ..limits(dir.path())};
+        let big = MultipartBuilder::new().bytes("doc", "x", "text/plain", Bytes::from("12345"));
+        assert!(matches!(get_multipart(request(big), &file).await.unwrap_err(), HypErr::BodyTooLarge(4)));
+
+        let total = MultipartLimits{max_total_size: 64, ..limits(dir.path())};
+        let big = MultipartBuilder::new().text("a", &"x".repeat(100));
+        let err = get_multipart(request(big), &total).await.unwrap_err();
+        assert!(matches!(err, HypErr::BodyTooLarge(_)), "{:?}", err);
+
+        let parts = MultipartLimits{max_parts: 2, ..limits(dir.path())};
+        let three = MultipartBuilder::new().text("a", "1").text("b", "2").text("c", "3");
+        assert!(matches!(get_multipart(request(three), &parts).await.unwrap_err(), HypErr::Multipart(_)));
+        let two = MultipartBuilder::new().text("a", "1").text("b", "2");
+        assert_eq!(get_multipart(request(two), &parts).await.unwrap().fields.len(), 2);
+        assert_eq!(spooled(dir.path()), 0);
+    }
+
+    #[tokio::test]
+    async fn deletes_spooled_files_on_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let limits = MultipartLimits{max_field_size: 4, ..limits(dir.path())};
+        let form = MultipartBuilder::new()
+            .bytes("first", "a.txt", "text/plain", Bytes::from("spooled"))
+            .bytes("second", "b.txt", "text/plain", Bytes::from("spooled too"))
+            .text("note", "too long");
+        assert!(matches!(get_multipart(request(form), &limits).await.unwrap_err(), HypErr::BodyTooLarge(4)));
+        assert_eq!(spooled(dir.path()), 0);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_bodies() {
+        let req = Request::builder().header("Content-Type", "text/plain").body(Body::from("x")).unwrap();
+        assert!(matches!(get_multipart(req, &MultipartLimits::default()).await.unwrap_err(), HypErr::Multipart(_)));
+    }
+}
@@ -0,0 +1,438 @@
+//! The negotiate module lets one handler serve several representations of the same data,
+//! choosing among them with the request's Accept header (RFC 9110, section 12.5.1).
+//! Media ranges are weighed by their q-values, the most specific range matching a representation
+//! decides its quality, and ties go to the order the representations were registered in.
+
+
+// crates.io
+use hyper::{header, Body, Request, Response, StatusCode};
+use serde::{ser, Serialize};
+// this crate
+use crate::err::HypErr;
+#[cfg(feature = "cbor")]
+use crate::format::Cbor;
+#[cfg(feature = "msgpack")]
+use crate::format::MsgPack;
+use crate::format::{Format, Json};
+use super::get_header;
+
+
+/// One media range of an Accept header, i.e. "text/*;q=0.5"
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaRange {
+    /// the type and subtype in lowercase, either of which may be *
+    pub media_type: String,
+    /// the quality between 0 and 1, where 0 means "not acceptable"
+    pub q: f32,
+}
+
+
+impl MediaRange {
+    // how closely this range matches a media type: 3 for an exact match, 2 for type/*, 1 for */*
+    fn specificity(&self, media_type: &str) -> Option<u8> {
+        let (range_type, range_subtype) = self.media_type.split_once('/')?;
+        let (type_, subtype) = media_type.split_once('/')?;
+        match (range_type, range_subtype) {
+            ("*", "*") => Some(1),
+            (range_type, "*") if range_type == type_ => Some(2),
+            (range_type, range_subtype) if range_type == type_ && range_subtype == subtype => Some(3),
+            _ => None,
+        }
+    }
+}
+
+
+/// Parse an Accept header into its media ranges. Parameters other than q are ignored,
+/// and ranges that are not of the form type/subtype or have an unreadable q are skipped
+pub fn parse_accept(accept: &str) -> Vec<MediaRange> {
+    accept.split(',').filter_map(|range| {
+        let mut params = range.split(';').map(str::trim);
+        let media_type = params.next()?.to_ascii_lowercase();
+        if !media_type.contains('/') {
+            return None
+        }
+        let mut q = 1.0;
+        for param in params {
+            if let Some((name, value)) = param.split_once('=') {
+                if name.trim().eq_ignore_ascii_case("q") {
+                    q = value.trim().parse::<f32>().ok().filter(|q| (0.0..=1.0).contains(q))?;
+                }
+            }
+        }
+        Some(MediaRange{media_type, q})
+    }).collect()
+}
+
+
+/// A way to send the same data, see Negotiator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Representation {
+    Json,
+    #[cfg(feature = "msgpack")]
+    MsgPack,
+    #[cfg(feature = "cbor")]
+    Cbor,
+    /// One row per element of a sequence, or a single row for anything else, with a header row
+    /// named after the fields. Fields must be flat, i.e. strings, numbers, bools and options
+    Csv,
+    /// Strings, numbers and bools as they are, anything else as pretty-printed JSON
+    Text,
+}
+
+
+impl Representation {
+    /// The Content-Type this representation is sent with
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Representation::Json => Json::CONTENT_TYPE,
+            #[cfg(feature = "msgpack")]
+            Representation::MsgPack => MsgPack::CONTENT_TYPE,
+            #[cfg(feature = "cbor")]
+            Representation::Cbor => Cbor::CONTENT_TYPE,
+            Representation::Csv => "text/csv; charset=utf-8",
+            Representation::Text => "text/plain; charset=utf-8",
+        }
+    }
+
+    // the media types an Accept header may name this representation by
+    fn media_types(&self) -> &'static [&'static str] {
+        match self {
+            Representation::Json => &["application/json"],
+            #[cfg(feature = "msgpack")]
+            Representation::MsgPack => &["application/msgpack", "application/x-msgpack", "application/vnd.msgpack"],
+            #[cfg(feature = "cbor")]
+            Representation::Cbor => &["application/cbor"],
+            Representation::Csv => &["text/csv"],
+            Representation::Text => &["text/plain"],
+        }
+    }
+
+    // the quality the ranges give this representation: the q of the most specific matching range
+    fn quality(&self, ranges: &[MediaRange]) -> f32 {
+        let mut best: Option<(u8, f32)> = None;
+        for media_type in self.media_types() {
+            for range in ranges {
+                if let Some(specificity) = range.specificity(media_type) {
+                    if best.is_none_or(|(s, q)| specificity > s || (specificity == s && range.q > q)) {
+                        best = Some((specificity, range.q));
+                    }
+                }
+            }
+        }
+        best.map(|(_, q)| q).unwrap_or(0.0)
+    }
+
+    /// Encode a serializable struct in this representation
+    pub fn to_vec<T: Serialize>(&self, payload: &T) -> Result<Vec<u8>, HypErr> {
+        match self {
+            Representation::Json => Json::to_vec(payload),
+            #[cfg(feature = "msgpack")]
+            Representation::MsgPack => MsgPack::to_vec(payload),
+            #[cfg(feature = "cbor")]
+            Representation::Cbor => Cbor::to_vec(payload),
+            Representation::Csv => to_csv(payload),
+            Representation::Text => match serde_json::to_value(payload)? {
+                serde_json::Value::String(text) => Ok(text.into_bytes()),
+                serde_json::Value::Null => Ok(Vec::new()),
+                value @ (serde_json::Value::Number(_) | serde_json::Value::Bool(_)) => Ok(value.to_string().into_bytes()),
+                value => Ok(serde_json::to_vec_pretty(&value)?),
+            },
+        }
+    }
+
+    /// Build a 200 response out of any serializable struct in this representation
+    pub fn build_response<T: Serialize>(&self, payload: &T) -> Result<Response<Body>, HypErr> {
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, self.content_type())
+            .header(header::VARY, "Accept")
+            .body(Body::from(self.to_vec(payload)?))?;
+        Ok(response)
+    }
+}
+
+
+/// Negotiator holds the representations a handler can send, in order of preference.
+/// # Examples:
+/// ```ignore
+/// let negotiator = Negotiator::new()
+///     .with_representation(Representation::Json)
+///     .with_representation(Representation::Csv);
+///
+/// // sends JSON or CSV, depending on the Accept header, or 406 Not Acceptable
+/// negotiator.respond(&req, &orders)
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Negotiator {
+    representations: Vec<Representation>,
+}
+
+
+impl Negotiator {
+    /// Create a negotiator that offers nothing yet
+    pub fn new() -> Self {
+        Negotiator::default()
+    }
+
+    /// Offer another representation, preferred less than those already offered
+    pub fn with_representation(mut self, representation: Representation) -> Self {
+        if !self.representations.contains(&representation) {
+            self.representations.push(representation);
+        }
+        self
+    }
+
+    /// Choose the representation to send for an Accept header, i.e. CommonHeaders::accept.
+    /// Without an Accept header the first representation is chosen, and None means nothing offered is acceptable
+    pub fn choose(&self, accept: Option<&str>) -> Option<Representation> {
+        let ranges = match accept.map(parse_accept) {
+            Some(ranges) if !ranges.is_empty() => ranges,
+            _ => return self.representations.first().copied(),
+        };
+        let mut best: Option<(Representation, f32)> = None;
+        for representation in &self.representations {
+            let q = representation.quality(&ranges);
+            if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                best = Some((*representation, q));
+            }
+        }
+        best.map(|(representation, _)| representation)
+    }
+
+    /// Choose the representation for the request's Accept header
+    pub fn negotiate(&self, req: &Request<Body>) -> Option<Representation> {
+        self.choose(get_header(req, "Accept").as_deref())
+    }
+
+    /// Build a 200 response out of any serializable struct in the representation the request accepts,
+    /// or a 406 Not Acceptable response listing what is available
+    pub fn respond<T: Serialize>(&self, req: &Request<Body>, payload: &T) -> Result<Response<Body>, HypErr> {
+        match self.negotiate(req) {
+            Some(representation) => representation.build_response(payload),
+            None => self.build_response_406(),
+        }
+    }
+
+    /// Build a 406 Not Acceptable response listing the content types on offer
+    pub fn build_response_406(&self) -> Result<Response<Body>, HypErr> {
+        let available = self.representations.iter().map(|r| r.content_type()).collect::<Vec<_>>().join(", ");
+        let response = Response::builder()
+            .status(StatusCode::NOT_ACCEPTABLE)
+            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .header(header::VARY, "Accept")
+            .body(Body::from(format!("NOT ACCEPTABLE, AVAILABLE: {}", available)))?;
+        Ok(response)
+    }
+}
+
+
+// what went wrong while writing CSV rows
+#[derive(Debug)]
+enum CsvError {
+    // the payload is not a sequence, so it is written as a single row instead
+    NotSeq,
+    Csv(csv::Error),
+    Custom(String),
+}
+
+
+impl std::error::Error for CsvError {}
+
+impl std::fmt::Display for CsvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CsvError::NotSeq => write!(f, "not a sequence"),
+            CsvError::Csv(err) => write!(f, "{}", err),
+            CsvError::Custom(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+
+impl ser::Error for CsvError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        CsvError::Custom(msg.to_string())
+    }
+}
+
+
+// a serializer that writes each element of a sequence as a CSV row, and refuses anything else
+struct Rows<'a> {
+    writer: &'a mut csv::Writer<Vec<u8>>,
+}
+
+
+impl ser::SerializeSeq for Rows<'_> {
+    type Ok = ();
+    type Error = CsvError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), CsvError> {
+        self.writer.serialize(value).map_err(CsvError::Csv)
+    }
+
+    fn end(self) -> Result<(), CsvError> {
+        Ok(())
+    }
+}
+
+
+macro_rules! not_seq {
+    ($($method:ident($($arg:ty),*) -> $ok:ty,)*) => {
+        $(
+            fn $method(self, $(_: $arg),*) -> Result<$ok, CsvError> {
+                Err(CsvError::NotSeq)
+            }
+        )*
+    };
+}
+
+
+impl<'a> ser::Serializer for Rows<'a> {
+    type Ok = ();
+    type Error = CsvError;
+    type SerializeSeq = Self;
+    type SerializeTuple = ser::Impossible<(), CsvError>;
+    type SerializeTupleStruct = ser::Impossible<(), CsvError>;
+    type SerializeTupleVariant = ser::Impossible<(), CsvError>;
+    type SerializeMap = ser::Impossible<(), CsvError>;
+    type SerializeStruct = ser::Impossible<(), CsvError>;
+    type SerializeStructVariant = ser::Impossible<(), CsvError>;
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self, CsvError> {
+        Ok(self)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), CsvError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<(), CsvError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _name: &'static str, _index: u32, _variant: &'static str, _value: &T) -> Result<(), CsvError> {
+        Err(CsvError::NotSeq)
+    }
+
+    not_seq! {
+        serialize_bool(bool) -> (),
+        serialize_i8(i8) -> (),
+        serialize_i16(i16) -> (),
+        serialize_i32(i32) -> (),
+        serialize_i64(i64) -> (),
+        serialize_u8(u8) -> (),
+        serialize_u16(u16) -> (),
+        serialize_u32(u32) -> (),
+        serialize_u64(u64) -> (),
+        serialize_f32(f32) -> (),
+        serialize_f64(f64) -> (),
+        serialize_char(char) -> (),
+        serialize_str(&str) -> (),
+        serialize_bytes(&[u8]) -> (),
+        serialize_none() -> (),
+        serialize_unit() -> (),
+        serialize_unit_struct(&'static str) -> (),
+        serialize_unit_variant(&'static str, u32, &'static str) -> (),
+        serialize_tuple(usize) -> Self::SerializeTuple,
+        serialize_tuple_struct(&'static str, usize) -> Self::SerializeTupleStruct,
+        serialize_tuple_variant(&'static str, u32, &'static str, usize) -> Self::SerializeTupleVariant,
+        serialize_map(Option<usize>) -> Self::SerializeMap,
+        serialize_struct(&'static str, usize) -> Self::SerializeStruct,
+        serialize_struct_variant(&'static str, u32, &'static str, usize) -> Self::SerializeStructVariant,
+    }
+}
+
+
+// write a sequence as one CSV row per element, or anything else as a single row
+fn to_csv<T: Serialize>(payload: &T) -> Result<Vec<u8>, HypErr> {
+    let csv_error = |err: csv::Error| HypErr::Format(err.to_string());
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    match payload.serialize(Rows{writer: &mut writer}) {
+        Ok(()) => {},
+        Err(CsvError::NotSeq) => writer.serialize(payload).map_err(csv_error)?,
+        Err(err) => return Err(HypErr::Format(err.to_string())),
+    }
+    writer.into_inner().map_err(|err| HypErr::Format(err.to_string()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn negotiator() -> Negotiator {
+        Negotiator::new()
+            .with_representation(Representation::Json)
+            .with_representation(Representation::Csv)
+            .with_representation(Representation::Text)
+    }
+
+    #[test]
+    fn parses_media_ranges() {
+        let ranges = parse_accept("Text/HTML, application/json;charset=utf-8;q=0.8 , */*;q=0, nonsense, image/png;q=2, text/csv;q=x");
+        assert_eq!(ranges, vec![
+            MediaRange{media_type: "text/html".to_string(), q: 1.0},
+            MediaRange{media_type: "application/json".to_string(), q: 0.8},
+            MediaRange{media_type: "*/*".to_string(), q: 0.0},
+        ]);
+        assert!(parse_accept("").is_empty());
+    }
+
+    #[test]
+    fn most_specific_range_decides() {
+        let ranges = parse_accept("text/*;q=0.2, text/csv;q=0.9, */*;q=0.1");
+        assert_eq!(Representation::Csv.quality(&ranges), 0.9);
+        assert_eq!(Representation::Text.quality(&ranges), 0.2);
+        assert_eq!(Representation::Json.quality(&ranges), 0.1);
+        // an exact refusal wins over a wildcard
+        assert_eq!(Representation::Json.quality(&parse_accept("*/*, application/json;q=0")), 0.0);
+    }
+
+    #[test]
+    fn chooses_by_quality_then_order() {
+        let negotiator = negotiator();
+        assert_eq!(negotiator.choose(Some("text/csv, application/json;q=0.5")), Some(Representation::Csv));
+        assert_eq!(negotiator.choose(Some("text/*")), Some(Representation::Csv));
+        assert_eq!(negotiator.choose(Some("*/*")), Some(Representation::Json));
+        assert_eq!(negotiator.choose(None), Some(Representation::Json));
+        assert_eq!(negotiator.choose(Some("garbage")), Some(Representation::Json));
+        assert_eq!(negotiator.choose(Some("image/png")), None);
+        assert_eq!(negotiator.choose(Some("*/*;q=0")), None);
+        assert_eq!(Negotiator::new().choose(None), None);
+    }
+
+    #[derive(Serialize)]
+    struct Order {
+        id: u32,
+        item: String,
+        paid: Option<bool>,
+    }
+
+    #[test]
+    fn writes_csv_rows() {
+        let orders = vec![Order{id: 1, item: "tea".to_string(), paid: Some(true)}, Order{id: 2, item: "a, b".to_string(), paid: None}];
+        let csv = Representation::Csv.to_vec(&orders).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap(), "id,item,paid\n1,tea,true\n2,\"a, b\",\n");
+        let single = Representation::Csv.to_vec(&orders[0]).unwrap();
+        assert_eq!(String::from_utf8(single).unwrap(), "id,item,paid\n1,tea,true\n");
+    }
+
+    #[test]
+    fn writes_text() {
+        assert_eq!(Representation::Text.to_vec(&"plain").unwrap(), b"plain");
+        assert_eq!(Representation::Text.to_vec(&42).unwrap(), b"42");
+    }
+
+    #[tokio::test]
+    async fn responds_or_refuses() {
+        let req = Request::builder().header(header::ACCEPT, "text/csv").body(Body::empty()).unwrap();
+        let response = negotiator().respond(&req, &vec![Order{id: 1, item: "tea".to_string(), paid: None}]).unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/csv; charset=utf-8");
+        assert_eq!(response.headers()[header::VARY], "Accept");
+        let req = Request::builder().header(header::ACCEPT, "image/png").body(Body::empty()).unwrap();
+        let response = negotiator().respond(&req, &1).unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_ACCEPTABLE);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "NOT ACCEPTABLE, AVAILABLE: application/json, text/csv; charset=utf-8, text/plain; charset=utf-8");
+    }
+}
@@ -0,0 +1,407 @@
+//! The rate_limit module throttles clients that make too many requests.
+//! Clients are identified by ip address, api key or a custom extractor, and the counts live in a
+//! RateLimitStore: in memory by default, or any shared backend implementing the trait.
+
+
+// standard library
+use std::{collections::HashMap, future::Future, sync::{Arc, Mutex}, time::{Duration, Instant}};
+// crates.io
+use hyper::{header::{self, HeaderMap, HeaderValue}, Body, Request, Response, StatusCode};
+// this crate
+use crate::err::HypErr;
+use super::{get_header, nginx_get_ip};
+
+
+/// The algorithm used to count requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    /// Allows bursts of up to the limit, refilling steadily over the window
+    TokenBucket,
+    /// Allows the limit within any window-long period, estimated from the current and previous windows
+    SlidingWindow,
+}
+
+
+/// A RateLimit allows a number of requests per window of time
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub window: Duration,
+    pub algorithm: Algorithm,
+}
+
+
+impl RateLimit {
+    /// Allow this many requests per window, using a token bucket
+    pub fn new(requests: u32, window: Duration) -> Self {
+        RateLimit{requests: requests.max(1), window, algorithm: Algorithm::TokenBucket}
+    }
+
+    pub fn per_second(requests: u32) -> Self {
+        RateLimit::new(requests, Duration::from_secs(1))
+    }
+
+    pub fn per_minute(requests: u32) -> Self {
+        RateLimit::new(requests, Duration::from_secs(60))
+    }
+
+    pub fn with_algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+}
+
+
+/// The outcome of counting a request against its limit
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// how long until the client has its full allowance again
+    pub reset_after: Duration,
+    /// if the request was refused, how long until another would be allowed
+    pub retry_after: Option<Duration>,
+}
+
+
+// headers only carry whole seconds, so round up rather than tell the client to retry too soon
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+
+impl RateLimitDecision {
+    /// Add the RateLimit-Limit, RateLimit-Remaining and RateLimit-Reset headers to a response
+    pub fn add_headers(&self, headers: &mut HeaderMap) {
+        headers.insert("RateLimit-Limit", HeaderValue::from(self.limit));
+        headers.insert("RateLimit-Remaining", HeaderValue::from(self.remaining));
+        headers.insert("RateLimit-Reset", HeaderValue::from(ceil_secs(self.reset_after)));
+    }
+
+    /// Build a 429 Too Many Requests response with the Retry-After and RateLimit-* headers
+    pub fn build_response_429(&self) -> Result<Response<Body>, HypErr> {
+        let retry_after = ceil_secs(self.retry_after.unwrap_or(self.reset_after)).max(1);
+        let mut response = Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header(header::RETRY_AFTER, retry_after)
+            .body(Body::from("TOO MANY REQUESTS"))?;
+        self.add_headers(response.headers_mut());
+        Ok(response)
+    }
+}
+
+
+/// A RateLimitStore counts requests per key. MemoryStore counts within one process, so behind a
+/// load balancer each instance would grant the full limit; a store that every instance reaches,
+/// i.e. atomic counters with an expiry in Redis, enforces one limit across all of them.
+pub trait RateLimitStore: Send + Sync {
+    /// Count one request against key and decide whether it is allowed under limit
+    fn hit(&self, key: &str, limit: &RateLimit) -> impl Future<Output = Result<RateLimitDecision, HypErr>> + Send;
+}
+
+
+// the per-key state kept by MemoryStore
+#[derive(Debug)]
+enum Counter {
+    Bucket{tokens: f64, updated: Instant},
+    Window{started: Instant, current: u32, previous: u32},
+}
+
+
+/// MemoryStore keeps the counts in a HashMap, which is enough for a single server instance
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    counters: Mutex<Counters>,
+}
+
+
+#[derive(Debug, Default)]
+struct Counters {
+    keys: HashMap<String, (Counter, Instant)>,
+    // how many keys were still active after the last prune
+    kept: usize,
+}
+
+
+// once the map holds more keys than this, idle keys are forgotten
+const PRUNE_THRESHOLD: usize = 10_000;
+
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+
+    fn hit_now(&self, key: &str, limit: &RateLimit, now: Instant) -> RateLimitDecision {
+        let mut counters = self.counters.lock().expect("rate limit lock poisoned");
+        // the map must double in size since the last prune before the next one, so a map of mostly
+        // active keys is not scanned on every request and pruning costs O(1) per request on average
+        if counters.keys.len() > PRUNE_THRESHOLD.max(counters.kept * 2) {
+            // keys idle for two windows have a full allowance again, so they are safe to drop
+            counters.keys.retain(|_, (_, last_seen)| now.duration_since(*last_seen) < limit.window * 2);
+            counters.kept = counters.keys.len();
+        }
+        let (counter, last_seen) = counters.keys.entry(key.to_string()).or_insert_with(|| {
+            let counter = match limit.algorithm {
+                Algorithm::TokenBucket => Counter::Bucket{tokens: f64::from(limit.requests), updated: now},
+                Algorithm::SlidingWindow => Counter::Window{started: now, current: 0, previous: 0},
+            };
+            (counter, now)
+        });
+        *last_seen = now;
+        let capacity = f64::from(limit.requests);
+        let window = limit.window.as_secs_f64().max(f64::EPSILON);
+        match counter {
+            Counter::Bucket{tokens, updated} => {
+                let refill_rate = capacity / window; // tokens per second
+                *tokens = (*tokens + now.duration_since(*updated).as_secs_f64() * refill_rate).min(capacity);
+                *updated = now;
+                let allowed = *tokens >= 1.0;
+                if allowed {
+                    *tokens -= 1.0;
+                }
+                let retry_after = (!allowed).then(|| Duration::from_secs_f64((1.0 - *tokens) / refill_rate));
+                RateLimitDecision{
+                    allowed,
+                    limit: limit.requests,
+                    remaining: tokens.floor() as u32,
+                    reset_after: Duration::from_secs_f64((capacity - *tokens) / refill_rate),
+                    retry_after,
+                }
+            },
+            Counter::Window{started, current, previous} => {
+                let mut elapsed = now.duration_since(*started);
+                if elapsed >= limit.window {
+                    let windows_passed = (elapsed.as_secs_f64() / window).floor() as u32;
+                    *previous = if windows_passed == 1 { *current } else { 0 };
+                    *current = 0;
+                    *started += limit.window * windows_passed;
+                    elapsed = now.duration_since(*started);
+                }
+                // weight the previous window by how much of it still overlaps the sliding window
+                let overlap = 1.0 - elapsed.as_secs_f64() / window;
+                let estimate = f64::from(*previous) * overlap + f64::from(*current);
+                let allowed = estimate + 1.0 <= capacity;
+                if allowed {
+                    *current += 1;
+                }
+                let used = (estimate + f64::from(u8::from(allowed))).ceil() as u32;
+                let reset_after = limit.window - elapsed;
+                RateLimitDecision{
+                    allowed,
+                    limit: limit.requests,
+                    remaining: limit.requests.saturating_sub(used),
+                    reset_after,
+                    retry_after: (!allowed).then_some(reset_after),
+                }
+            },
+        }
+    }
+}
+
+
+impl RateLimitStore for MemoryStore {
+    fn hit(&self, key: &str, limit: &RateLimit) -> impl Future<Output = Result<RateLimitDecision, HypErr>> + Send {
+        let decision = self.hit_now(key, limit, Instant::now());
+        std::future::ready(Ok(decision))
+    }
+}
+
+
+/// A function of the request and socket ip address returning the key a request is counted against
+pub type KeyExtractor = Arc<dyn Fn(&Request<Body>, &str) -> String + Send + Sync>;
+
+
+/// KeyBy decides which requests share a limit
+#[derive(Clone)]
+pub enum KeyBy {
+    /// the socket ip address passed to RateLimiter::check
+    Ip,
+    /// the real ip address from X-Forwarded-For, see server::nginx_get_ip
+    NginxIp,
+    /// the X-Api-Key header, falling back to the socket ip address when it is missing
+    ApiKey,
+    /// any function of the request and socket ip address
+    Custom(KeyExtractor),
+}
+
+
+impl KeyBy {
+    fn key(&self, req: &Request<Body>, ip_address: &str) -> String {
+        match self {
+            KeyBy::Ip => format!("ip:{}", ip_address),
+            KeyBy::NginxIp => format!("ip:{}", nginx_get_ip(req)),
+            KeyBy::ApiKey => match get_header(req, "X-Api-Key") {
+                Some(key) => format!("key:{}", key),
+                None => format!("ip:{}", ip_address),
+            },
+            KeyBy::Custom(extract) => format!("custom:{}", extract(req, ip_address)),
+        }
+    }
+}
+
+
+impl std::fmt::Debug for KeyBy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            KeyBy::Ip => write!(f, "Ip"),
+            KeyBy::NginxIp => write!(f, "NginxIp"),
+            KeyBy::ApiKey => write!(f, "ApiKey"),
+            KeyBy::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+
+/// A RateLimiter applies one RateLimit to requests grouped by KeyBy.
+/// # Examples:
+/// ```ignore
+/// // one limiter per policy, created at startup so every connection counts against the same store
+/// let limiter = RateLimiter::new(RateLimit::per_minute(120), KeyBy::NginxIp);
+///
+/// async fn request_router(req: Request<Body>, ip_address: String, limiter: Arc<RateLimiter>) -> Result<Response<Body>, HypErr> {
+///     let decision = limiter.check(&req, &ip_address).await?;
+///     if !decision.allowed {
+///         return decision.build_response_429()
+///     }
+///     ...
+/// }
+/// ```
+#[derive(Debug)]
+pub struct RateLimiter<S: RateLimitStore = MemoryStore> {
+    limit: RateLimit,
+    key_by: KeyBy,
+    store: S,
+}
+
+
+impl RateLimiter<MemoryStore> {
+    /// Create a limiter that keeps its counts in memory
+    pub fn new(limit: RateLimit, key_by: KeyBy) -> Self {
+        RateLimiter{limit, key_by, store: MemoryStore::new()}
+    }
+}
+
+
+impl<S: RateLimitStore> RateLimiter<S> {
+    /// Create a limiter that keeps its counts in the provided store
+    pub fn with_store(limit: RateLimit, key_by: KeyBy, store: S) -> Self {
+        RateLimiter{limit, key_by, store}
+    }
+
+    /// Count the request against its key. ip_address is the socket address of the client
+    pub async fn check(&self, req: &Request<Body>, ip_address: &str) -> Result<RateLimitDecision, HypErr> {
+        let key = self.key_by.key(req, ip_address);
+        self.store.hit(&key, &self.limit).await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket_bursts_then_refills() {
+        let store = MemoryStore::new();
+        let limit = RateLimit::new(3, Duration::from_secs(3));
+        let start = Instant::now();
+        for remaining in [2, 1, 0] {
+            let decision = store.hit_now("a", &limit, start);
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+        }
+        let refused = store.hit_now("a", &limit, start);
+        assert!(!refused.allowed);
+        assert_eq!(refused.retry_after, Some(Duration::from_secs(1)));
+        assert_eq!(refused.reset_after, Duration::from_secs(3));
+        // one token comes back per second
+        let later = store.hit_now("a", &limit, start + Duration::from_secs(1));
+        assert!(later.allowed);
+        assert_eq!(later.remaining, 0);
+        // other keys have their own bucket
+        assert!(store.hit_now("b", &limit, start).allowed);
+    }
+
+    #[test]
+    fn sliding_window_weights_the_previous_window() {
+        let store = MemoryStore::new();
+        let limit = RateLimit::new(10, Duration::from_secs(10)).with_algorithm(Algorithm::SlidingWindow);
+        let start = Instant::now();
+        for _ in 0..10 {
+            assert!(store.hit_now("a", &limit, start).allowed);
+        }
+        let refused = store.hit_now("a", &limit, start + Duration::from_secs(4));
+        assert!(!refused.allowed);
+        assert_eq!(refused.retry_after, Some(Duration::from_secs(6)));
+        // halfway into the next window half of the previous ten still count
+        let next = start + Duration::from_secs(15);
+        for _ in 0..5 {
+            assert!(store.hit_now("a", &limit, next).allowed);
+        }
+        let refused = store.hit_now("a", &limit, next);
+        assert!(!refused.allowed);
+        assert_eq!(refused.remaining, 0);
+        assert_eq!(refused.reset_after, Duration::from_secs(5));
+        // after two idle windows nothing carries over
+        let decision = store.hit_now("a", &limit, start + Duration::from_secs(35));
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 9);
+    }
+
+    #[test]
+    fn prunes_idle_keys_past_the_threshold() {
+        let store = MemoryStore::new();
+        let limit = RateLimit::per_second(1);
+        let start = Instant::now();
+        for i in 0..=PRUNE_THRESHOLD {
+            store.hit_now(&i.to_string(), &limit, start);
+        }
+        store.hit_now("late", &limit, start + Duration::from_secs(2));
+        assert_eq!(store.counters.lock().unwrap().keys.len(), 1);
+    }
+
+    #[test]
+    fn waits_for_the_map_to_double_after_a_prune_that_kept_most_keys() {
+        let store = MemoryStore::new();
+        let limit = RateLimit::per_second(1);
+        let start = Instant::now();
+        // every key stays active, so the first prune past the threshold drops nothing
+        for i in 0..2 * PRUNE_THRESHOLD + 3 {
+            store.hit_now(&i.to_string(), &limit, start);
+        }
+        let counters = store.counters.lock().unwrap();
+        assert_eq!((counters.keys.len(), counters.kept), (2 * PRUNE_THRESHOLD + 3, PRUNE_THRESHOLD + 1));
+        drop(counters);
+        // the next prune only comes once the map doubled, and then drops the idle keys
+        store.hit_now("late", &limit, start + Duration::from_secs(2));
+        let counters = store.counters.lock().unwrap();
+        assert_eq!((counters.keys.len(), counters.kept), (1, 0));
+    }
+
+    #[test]
+    fn headers_round_up_to_whole_seconds() {
+        let decision = RateLimitDecision{
+            allowed: false,
+            limit: 5,
+            remaining: 0,
+            reset_after: Duration::from_millis(1500),
+            retry_after: Some(Duration::from_millis(200)),
+        };
+        let response = decision.build_response_429().unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["Retry-After"], "1");
+        assert_eq!(response.headers()["RateLimit-Reset"], "2");
+        assert_eq!(response.headers()["RateLimit-Remaining"], "0");
+    }
+
+    #[test]
+    fn keys_by_api_key_or_ip() {
+        let req = Request::builder().header("X-Api-Key", "abc").body(Body::empty()).unwrap();
+        assert_eq!(KeyBy::ApiKey.key(&req, "10.0.0.1"), "key:abc");
+        assert_eq!(KeyBy::Ip.key(&req, "10.0.0.1"), "ip:10.0.0.1");
+        let req = Request::new(Body::empty());
+        assert_eq!(KeyBy::ApiKey.key(&req, "10.0.0.1"), "ip:10.0.0.1");
+    }
+}
@@ -0,0 +1,364 @@
+//! The session module keeps per-visitor state on the server between requests.
+//! The client only holds a signed cookie with a random session id, and the data lives in a
+//! SessionStore: in memory by default, or any shared backend implementing the trait.
+
+
+// standard library
+use std::{collections::HashMap, future::Future, sync::Mutex, time::{Duration, Instant}};
+// crates.io
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hyper::{Body, Request, Response};
+use rand::RngCore;
+use serde::{Serialize, de::DeserializeOwned};
+// this crate
+use crate::err::HypErr;
+use super::cookies::{self, time, Cookie, Key, SameSite};
+
+
+/// The data of one session, as JSON values by key
+pub type SessionData = HashMap<String, serde_json::Value>;
+
+/// The cookie holding the session id, unless configured otherwise
+pub const DEFAULT_COOKIE_NAME: &str = "session";
+/// How long a session lasts after its last request by default
+pub const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+
+/// A SessionStore keeps session data by id. MemorySessionStore loses every session when the process
+/// restarts and is invisible to other instances behind a load balancer, so implement this trait over a
+/// database when visitors must stay logged in across deploys or land on any instance.
+pub trait SessionStore: Send + Sync {
+    /// Return the data of a session, or None if it does not exist or has expired
+    fn load(&self, id: &str) -> impl Future<Output = Result<Option<SessionData>, HypErr>> + Send;
+    /// Store the data of a session, to expire after ttl without another save
+    fn save(&self, id: &str, data: &SessionData, ttl: Duration) -> impl Future<Output = Result<(), HypErr>> + Send;
+    /// Delete a session
+    fn remove(&self, id: &str) -> impl Future<Output = Result<(), HypErr>> + Send;
+}
+
+
+/// MemorySessionStore keeps sessions in a HashMap, which is enough for a single server instance
+#[derive(Debug, Default)]
+pub struct MemorySessionStore {
+    sessions: Mutex<Sessions>,
+}
+
+
+#[derive(Debug, Default)]
+struct Sessions {
+    // the session data and when it expires, by session id
+    by_id: HashMap<String, (SessionData, Instant)>,
+    // how many sessions were still live after the last prune
+    kept: usize,
+}
+
+
+// once the map holds more sessions than this, expired ones are forgotten
+const PRUNE_THRESHOLD: usize = 10_000;
+
+
+impl MemorySessionStore {
+    pub fn new() -> Self {
+        MemorySessionStore::default()
+    }
+}
+
+
+impl SessionStore for MemorySessionStore {
+    fn load(&self, id: &str) -> impl Future<Output = Result<Option<SessionData>, HypErr>> + Send {
+        let sessions = self.sessions.lock().expect("session lock poisoned");
+        let data = sessions.by_id.get(id)
+            .filter(|(_, expires)| Instant::now() < *expires)
+            .map(|(data, _)| data.clone());
+        std::future::ready(Ok(data))
+    }
+
+    fn save(&self, id: &str, data: &SessionData, ttl: Duration) -> impl Future<Output = Result<(), HypErr>> + Send {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().expect("session lock poisoned");
+        // the map must double in size since the last prune before the next one, so a map of mostly
+        // live sessions is not scanned on every save
+        if sessions.by_id.len() > PRUNE_THRESHOLD.max(sessions.kept * 2) {
+            sessions.by_id.retain(|_, (_, expires)| now < *expires);
+            sessions.kept = sessions.by_id.len();
+        }
+        sessions.by_id.insert(id.to_string(), (data.clone(), now + ttl));
+        std::future::ready(Ok(()))
+    }
+
+    fn remove(&self, id: &str) -> impl Future<Output = Result<(), HypErr>> + Send {
+        self.sessions.lock().expect("session lock poisoned").by_id.remove(id);
+        std::future::ready(Ok(()))
+    }
+}
+
+
+/// The session of one request, loaded by SessionManager::load and written back by SessionManager::save
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    id: Option<String>,
+    data: SessionData,
+    changed: bool,
+    destroyed: bool,
+    // the id to delete from the store once a regenerated session is saved
+    previous_id: Option<String>,
+}
+
+
+impl Session {
+    /// The session id, or None for a new session that has not been saved yet
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    /// Returns true if the request did not belong to an existing session
+    pub fn is_new(&self) -> bool {
+        self.id.is_none()
+    }
+
+    /// Return the value stored under key, if there is one and it deserializes into T
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        self.data.get(key).and_then(|value| serde_json::from_value(value.clone()).ok())
+    }
+
+    pub fn insert<T: Serialize>(&mut self, key: &str, value: &T) -> Result<(), HypErr> {
+        self.data.insert(key.to_string(), serde_json::to_value(value)?);
+        self.changed = true;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        if self.data.remove(key).is_some() {
+            self.changed = true;
+        }
+    }
+
+    /// Give the session a new id when it is saved, keeping its data.
+    /// Call this when a user logs in, so an id planted before login (session fixation) is useless
+    pub fn regenerate(&mut self) {
+        if let Some(id) = self.id.take() {
+            self.previous_id = Some(id);
+        }
+        self.changed = true;
+    }
+
+    /// Delete the session from the store and the client when it is saved, i.e. on logout
+    pub fn destroy(&mut self) {
+        self.data.clear();
+        self.destroyed = true;
+    }
+}
+
+
+/// A SessionManager loads and saves the Session of each request.
+/// # Examples:
+/// ```ignore
+/// // created once at startup: a manager built per request would start with an empty MemorySessionStore
+/// let sessions = SessionManager::new(Key::derive_from(secret.as_bytes()));
+///
+/// let mut session = sessions.load(&req).await?;
+/// let visits = session.get::<u32>("visits").unwrap_or(0) + 1;
+/// session.insert("visits", &visits)?;
+/// let mut response = build_response_json(&visits)?;
+/// sessions.save(session, &mut response).await?;
+/// ```
+#[derive(Debug)]
+pub struct SessionManager<S: SessionStore = MemorySessionStore> {
+    store: S,
+    key: Key,
+    cookie_name: String,
+    ttl: Duration,
+    secure: bool,
+    same_site: SameSite,
+}
+
+
+impl SessionManager<MemorySessionStore> {
+    /// Keep sessions in memory, signing the session cookie with key
+    pub fn new(key: Key) -> Self {
+        SessionManager::with_store(key, MemorySessionStore::new())
+    }
+}
+
+
+impl<S: SessionStore> SessionManager<S> {
+    /// Keep sessions in the provided store, signing the session cookie with key
+    pub fn with_store(key: Key, store: S) -> Self {
+        SessionManager{
+            store,
+            key,
+            cookie_name: DEFAULT_COOKIE_NAME.to_string(),
+            ttl: DEFAULT_TTL,
+            secure: true,
+            same_site: SameSite::Lax,
+        }
+    }
+
+    pub fn with_cookie_name(mut self, cookie_name: &str) -> Self {
+        self.cookie_name = cookie_name.to_string();
+        self
+    }
+
+    /// End sessions this long after their last request
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Choose whether the cookie is only sent over https (on by default, turn it off for local development)
+    pub fn with_secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    pub fn with_same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = same_site;
+        self
+    }
+
+    /// Load the session of a request. A missing, forged or expired session cookie gives a new, empty session
+    pub async fn load(&self, req: &Request<Body>) -> Result<Session, HypErr> {
+        let id = match cookies::get_signed_cookie(req, &self.cookie_name, &self.key) {
+            Some(cookie) => cookie.value().to_string(),
+            None => return Ok(Session::default()),
+        };
+        match self.store.load(&id).await? {
+            Some(data) => Ok(Session{id: Some(id), data, ..Session::default()}),
+            None => Ok(Session::default()),
+        }
+    }
+
+    /// Save the session and add its Set-Cookie header to the response.
+    /// Existing sessions are saved on every request, so they expire ttl after the last one.
+    /// New sessions are only saved once something was inserted
+    pub async fn save(&self, session: Session, resp: &mut Response<Body>) -> Result<(), HypErr> {
+        if let Some(previous_id) = &session.previous_id {
+            self.store.remove(previous_id).await?;
+        }
+        if session.destroyed {
+            if let Some(id) = &session.id {
+                self.store.remove(id).await?;
+            }
+            return cookies::remove_cookie(resp, &self.cookie_name, "/")
+        }
+        if session.id.is_none() && !session.changed {
+            return Ok(())
+        }
+        let id = session.id.clone().unwrap_or_else(new_session_id);
+        self.store.save(&id, &session.data, self.ttl).await?;
+        let cookie = Cookie::build((self.cookie_name.clone(), id))
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(self.same_site)
+            .max_age(time::Duration::seconds(self.ttl.as_secs() as i64))
+            .build();
+        cookies::add_cookie(resp, &cookies::sign_cookie(cookie, &self.key))
+    }
+}
+
+
+// 256 random bits, so ids cannot be guessed
+fn new_session_id() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header;
+
+    fn manager() -> SessionManager {
+        SessionManager::new(Key::generate())
+    }
+
+    // send the session cookie set by resp back, as a browser would
+    fn next_request(resp: &Response<Body>) -> Request<Body> {
+        let set_cookie = resp.headers()[header::SET_COOKIE].to_str().unwrap();
+        let cookie = set_cookie.split(';').next().unwrap().to_string();
+        Request::builder().header(header::COOKIE, cookie).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn sessions_round_trip() {
+        let sessions = manager();
+        let mut session = sessions.load(&Request::new(Body::empty())).await.unwrap();
+        assert!(session.is_new());
+        session.insert("visits", &1).unwrap();
+        let mut resp = Response::new(Body::empty());
+        sessions.save(session, &mut resp).await.unwrap();
+        let session = sessions.load(&next_request(&resp)).await.unwrap();
+        assert!(!session.is_new());
+        assert_eq!(session.get::<u32>("visits"), Some(1));
+    }
+
+    #[tokio::test]
+    async fn untouched_new_sessions_are_not_saved() {
+        let sessions = manager();
+        let session = sessions.load(&Request::new(Body::empty())).await.unwrap();
+        let mut resp = Response::new(Body::empty());
+        sessions.save(session, &mut resp).await.unwrap();
+        assert!(!resp.headers().contains_key(header::SET_COOKIE));
+    }
+
+    #[tokio::test]
+    async fn regenerate_replaces_the_id() {
+        let sessions = manager();
+        let mut session = Session::default();
+        session.insert("user", &"alice").unwrap();
+        let mut resp = Response::new(Body::empty());
+        sessions.save(session, &mut resp).await.unwrap();
+        let old_request = next_request(&resp);
+        let mut session = sessions.load(&old_request).await.unwrap();
+        let old_id = session.id().unwrap().to_string();
+        session.regenerate();
+        let mut resp = Response::new(Body::empty());
+        sessions.save(session, &mut resp).await.unwrap();
+        let session = sessions.load(&next_request(&resp)).await.unwrap();
+        assert_ne!(session.id(), Some(old_id.as_str()));
+        assert_eq!(session.get::<String>("user").as_deref(), Some("alice"));
+        assert!(sessions.load(&old_request).await.unwrap().is_new());
+    }
+
+    #[tokio::test]
+    async fn forged_cookies_give_a_new_session() {
+        let sessions = manager();
+        let mut resp = Response::new(Body::empty());
+        let mut session = Session::default();
+        session.insert("user", &"alice").unwrap();
+        sessions.save(session, &mut resp).await.unwrap();
+        // the same cookie checked with another key
+        let other = SessionManager::new(Key::generate());
+        assert!(other.load(&next_request(&resp)).await.unwrap().is_new());
+    }
+
+    #[tokio::test]
+    async fn memory_store_expires_and_prunes() {
+        let store = MemorySessionStore::new();
+        let data = SessionData::new();
+        store.save("expired", &data, Duration::ZERO).await.unwrap();
+        assert_eq!(store.load("expired").await.unwrap(), None);
+        for i in 0..PRUNE_THRESHOLD {
+            store.save(&i.to_string(), &data, Duration::ZERO).await.unwrap();
+        }
+        assert_eq!(store.sessions.lock().unwrap().by_id.len(), PRUNE_THRESHOLD + 1);
+        store.save("live", &data, Duration::from_secs(60)).await.unwrap();
+        assert_eq!(store.sessions.lock().unwrap().by_id.len(), 1);
+        assert_eq!(store.load("live").await.unwrap(), Some(data));
+    }
+
+    #[tokio::test]
+    async fn memory_store_waits_for_the_map_to_double_after_a_prune_that_kept_most_sessions() {
+        let store = MemorySessionStore::new();
+        let data = SessionData::new();
+        for i in 0..2 * PRUNE_THRESHOLD + 3 {
+            store.save(&i.to_string(), &data, Duration::from_secs(60)).await.unwrap();
+        }
+        // the first prune found every session live, so the next one waits for the map to double
+        let sessions = store.sessions.lock().unwrap();
+        assert_eq!((sessions.by_id.len(), sessions.kept), (2 * PRUNE_THRESHOLD + 3, PRUNE_THRESHOLD + 1));
+    }
+}
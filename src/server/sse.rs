@@ -0,0 +1,146 @@
+//! The sse module builds Server-Sent Events (text/event-stream) responses,
+//! so browsers and other clients can receive push updates over a plain http connection.
+
+
+// standard library
+use std::{convert::Infallible, fmt, time::Duration};
+// crates.io
+use futures_util::{stream, Stream, StreamExt};
+use hyper::{header, Body, Response, StatusCode};
+use serde::Serialize;
+use tokio::time::{interval_at, Instant};
+// this crate
+use crate::err::HypErr;
+
+
+const TEXT_EVENT_STREAM: &str = "text/event-stream";
+// lines starting with a colon are comments, which clients ignore
+const KEEP_ALIVE_COMMENT: &str = ": keep-alive\n\n";
+
+
+/// An Event is a single message sent over a Server-Sent Events stream.
+#[derive(Debug, Clone, Default)]
+pub struct Event {
+    /// sets the last event ID, which the client sends back in Last-Event-ID when it reconnects
+    pub id: Option<String>,
+    /// the event type. Clients treat events without one as "message"
+    pub event: Option<String>,
+    /// the payload. Multiple lines are sent as multiple data fields
+    pub data: String,
+    /// tells the client how long to wait before reconnecting
+    pub retry: Option<Duration>,
+}
+
+
+impl Event {
+    /// Build an event carrying the provided string as its data
+    pub fn new(data: &str) -> Self {
+        Event{data: data.to_string(), ..Default::default()}
+    }
+
+    /// Build an event carrying any serializable struct as JSON data
+    pub fn json<T: Serialize>(payload: &T) -> Result<Self, HypErr> {
+        let data = serde_json::to_string(payload)?;
+        Ok(Event{data, ..Default::default()})
+    }
+
+    pub fn with_id(mut self, id: &str) -> Self {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    pub fn with_event(mut self, event: &str) -> Self {
+        self.event = Some(event.to_string());
+        self
+    }
+
+    pub fn with_retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+}
+
+
+// id and event fields cannot span lines, so drop any line breaks
+fn single_line(val: &str) -> String {
+    val.chars().filter(|c| *c != '\n' && *c != '\r').collect()
+}
+
+
+impl fmt::Display for Event {
+    /// Format the event in the text/event-stream wire format, including the blank line that terminates it
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(id) = &self.id {
+            writeln!(f, "id: {}", single_line(id))?;
+        }
+        if let Some(event) = &self.event {
+            writeln!(f, "event: {}", single_line(event))?;
+        }
+        if let Some(retry) = &self.retry {
+            writeln!(f, "retry: {}", retry.as_millis())?;
+        }
+        // \r\n, \r and \n all end a line in the wire format, so each starts a new data field
+        for line in self.data.replace("\r\n", "\n").split(['\r', '\n']) {
+            writeln!(f, "data: {}", line)?;
+        }
+        writeln!(f)
+    }
+}
+
+
+/// Build a text/event-stream response out of a stream of events.
+/// If keep_alive is provided, a comment is sent at that interval so proxies do not close an idle connection.
+/// The response ends when the stream of events ends.
+/// # Examples:
+/// ```ignore
+/// let events = futures_util::stream::iter(prices).map(|p| Event::json(&p).unwrap().with_event("price"));
+/// sse::build_response_sse(events, Some(Duration::from_secs(15)))
+/// ```
+pub fn build_response_sse<S>(events: S, keep_alive: Option<Duration>) -> Result<Response<Body>, HypErr>
+where
+    S: Stream<Item = Event> + Send + 'static,
+{
+    let ticker = keep_alive.map(|period| interval_at(Instant::now() + period, period));
+    let chunks = stream::unfold((Box::pin(events), ticker), |(mut events, mut ticker)| async move {
+        let chunk = match ticker.as_mut() {
+            Some(ticker) => tokio::select! {
+                event = events.next() => event.map(|e| e.to_string()),
+                _ = ticker.tick() => Some(KEEP_ALIVE_COMMENT.to_string()),
+            },
+            None => events.next().await.map(|e| e.to_string()),
+        }?;
+        Some((Ok::<_, Infallible>(chunk), (events, ticker)))
+    });
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, TEXT_EVENT_STREAM)
+        .header(header::CACHE_CONTROL, "no-cache")
+        // stop nginx from buffering the stream
+        .header("X-Accel-Buffering", "no")
+        .body(Body::wrap_stream(chunks))?;
+    Ok(response)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_all_fields() {
+        let event = Event::new("hello").with_id("7").with_event("greeting").with_retry(Duration::from_secs(3));
+        assert_eq!(event.to_string(), "id: 7\nevent: greeting\nretry: 3000\ndata: hello\n\n");
+    }
+
+    #[test]
+    fn splits_data_on_every_line_terminator() {
+        let event = Event::new("a\nb\r\nc\rd");
+        assert_eq!(event.to_string(), "data: a\ndata: b\ndata: c\ndata: d\n\n");
+    }
+
+    #[test]
+    fn strips_line_breaks_from_id_and_event() {
+        let event = Event::new("x").with_id("1\r\ndata: injected").with_event("a\rb");
+        assert_eq!(event.to_string(), "id: 1data: injected\nevent: ab\ndata: x\n\n");
+    }
+}
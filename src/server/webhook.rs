@@ -0,0 +1,265 @@
+//! The webhook module signs and verifies webhook bodies with HMAC-SHA256.
+//! The sender signs "{timestamp}.{body}" with a shared secret and sends the signature and timestamp
+//! in headers. The receiver recomputes it in constant time and refuses timestamps outside a tolerance,
+//! so a captured request cannot be replayed later.
+
+
+// standard library
+use std::time::Duration;
+// crates.io
+use hmac::{Hmac, Mac};
+use hyper::{header::{self, HeaderMap, HeaderValue}, Body, Request, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+// this crate
+use crate::{err::{HypErr, SignatureError}, util::{to_hex, unix_now}};
+use super::{decode_body, read_body_limited, MAX_DECOMPRESSED_PAYLOAD};
+
+
+/// The header carrying the signature, as "sha256=<hex>", unless configured otherwise
+pub const DEFAULT_SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+/// The header carrying the unix timestamp the signature covers, unless configured otherwise
+pub const DEFAULT_TIMESTAMP_HEADER: &str = "X-Webhook-Timestamp";
+/// How far a timestamp may be from the receiver's clock by default
+pub const DEFAULT_TOLERANCE: Duration = Duration::from_secs(300);
+
+
+/// Compute the signature of a body sent at timestamp (seconds since the unix epoch), as "sha256=<hex>"
+pub fn signature(secret: &[u8], timestamp: u64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac accepts any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    let hex = to_hex(&mac.finalize().into_bytes());
+    format!("sha256={}", hex)
+}
+
+
+/// A WebhookSigner adds signature and timestamp headers to outgoing requests.
+/// # Examples:
+/// ```ignore
+/// let client = ApiClient::new().with_webhook_signer(WebhookSigner::new(secret.as_bytes()));
+/// client.post_noback("https://partner.example.com/hooks/orders", &event).await?;
+/// ```
+#[derive(Clone)]
+pub struct WebhookSigner {
+    secret: Vec<u8>,
+    signature_header: String,
+    timestamp_header: String,
+}
+
+
+impl WebhookSigner {
+    pub fn new(secret: &[u8]) -> Self {
+        WebhookSigner{
+            secret: secret.to_vec(),
+            signature_header: DEFAULT_SIGNATURE_HEADER.to_string(),
+            timestamp_header: DEFAULT_TIMESTAMP_HEADER.to_string(),
+        }
+    }
+
+    /// Send the signature and timestamp in these headers instead of the defaults
+    pub fn with_header_names(mut self, signature_header: &str, timestamp_header: &str) -> Self {
+        self.signature_header = signature_header.to_string();
+        self.timestamp_header = timestamp_header.to_string();
+        self
+    }
+
+    /// Sign a body with the current time, adding both headers
+    pub fn sign(&self, headers: &mut HeaderMap, body: &[u8]) -> Result<(), HypErr> {
+        let timestamp = unix_now();
+        let name = header::HeaderName::from_bytes(self.signature_header.as_bytes()).map_err(hyper::http::Error::from)?;
+        let value = HeaderValue::from_str(&signature(&self.secret, timestamp, body)).map_err(hyper::http::Error::from)?;
+        headers.insert(name, value);
+        let name = header::HeaderName::from_bytes(self.timestamp_header.as_bytes()).map_err(hyper::http::Error::from)?;
+        headers.insert(name, HeaderValue::from(timestamp));
+        Ok(())
+    }
+}
+
+
+impl std::fmt::Debug for WebhookSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // the secret is left out, since it should never end up in a log
+        f.debug_struct("WebhookSigner")
+            .field("signature_header", &self.signature_header)
+            .field("timestamp_header", &self.timestamp_header)
+            .finish()
+    }
+}
+
+
+/// A WebhookVerifier checks the signature of incoming webhook requests.
+/// # Examples:
+/// ```ignore
+/// let verifier = WebhookVerifier::new(secret.as_bytes());
+/// let event: OrderEvent = match verifier.get_payload(req).await {
+///     Ok(event) => event,
+///     Err(HypErr::Signature(err)) => return webhook::build_response_401(&err),
+///     Err(err) => return bad_request_resp(&err),
+/// };
+/// ```
+#[derive(Clone)]
+pub struct WebhookVerifier {
+    secrets: Vec<Vec<u8>>,
+    signature_header: String,
+    timestamp_header: String,
+    tolerance: Duration,
+}
+
+
+impl WebhookVerifier {
+    pub fn new(secret: &[u8]) -> Self {
+        WebhookVerifier{
+            secrets: vec![secret.to_vec()],
+            signature_header: DEFAULT_SIGNATURE_HEADER.to_string(),
+            timestamp_header: DEFAULT_TIMESTAMP_HEADER.to_string(),
+            tolerance: DEFAULT_TOLERANCE,
+        }
+    }
+
+    /// Also accept signatures made with this secret, i.e. while rotating secrets
+    pub fn with_secret(mut self, secret: &[u8]) -> Self {
+        self.secrets.push(secret.to_vec());
+        self
+    }
+
+    /// Read the signature and timestamp from these headers instead of the defaults
+    pub fn with_header_names(mut self, signature_header: &str, timestamp_header: &str) -> Self {
+        self.signature_header = signature_header.to_string();
+        self.timestamp_header = timestamp_header.to_string();
+        self
+    }
+
+    /// Refuse timestamps further than this from the current time
+    pub fn with_tolerance(mut self, tolerance: Duration) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// Check the signature headers of a request against its raw (still encoded) body
+    pub fn verify(&self, headers: &HeaderMap, body: &[u8]) -> Result<(), SignatureError> {
+        let header_str = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+        let (signatures, timestamp) = match (header_str(&self.signature_header), header_str(&self.timestamp_header)) {
+            (Some(signatures), Some(timestamp)) => (signatures, timestamp),
+            _ => return Err(SignatureError::Missing),
+        };
+        let timestamp: u64 = timestamp.trim().parse().map_err(|_| SignatureError::Missing)?;
+        if unix_now().abs_diff(timestamp) > self.tolerance.as_secs() {
+            return Err(SignatureError::Stale)
+        }
+        // a sender rotating secrets may send several comma separated signatures
+        let matched = self.secrets.iter().any(|secret| {
+            let expected = signature(secret, timestamp, body);
+            signatures.split(',').any(|candidate| bool::from(candidate.trim().as_bytes().ct_eq(expected.as_bytes())))
+        });
+        if !matched {
+            return Err(SignatureError::Invalid)
+        }
+        Ok(())
+    }
+
+    /// Like server::get_payload, but returns HypErr::Signature unless the body is correctly signed.
+    /// The signature covers the body as sent, before any Content-Encoding is decoded
+    pub async fn get_payload<T: DeserializeOwned>(&self, req: Request<Body>) -> Result<T, HypErr> {
+        let (parts, body) = req.into_parts();
+        let bytes = read_body_limited(body, MAX_DECOMPRESSED_PAYLOAD).await?;
+        self.verify(&parts.headers, &bytes)?;
+        let bytes = decode_body(&parts.headers, bytes, MAX_DECOMPRESSED_PAYLOAD)?;
+        let req_payload: T = serde_json::from_slice(&bytes)?;
+        Ok(req_payload)
+    }
+}
+
+
+impl std::fmt::Debug for WebhookVerifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("WebhookVerifier")
+            .field("secrets", &self.secrets.len())
+            .field("signature_header", &self.signature_header)
+            .field("timestamp_header", &self.timestamp_header)
+            .field("tolerance", &self.tolerance)
+            .finish()
+    }
+}
+
+
+/// Build a 401 Unauthorized response for a rejected webhook
+pub fn build_response_401(err: &SignatureError) -> Result<Response<Body>, HypErr> {
+    let response = Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .body(Body::from(format!("UNAUTHORIZED: {}", err)))?;
+    Ok(response)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed_headers(secret: &[u8], timestamp: u64, body: &[u8]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(DEFAULT_SIGNATURE_HEADER, signature(secret, timestamp, body).parse().unwrap());
+        headers.insert(DEFAULT_TIMESTAMP_HEADER, HeaderValue::from(timestamp));
+        headers
+    }
+
+    #[test]
+    fn signer_and_verifier_agree() {
+        let mut headers = HeaderMap::new();
+        WebhookSigner::new(b"secret").sign(&mut headers, b"{\"id\":1}").unwrap();
+        assert!(WebhookVerifier::new(b"secret").verify(&headers, b"{\"id\":1}").is_ok());
+    }
+
+    #[test]
+    fn rejects_tampered_bodies_and_wrong_secrets() {
+        let headers = signed_headers(b"secret", unix_now(), b"{\"amount\":1}");
+        let verifier = WebhookVerifier::new(b"secret");
+        assert!(matches!(verifier.verify(&headers, b"{\"amount\":100}"), Err(SignatureError::Invalid)));
+        assert!(matches!(WebhookVerifier::new(b"other").verify(&headers, b"{\"amount\":1}"), Err(SignatureError::Invalid)));
+        // while rotating, either secret is accepted
+        assert!(WebhookVerifier::new(b"other").with_secret(b"secret").verify(&headers, b"{\"amount\":1}").is_ok());
+    }
+
+    #[test]
+    fn accepts_any_of_several_signatures() {
+        let now = unix_now();
+        let mut headers = signed_headers(b"new", now, b"body");
+        let both = format!("{}, {}", signature(b"old", now, b"body"), signature(b"new", now, b"body"));
+        headers.insert(DEFAULT_SIGNATURE_HEADER, both.parse().unwrap());
+        assert!(WebhookVerifier::new(b"old").verify(&headers, b"body").is_ok());
+    }
+
+    #[test]
+    fn timestamps_must_be_within_the_tolerance() {
+        let verifier = WebhookVerifier::new(b"secret").with_tolerance(Duration::from_secs(60));
+        let now = unix_now();
+        assert!(verifier.verify(&signed_headers(b"secret", now - 50, b"body"), b"body").is_ok());
+        assert!(verifier.verify(&signed_headers(b"secret", now + 50, b"body"), b"body").is_ok());
+        assert!(matches!(verifier.verify(&signed_headers(b"secret", now - 120, b"body"), b"body"), Err(SignatureError::Stale)));
+        assert!(matches!(verifier.verify(&signed_headers(b"secret", now + 120, b"body"), b"body"), Err(SignatureError::Stale)));
+    }
+
+    #[test]
+    fn missing_headers() {
+        let verifier = WebhookVerifier::new(b"secret");
+        assert!(matches!(verifier.verify(&HeaderMap::new(), b"body"), Err(SignatureError::Missing)));
+        let mut headers = signed_headers(b"secret", unix_now(), b"body");
+        headers.insert(DEFAULT_TIMESTAMP_HEADER, "yesterday".parse().unwrap());
+        assert!(matches!(verifier.verify(&headers, b"body"), Err(SignatureError::Missing)));
+    }
+
+    #[tokio::test]
+    async fn get_payload_verifies_the_encoded_body() {
+        let gzipped = crate::server::compress::encode(b"{\"id\":7}", crate::server::compress::Encoding::Gzip).unwrap();
+        let mut req = Request::new(Body::from(gzipped.clone()));
+        *req.headers_mut() = signed_headers(b"secret", unix_now(), &gzipped);
+        req.headers_mut().insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        let payload: serde_json::Value = WebhookVerifier::new(b"secret").get_payload(req).await.unwrap();
+        assert_eq!(payload["id"], 7);
+        let req = Request::new(Body::from(vec![b' '; MAX_DECOMPRESSED_PAYLOAD + 1]));
+        let result = WebhookVerifier::new(b"secret").get_payload::<serde_json::Value>(req).await;
+        assert!(matches!(result, Err(HypErr::BodyTooLarge(_))));
+    }
+}
@@ -0,0 +1,252 @@
+//! The ws module upgrades an incoming http request to a WebSocket,
+//! and wraps the connection with helpers for sending and receiving JSON messages.
+
+
+// crates.io
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_util::{future, Sink, SinkExt, Stream, StreamExt};
+use hyper::{header, upgrade::{OnUpgrade, Upgraded}, Body, Method, Request, Response, StatusCode};
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::{WebSocketStream, tungstenite::{
+    error::ProtocolError, handshake::derive_accept_key, http, protocol::Role, Error as WsError, Message,
+}};
+// this crate
+use crate::err::HypErr;
+
+
+// does the comma-separated header contain the token, ignoring case?
+fn header_has_token(req: &Request<Body>, name: header::HeaderName, token: &str) -> bool {
+    req.headers().get_all(name).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim().eq_ignore_ascii_case(token))
+}
+
+
+/// Returns true if the request is asking to be upgraded to a WebSocket
+pub fn is_upgrade_request(req: &Request<Body>) -> bool {
+    header_has_token(req, header::CONNECTION, "upgrade") && header_has_token(req, header::UPGRADE, "websocket")
+}
+
+
+/// Validate the WebSocket handshake of a request, returning the 101 Switching Protocols response
+/// to send back along with a PendingWebSocket that resolves once hyper has handed over the connection.
+/// Return the response from your handler first, then await the PendingWebSocket in a spawned task.
+/// A request that is not a valid handshake, i.e. one whose Sec-WebSocket-Key is missing or is not
+/// 16 bytes encoded as base64, returns HypErr::WebSocket, which should be answered with bad_request_resp (400).
+/// # Examples:
+/// ```ignore
+/// (&Method::GET, "/ws") => {
+///     let (response, pending) = match ws::upgrade(req) {
+///         Ok(upgrade) => upgrade,
+///         Err(err) => return bad_request_resp(&err),
+///     };
+///     tokio::spawn(async move {
+///         let mut socket = pending.accept().await?;
+///         while let Some(msg) = socket.recv_json::<ChatMessage>().await? {
+///             socket.send_json(&msg).await?; // echo
+///         }
+///         Ok::<_, HypErr>(())
+///     });
+///     Ok(response)
+/// },
+/// ```
+pub fn upgrade(mut req: Request<Body>) -> Result<(Response<Body>, PendingWebSocket), HypErr> {
+    if req.method() != Method::GET {
+        return Err(WsError::Protocol(ProtocolError::WrongHttpMethod).into())
+    }
+    if !header_has_token(&req, header::CONNECTION, "upgrade") {
+        return Err(WsError::Protocol(ProtocolError::MissingConnectionUpgradeHeader).into())
+    }
+    if !header_has_token(&req, header::UPGRADE, "websocket") {
+        return Err(WsError::Protocol(ProtocolError::MissingUpgradeWebSocketHeader).into())
+    }
+    if req.headers().get(header::SEC_WEBSOCKET_VERSION).map(|v| v.as_bytes()) != Some(b"13") {
+        return Err(WsError::Protocol(ProtocolError::MissingSecWebSocketVersionHeader).into())
+    }
+    let accept_key = match req.headers().get(header::SEC_WEBSOCKET_KEY) {
+        // RFC 6455 requires the key to be a random 16 byte value encoded as base64
+        Some(key) if STANDARD.decode(key.as_bytes()).is_ok_and(|nonce| nonce.len() == 16) => derive_accept_key(key.as_bytes()),
+        Some(_) => return Err(WsError::Protocol(ProtocolError::InvalidHeader(http::header::SEC_WEBSOCKET_KEY)).into()),
+        None => return Err(WsError::Protocol(ProtocolError::MissingSecWebSocketKey).into()),
+    };
+    let on_upgrade = hyper::upgrade::on(&mut req);
+    let response = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_ACCEPT, accept_key)
+        .body(Body::empty())?;
+    Ok((response, PendingWebSocket{on_upgrade}))
+}
+
+
+/// A WebSocket whose handshake response has not been sent yet. See upgrade.
+#[derive(Debug)]
+pub struct PendingWebSocket {
+    on_upgrade: OnUpgrade,
+}
+
+impl PendingWebSocket {
+    /// Wait for hyper to send the 101 response and hand over the connection
+    pub async fn accept(self) -> Result<WebSocket<Upgraded>, HypErr> {
+        let upgraded = self.on_upgrade.await?;
+        let inner = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+        Ok(WebSocket{inner})
+    }
+}
+
+
+/// An open WebSocket connection.
+/// Pings from the peer are answered automatically whenever the socket is read from,
+/// and ping, pong and close frames are skipped by the recv methods.
+#[derive(Debug)]
+pub struct WebSocket<S> {
+    inner: WebSocketStream<S>,
+}
+
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocket<S> {
+    /// Wrap a connection that has already completed the WebSocket handshake
+    pub fn new(inner: WebSocketStream<S>) -> Self {
+        WebSocket{inner}
+    }
+
+    /// Send any serializable struct as a JSON text message
+    pub async fn send_json<T: Serialize>(&mut self, payload: &T) -> Result<(), HypErr> {
+        let json = serde_json::to_string(payload)?;
+        self.inner.send(Message::Text(json)).await?;
+        Ok(())
+    }
+
+    /// Let T be any struct implementing serde::de::DeserializeOwned.
+    /// Wait for the next text or binary message and deserialize it from JSON,
+    /// returning None once the connection is closed.
+    pub async fn recv_json<T: DeserializeOwned>(&mut self) -> Result<Option<T>, HypErr> {
+        match self.recv_data().await? {
+            Some(data) => Ok(Some(serde_json::from_slice::<T>(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Send a plain text message
+    pub async fn send_text(&mut self, text: &str) -> Result<(), HypErr> {
+        self.inner.send(Message::Text(text.to_string())).await?;
+        Ok(())
+    }
+
+    /// Wait for the payload of the next text or binary message, returning None once the connection is closed
+    pub async fn recv_data(&mut self) -> Result<Option<Vec<u8>>, HypErr> {
+        while let Some(msg) = self.inner.next().await {
+            match msg? {
+                Message::Text(text) => return Ok(Some(text.into_bytes())),
+                Message::Binary(data) => return Ok(Some(data)),
+                Message::Close(_) => return Ok(None),
+                _ => continue,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Send a ping. Sending one periodically keeps idle connections open through proxies
+    pub async fn ping(&mut self) -> Result<(), HypErr> {
+        self.inner.send(Message::Ping(Vec::new())).await?;
+        Ok(())
+    }
+
+    /// Send a close frame and wait for the connection to shut down
+    pub async fn close(mut self) -> Result<(), HypErr> {
+        match self.inner.close(None).await {
+            Ok(()) | Err(WsError::ConnectionClosed) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Split the connection into a typed sink of outgoing messages and a typed stream of incoming messages,
+    /// each framed as JSON, so they can be used from separate tasks.
+    pub fn split_json<Tx, Rx>(self) -> (impl Sink<Tx, Error = HypErr>, impl Stream<Item = Result<Rx, HypErr>>)
+    where
+        Tx: Serialize,
+        Rx: DeserializeOwned,
+    {
+        let (sink, stream) = self.inner.split();
+        let sink = sink.sink_map_err(HypErr::from).with(|payload: Tx| {
+            future::ready(serde_json::to_string(&payload).map(Message::Text).map_err(HypErr::from))
+        });
+        let stream = stream
+            .take_while(|msg| future::ready(!matches!(msg, Ok(Message::Close(_)))))
+            .filter_map(|msg| future::ready(match msg {
+                Ok(Message::Text(text)) => Some(serde_json::from_str::<Rx>(&text).map_err(HypErr::from)),
+                Ok(Message::Binary(data)) => Some(serde_json::from_slice::<Rx>(&data).map_err(HypErr::from)),
+                Ok(_) => None,
+                Err(err) => Some(Err(err.into())),
+            }));
+        (sink, stream)
+    }
+
+    /// Return the underlying tokio-tungstenite stream, for anything the helpers above do not cover
+    pub fn into_inner(self) -> WebSocketStream<S> {
+        self.inner
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::bad_request_resp;
+
+    // a handshake request with the sample key from RFC 6455, with one header replaced or removed
+    fn handshake(name: header::HeaderName, value: Option<&str>) -> Request<Body> {
+        let mut req = Request::builder()
+            .method(Method::GET)
+            .uri("/ws")
+            .header(header::CONNECTION, "keep-alive, Upgrade")
+            .header(header::UPGRADE, "websocket")
+            .header(header::SEC_WEBSOCKET_VERSION, "13")
+            .header(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+            .body(Body::empty())
+            .unwrap();
+        match value {
+            Some(value) => req.headers_mut().insert(name, value.parse().unwrap()),
+            None => req.headers_mut().remove(name),
+        };
+        req
+    }
+
+    // the status of the response a handler would send for the request
+    fn status(req: Request<Body>) -> StatusCode {
+        match upgrade(req) {
+            Ok((response, _)) => response.status(),
+            Err(err) => bad_request_resp(&err).unwrap().status(),
+        }
+    }
+
+    #[test]
+    fn switches_protocols_with_the_accept_key() {
+        let (response, _) = upgrade(handshake(header::SEC_WEBSOCKET_KEY, Some("dGhlIHNhbXBsZSBub25jZQ=="))).unwrap();
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        let headers = response.headers();
+        assert_eq!(headers[header::SEC_WEBSOCKET_ACCEPT], "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(headers[header::CONNECTION], "upgrade");
+        assert_eq!(headers[header::UPGRADE], "websocket");
+    }
+
+    #[test]
+    fn rejects_missing_or_invalid_keys() {
+        assert_eq!(status(handshake(header::SEC_WEBSOCKET_KEY, None)), StatusCode::BAD_REQUEST);
+        assert_eq!(status(handshake(header::SEC_WEBSOCKET_KEY, Some("not base64!"))), StatusCode::BAD_REQUEST);
+        // valid base64, but 8 bytes rather than 16
+        assert_eq!(status(handshake(header::SEC_WEBSOCKET_KEY, Some("c2hvcnRrZXk="))), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn rejects_other_invalid_handshakes() {
+        assert_eq!(status(handshake(header::SEC_WEBSOCKET_VERSION, Some("8"))), StatusCode::BAD_REQUEST);
+        assert_eq!(status(handshake(header::UPGRADE, None)), StatusCode::BAD_REQUEST);
+        assert_eq!(status(handshake(header::CONNECTION, Some("keep-alive"))), StatusCode::BAD_REQUEST);
+        assert!(is_upgrade_request(&handshake(header::UPGRADE, Some("WebSocket"))));
+        assert!(!is_upgrade_request(&handshake(header::UPGRADE, None)));
+    }
+}
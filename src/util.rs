@@ -0,0 +1,29 @@
+//! The util module holds small helpers shared by the client and server modules.
+
+
+// standard library
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+/// The current time as whole seconds since the unix epoch, or 0 if the clock is set before it
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+
+/// Lowercase hex encoding of bytes, i.e. for digests and signatures
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_is_lowercase_and_padded() {
+        assert_eq!(to_hex(&[0x00, 0x0f, 0xab, 0xff]), "000fabff");
+        assert_eq!(to_hex(&[]), "");
+    }
+}